@@ -0,0 +1,114 @@
+//! A small harness shared by the benchmarks, timing closures with the std clock.
+//!
+//! Run with `cargo bench`, or `cargo bench --bench <file> <filter>` to only run the
+//! benchmarks with names containing the filter.
+
+#![allow(dead_code)]
+
+use nouzdb::DatabaseBuilder;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A fresh folder under the temporary folder of the OS, removed once dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("nouzdb-bench-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Remove all the files of the folder.
+    pub fn clear(&self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        std::fs::create_dir_all(&self.0).unwrap();
+    }
+
+    /// Replace the files of the folder with copies of the files of `from`, leaving out
+    /// the lock file.
+    pub fn copy_from(&self, from: &Path) {
+        self.clear();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() && entry.file_name() != "LOCK" {
+                std::fs::copy(entry.path(), self.0.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A builder with synchronous flushes and no background merges, so only the benchmarked
+/// work is timed.
+pub fn quiet() -> DatabaseBuilder {
+    let mut builder = DatabaseBuilder::default();
+    builder.sync_flush(true).auto_merge(false);
+    builder
+}
+
+/// Whether the benchmark `name` is selected by the filter given on the command line.
+pub fn selected(name: &str) -> bool {
+    std::env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .is_none_or(|filter| name.contains(&filter))
+}
+
+/// Time `iterations` calls of `f` with the number of the call, after a tenth as many to
+/// warm up, and print the mean time of a call.
+pub fn bench(name: &str, iterations: u64, mut f: impl FnMut(u64)) {
+    if !selected(name) {
+        return;
+    }
+    for i in 0..iterations / 10 {
+        f(i);
+    }
+    let start = Instant::now();
+    for i in 0..iterations {
+        f(iterations / 10 + i);
+    }
+    report(name, iterations, start.elapsed());
+}
+
+/// Time `iterations` calls of `f` like [`bench`], each with a fresh input made by
+/// `setup`, which is not timed, and without timing the drop of the output either.
+pub fn bench_with<T, R>(
+    name: &str,
+    iterations: u64,
+    mut setup: impl FnMut() -> T,
+    mut f: impl FnMut(T) -> R,
+) {
+    if !selected(name) {
+        return;
+    }
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iterations {
+        let input = setup();
+        let start = Instant::now();
+        let output = f(input);
+        elapsed += start.elapsed();
+        drop(output);
+    }
+    report(name, iterations, elapsed);
+}
+
+fn report(name: &str, iterations: u64, elapsed: Duration) {
+    println!(
+        "{:<40} {:>10.0} ns/iter ({} iterations)",
+        name,
+        elapsed.as_nanos() as f64 / iterations as f64,
+        iterations
+    );
+}
@@ -0,0 +1,113 @@
+//! Benchmarks of the read path: scans of large segments, through read buffers of several
+//! sizes, and lookups through indexes of several sizes.
+
+mod common;
+
+use common::{bench, quiet, TempDir};
+use nouzdb::{Database, DatabaseBuilder, Get, Map};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The system allocator, counting the allocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const RECORDS: usize = 100_000;
+
+fn main() {
+    let dir = TempDir::new("read");
+    let db = segment(&dir, &quiet());
+    scan(&db);
+    drop(db);
+    scan_read_buffer();
+    get_index_size();
+}
+
+/// A database with a single segment of [`RECORDS`] records.
+fn segment(dir: &TempDir, builder: &DatabaseBuilder) -> Database {
+    segment_of(dir, builder, RECORDS)
+}
+
+/// A database with a single segment of `records` records.
+fn segment_of(dir: &TempDir, builder: &DatabaseBuilder, records: usize) -> Database {
+    dir.clear();
+    let mut db = builder.open(dir.path()).unwrap();
+    for i in 0..records {
+        db.set(format!("key{:08}", i), format!("value{:08}", i))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    db
+}
+
+/// The keys and the values are copied into shared chunks, so the Arc of each value is
+/// about the only allocation per record.
+fn scan(db: &Database) {
+    let name = format!("scan/{}_records", RECORDS);
+    bench(&name, 10, |_| {
+        for entry in db.range::<str, _>(..).unwrap() {
+            black_box(entry.unwrap());
+        }
+    });
+    if common::selected(&name) {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let count = db.range::<str, _>(..).unwrap().count();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "{:<40} {:>10.2} allocations/record",
+            name,
+            allocations as f64 / count as f64
+        );
+    }
+}
+
+/// A larger buffer takes fewer reads for the same scan.
+fn scan_read_buffer() {
+    let dir = TempDir::new("read-buffer");
+    for size in [4 * 1024, 64 * 1024, 1024 * 1024] {
+        let mut builder = quiet();
+        builder.read_buffer_size(size);
+        let db = segment(&dir, &builder);
+        bench(&format!("scan/read_buffer_{}", size), 10, |_| {
+            for entry in db.range::<str, _>(..).unwrap() {
+                black_box(entry.unwrap());
+            }
+        });
+    }
+}
+
+/// With every record in a block of its own, a lookup is about a binary search of the
+/// index, so ten times the blocks only add a few steps to it.
+fn get_index_size() {
+    let dir = TempDir::new("index-size");
+    for records in [1000, 10_000, 100_000] {
+        let mut builder = quiet();
+        builder.block_size(1);
+        let db = segment_of(&dir, &builder, records);
+        bench(&format!("get/index_{}_blocks", records), 10_000, |i| {
+            let key = format!("key{:08}", i as usize * 7919 % records);
+            black_box(db.get(&key).unwrap().unwrap());
+        });
+    }
+}
@@ -0,0 +1,104 @@
+//! Benchmarks of the write path, of the replay of the log and of flushes.
+
+mod common;
+
+use bytes::Bytes;
+use common::{bench, bench_with, quiet, TempDir};
+use nouzdb::Map;
+use std::hint::black_box;
+
+/// Large enough for the memtable not to be written out meanwhile.
+const SWITCH_MEM_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() {
+    set_bytes_key();
+    set_small_record();
+    replay_log();
+    flush();
+}
+
+fn set_bytes_key() {
+    let dir = TempDir::new("set-bytes");
+    let mut db = quiet()
+        .switch_mem_size(SWITCH_MEM_SIZE)
+        .open(dir.path())
+        .unwrap();
+    // Made up front, so only the set is timed.
+    let keys: Vec<Bytes> = (0..110_000)
+        .map(|i| Bytes::from(format!("key{:08}", i)))
+        .collect();
+    let value = Bytes::from(vec![b'v'; 100]);
+    bench("set/bytes_key", 100_000, |i| {
+        db.set(black_box(keys[i as usize].clone()), value.clone())
+            .unwrap();
+    });
+}
+
+/// The cost of a log record, its checksum included, dominates for tiny records.
+fn set_small_record() {
+    let dir = TempDir::new("set-small");
+    let mut db = quiet()
+        .switch_mem_size(SWITCH_MEM_SIZE)
+        .open(dir.path())
+        .unwrap();
+    let keys: Vec<Bytes> = (0..110_000)
+        .map(|i| Bytes::from(format!("{:08}", i)))
+        .collect();
+    bench("set/small_record", 100_000, |i| {
+        db.set(keys[i as usize].clone(), "v").unwrap();
+    });
+}
+
+/// Opening replays the log, reading and checking the checksum of every record.
+fn replay_log() {
+    const RECORDS: u64 = 100_000;
+    let logged = TempDir::new("replay-logged");
+    let mut db = quiet()
+        .switch_mem_size(SWITCH_MEM_SIZE)
+        .open(logged.path())
+        .unwrap();
+    for i in 0..RECORDS {
+        db.set(format!("key{:08}", i), "value").unwrap();
+    }
+    // Copied while the database is open, as closing it writes the memtable out.
+    let stash = TempDir::new("replay-stash");
+    stash.copy_from(logged.path());
+    drop(db);
+    let dir = TempDir::new("replay");
+    bench_with(
+        &format!("replay/{}_records", RECORDS),
+        10,
+        || dir.copy_from(stash.path()),
+        |()| {
+            quiet()
+                .switch_mem_size(SWITCH_MEM_SIZE)
+                .open(dir.path())
+                .unwrap()
+        },
+    );
+}
+
+/// Writing a memtable out to a segment.
+fn flush() {
+    const ENTRIES: u64 = 100_000;
+    let dir = TempDir::new("flush");
+    bench_with(
+        &format!("flush/{}_entries", ENTRIES),
+        10,
+        || {
+            dir.clear();
+            let mut db = quiet()
+                .switch_mem_size(SWITCH_MEM_SIZE)
+                .open(dir.path())
+                .unwrap();
+            for i in 0..ENTRIES {
+                db.set(format!("key{:08}", i), "value").unwrap();
+            }
+            db
+        },
+        |db| {
+            db.flush().unwrap();
+            db
+        },
+    );
+}
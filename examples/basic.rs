@@ -4,7 +4,8 @@ use nouzdb::{DatabaseBuilder, Get, Map};
 fn main() -> Result<()> {
     let mut db = DatabaseBuilder::default().open("data/")?;
     db.set("hello", "world")?;
-    let value = db.get(b"hello")?.unwrap();
-    assert_eq!(value.as_ref().as_ref(), b"world");
+    let value = db.get_value(b"hello")?.unwrap();
+    assert_eq!(value, b"world");
+    assert_eq!(&value[..], b"world");
     Ok(())
 }
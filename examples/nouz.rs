@@ -1,4 +1,5 @@
 use anyhow::Result;
+use nouzdb::output::{format_error, format_get_result, format_set_result};
 use nouzdb::{DatabaseBuilder, Get, Map};
 use rustyline::error::ReadlineError;
 use std::path::PathBuf;
@@ -17,6 +18,10 @@ struct Opt {
 
     #[structopt(long, short, default_value = "4096")]
     block_size: u64,
+
+    /// Print the results of `get` and `set` as JSON lines.
+    #[structopt(long)]
+    json: bool,
 }
 
 fn main() -> Result<()> {
@@ -38,7 +43,20 @@ fn main() -> Result<()> {
                     match cmd {
                         "get" => {
                             if let Some(key) = cmds.next() {
-                                match db.get(&key) {
+                                let res = db.get(&key);
+                                if opt.json {
+                                    match res {
+                                        Ok(value) => println!(
+                                            "{}",
+                                            format_get_result(
+                                                value.as_deref().map(|value| &value[..])
+                                            )
+                                        ),
+                                        Err(err) => println!("{}", format_error(&err)),
+                                    }
+                                    continue;
+                                }
+                                match res {
                                     Ok(Some(value)) => match String::from_utf8(value.to_vec()) {
                                         Ok(s) => {
                                             println!("{}", s);
@@ -63,8 +81,11 @@ fn main() -> Result<()> {
                             let value = cmds.next();
                             match (key, value) {
                                 (Some(key), Some(value)) => {
-                                    if let Err(err) = db.set(key.to_string(), value.to_string()) {
-                                        println!("Set error: {}", err);
+                                    match db.set(key.to_string(), value.to_string()) {
+                                        Ok(()) if opt.json => println!("{}", format_set_result()),
+                                        Ok(()) => {}
+                                        Err(err) if opt.json => println!("{}", format_error(&err)),
+                                        Err(err) => println!("Set error: {}", err),
                                     }
                                 }
                                 (Some(_), None) => {
@@ -76,6 +97,9 @@ fn main() -> Result<()> {
                                 _ => {}
                             }
                         }
+                        cmd if opt.json => {
+                            println!("{}", format_error(&format!("unknown command: {}", cmd)));
+                        }
                         cmd => {
                             println!("Unknown command: {}", cmd);
                         }
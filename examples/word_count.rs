@@ -1,5 +1,6 @@
 use anyhow::Result;
-use nouzdb::{DatabaseBuilder, Get, Map};
+use bytes::Bytes;
+use nouzdb::{DatabaseBuilder, GetOrReserve, Map};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
@@ -42,12 +43,15 @@ fn main() -> Result<()> {
             let file = File::open(&path)?;
             for line in BufReader::new(file).lines() {
                 for word in line?.split_whitespace() {
-                    let mut count = db
-                        .get(word)?
-                        .and_then(|bytes| parse_to_usize(&bytes).ok())
-                        .unwrap_or_default();
+                    let (key, mut count) =
+                        match db.get_or_reserve(Bytes::copy_from_slice(word.as_bytes()))? {
+                            GetOrReserve::Found { key, value } => {
+                                (key, parse_to_usize(&value).unwrap_or_default())
+                            }
+                            GetOrReserve::Vacant(key) => (key, 0),
+                        };
                     count += 1;
-                    db.set(word.to_string(), count.to_string())?;
+                    db.set(key, count.to_string())?;
                 }
             }
         }
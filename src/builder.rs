@@ -1,12 +1,22 @@
 //! Builder for [`Database`].
 
-use crate::{database::Error, Database};
-use std::path::Path;
+use crate::cache::IndexCache;
+use crate::database::{Error, PanicHook, PanicHookFn, ReadOrder, SegmentFormat, WriteStall, DOT};
+use crate::schema::{KeyNormalizer, NormalizeFn};
+use crate::segment::{Blocks, ReadOptions};
+use crate::{Database, KeySchema, RecoveryReport, SegmentSetReader, Validator, ValueResolver};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
 
 /// Default log suffix.
 pub const DEFAULT_LOG_SUFFIX: &str = "log";
 /// Default data suffix.
 pub const DEFAULT_DATA_SUFIX: &str = "data";
+/// Default temporary file suffix.
+pub const DEFAULT_TMP_SUFFIX: &str = "tmp";
+/// Default pack file suffix.
+pub const DEFAULT_PACK_SUFFIX: &str = "pack";
 /// Default switch mem size.
 pub const DEFAULT_SWTICH_MEM_SIZE: usize = 1024 * 1024;
 /// Default merge period in secs.
@@ -15,16 +25,97 @@ pub const DEFAULT_MERGE_PERIOD_SECS: u64 = 3600;
 pub const DEFAULT_POLL_PERIOD_MILLIS: u64 = 100;
 /// Default block_size.
 pub const DEFAULT_BLOCK_SIZE: u64 = 4 * 1024;
+/// Default size of the read buffer of a segment file.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+/// Default max number of segments to merge at once.
+pub const DEFAULT_MAX_MERGE_SEGMENTS: usize = 8;
+/// Default share of tombstones at which a lone segment is compacted by itself.
+pub const DEFAULT_SELF_COMPACT_TOMBSTONE_RATIO: f64 = 0.5;
+
+/// Errors of a misconfigured [`DatabaseBuilder`], found before any file is touched.
+#[derive(Debug, Error)]
+pub enum BuilderError {
+    /// Invalid file suffix.
+    #[error("invalid file suffix: {0:?}")]
+    InvalidSuffix(String),
+
+    /// The same suffix is used for more than one kind of files.
+    #[error("suffix {0:?} is used for more than one kind of files")]
+    DuplicateSuffix(String),
+
+    /// The switch mem size is zero.
+    #[error("switch mem size must not be zero")]
+    InvalidSwitchMemSize,
+
+    /// The max number of segments to merge at once is less than 2.
+    #[error("max merge segments must be at least 2, got {0}")]
+    InvalidMaxMergeSegments(usize),
+
+    /// The max number of segments is less than 2.
+    #[error("max segments must be at least 2, got {0}")]
+    InvalidMaxSegments(usize),
+
+    /// The number of segments to pack together is less than 2.
+    #[error("pack segments must be at least 2, got {0}")]
+    InvalidPackSegments(usize),
+
+    /// The block size is zero.
+    #[error("block size must not be zero")]
+    InvalidBlockSize,
+
+    /// The read buffer size is zero.
+    #[error("read buffer size must not be zero")]
+    InvalidReadBufferSize,
+
+    /// The max number of segments with a cached index is zero.
+    #[error("max cached segment meta must not be zero")]
+    InvalidMaxCachedSegmentMeta,
+
+    /// The self compact tombstone ratio is negative or not finite.
+    #[error("self compact tombstone ratio must be finite and not negative, got {0}")]
+    InvalidSelfCompactTombstoneRatio(f64),
+}
 
 /// Database builder.
 #[derive(Debug)]
 pub struct DatabaseBuilder {
-    log_suffix: String,
-    data_suffix: String,
-    switch_mem_size: usize,
-    merge_period: std::time::Duration,
-    poll_period: std::time::Duration,
-    block_size: u64,
+    pub(crate) log_suffix: String,
+    pub(crate) data_suffix: String,
+    pub(crate) tmp_suffix: String,
+    pub(crate) pack_suffix: String,
+    pub(crate) switch_mem_size: usize,
+    pub(crate) stall_threshold: Option<usize>,
+    pub(crate) write_stall: WriteStall,
+    pub(crate) sync_flush: bool,
+    pub(crate) panic_hook: Option<PanicHook>,
+    pub(crate) slow_op_threshold: Option<std::time::Duration>,
+    pub(crate) merge_period: std::time::Duration,
+    pub(crate) poll_period: std::time::Duration,
+    pub(crate) block_size: u64,
+    pub(crate) block_alignment: Option<u64>,
+    pub(crate) read_buffer_size: usize,
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap_segments: bool,
+    pub(crate) max_merge_segments: usize,
+    pub(crate) self_compact_tombstone_ratio: f64,
+    pub(crate) max_segments: Option<usize>,
+    pub(crate) pack_segments: Option<usize>,
+    pub(crate) auto_merge: bool,
+    pub(crate) max_segment_id: Option<u64>,
+    pub(crate) value_resolver: Option<Arc<dyn ValueResolver>>,
+    pub(crate) key_schema: KeySchema,
+    pub(crate) validator: Option<Arc<dyn Validator>>,
+    pub(crate) key_normalizer: Option<KeyNormalizer>,
+    pub(crate) read_order: ReadOrder,
+    pub(crate) strict_reads: bool,
+    pub(crate) segment_format: SegmentFormat,
+    pub(crate) recovery_timeout: Option<std::time::Duration>,
+    pub(crate) strict_recovery: bool,
+    pub(crate) idle_flush: Option<std::time::Duration>,
+    pub(crate) value_cache_entries: Option<usize>,
+    pub(crate) max_cached_segment_meta: Option<usize>,
+    pub(crate) op_trace: Option<PathBuf>,
+    pub(crate) log_dir: Option<PathBuf>,
 }
 
 impl Default for DatabaseBuilder {
@@ -32,29 +123,121 @@ impl Default for DatabaseBuilder {
         Self {
             log_suffix: DEFAULT_LOG_SUFFIX.to_string(),
             data_suffix: DEFAULT_DATA_SUFIX.to_string(),
+            tmp_suffix: DEFAULT_TMP_SUFFIX.to_string(),
+            pack_suffix: DEFAULT_PACK_SUFFIX.to_string(),
             switch_mem_size: DEFAULT_SWTICH_MEM_SIZE,
+            stall_threshold: None,
+            write_stall: WriteStall::default(),
+            sync_flush: false,
+            panic_hook: None,
+            slow_op_threshold: None,
             merge_period: std::time::Duration::from_secs(DEFAULT_MERGE_PERIOD_SECS),
             poll_period: std::time::Duration::from_millis(DEFAULT_POLL_PERIOD_MILLIS),
             block_size: DEFAULT_BLOCK_SIZE,
+            block_alignment: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            #[cfg(feature = "mmap")]
+            mmap_segments: false,
+            max_merge_segments: DEFAULT_MAX_MERGE_SEGMENTS,
+            self_compact_tombstone_ratio: DEFAULT_SELF_COMPACT_TOMBSTONE_RATIO,
+            max_segments: None,
+            pack_segments: None,
+            auto_merge: true,
+            max_segment_id: None,
+            value_resolver: None,
+            key_schema: KeySchema::default(),
+            validator: None,
+            key_normalizer: None,
+            read_order: ReadOrder::default(),
+            strict_reads: false,
+            segment_format: SegmentFormat::default(),
+            recovery_timeout: None,
+            strict_recovery: false,
+            idle_flush: None,
+            value_cache_entries: None,
+            max_cached_segment_meta: None,
+            op_trace: None,
+            log_dir: None,
         }
     }
 }
 
 impl DatabaseBuilder {
+    /// Check the options, which opening does first.
+    ///
+    /// The file suffixes must be valid and distinct, the sizes must not be zero, the
+    /// segment counts must be at least 2 and the self compact tombstone ratio must be
+    /// finite and not negative.
+    pub fn validate(&self) -> Result<(), BuilderError> {
+        let suffixes = [
+            &self.log_suffix,
+            &self.data_suffix,
+            &self.tmp_suffix,
+            &self.pack_suffix,
+        ];
+        for (idx, suffix) in suffixes.iter().enumerate() {
+            if suffix.is_empty() || suffix.contains(DOT) {
+                return Err(BuilderError::InvalidSuffix(suffix.to_string()));
+            }
+            if suffixes[..idx].contains(suffix) {
+                return Err(BuilderError::DuplicateSuffix(suffix.to_string()));
+            }
+        }
+        if self.switch_mem_size == 0 {
+            return Err(BuilderError::InvalidSwitchMemSize);
+        }
+        if self.max_merge_segments < 2 {
+            return Err(BuilderError::InvalidMaxMergeSegments(
+                self.max_merge_segments,
+            ));
+        }
+        if let Some(count) = self.max_segments.filter(|count| *count < 2) {
+            return Err(BuilderError::InvalidMaxSegments(count));
+        }
+        if let Some(count) = self.pack_segments.filter(|count| *count < 2) {
+            return Err(BuilderError::InvalidPackSegments(count));
+        }
+        if self.block_size == 0 {
+            return Err(BuilderError::InvalidBlockSize);
+        }
+        if self.read_buffer_size == 0 {
+            return Err(BuilderError::InvalidReadBufferSize);
+        }
+        if self.max_cached_segment_meta == Some(0) {
+            return Err(BuilderError::InvalidMaxCachedSegmentMeta);
+        }
+        let ratio = self.self_compact_tombstone_ratio;
+        if !ratio.is_finite() || ratio < 0.0 {
+            return Err(BuilderError::InvalidSelfCompactTombstoneRatio(ratio));
+        }
+        Ok(())
+    }
+
     /// Open database at `path`.
-    pub fn open<P: AsRef<Path>>(&self, path: &P) -> Result<Database, Error>
+    pub fn open<P>(&self, path: &P) -> Result<Database, Error>
     where
-        P: ?Sized,
+        P: AsRef<Path> + ?Sized,
     {
-        Database::new(
-            path.as_ref(),
-            &self.log_suffix,
-            &self.data_suffix,
-            self.switch_mem_size,
-            self.merge_period,
-            self.poll_period,
-            self.block_size,
-        )
+        Database::new(path.as_ref(), self)
+    }
+
+    /// Open the segments at `path` for reading only, see [`SegmentSetReader`].
+    pub fn open_reader<P>(&self, path: &P) -> Result<SegmentSetReader, Error>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        SegmentSetReader::new(path.as_ref(), self)
+    }
+
+    /// Replay all the logs at `path` into new segments and remove the logs, without
+    /// starting the database.
+    ///
+    /// Replaying a log stops at its first corrupt record, like opening does.
+    pub fn recover<P>(&self, path: &P) -> Result<RecoveryReport, Error>
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        Database::recover(path.as_ref(), self)
     }
 
     /// Set log suffix.
@@ -69,12 +252,55 @@ impl DatabaseBuilder {
         self
     }
 
-    /// Set switch mem size.
+    /// Set the suffix of temporary files, which are renamed once they are fully written.
+    pub fn tmp_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.tmp_suffix = suffix.to_string();
+        self
+    }
+
+    /// Set the suffix of pack files, see [`pack_segments`](Self::pack_segments).
+    pub fn pack_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.pack_suffix = suffix.to_string();
+        self
+    }
+
+    /// Set switch mem size, which must not be zero.
+    ///
+    /// With a zero size every write would switch to a new memtable and end up in a
+    /// segment of its own.
     pub fn switch_mem_size(&mut self, size: usize) -> &mut Self {
         self.switch_mem_size = size;
         self
     }
 
+    /// Set the size of the active memtable at which writes stall while a frozen
+    /// memtable is still being written out. `0` turns it off, which is the default.
+    ///
+    /// The active memtable is only switched out once the frozen one is written, so it
+    /// grows past the switch mem size without bound when writes outpace the flushes.
+    /// How a stalled write behaves is set by [`DatabaseBuilder::write_stall`].
+    pub fn stall_threshold(&mut self, size: usize) -> &mut Self {
+        self.stall_threshold = (size > 0).then_some(size);
+        self
+    }
+
+    /// Set whether a stalled write waits for the flush or fails, see [`WriteStall`].
+    pub fn write_stall(&mut self, stall: WriteStall) -> &mut Self {
+        self.write_stall = stall;
+        self
+    }
+
+    /// Set whether the memtables are written out to segments by the write switching
+    /// them, or by the flush, before it returns. Defaults to `false`, in which case a
+    /// background task writes them.
+    ///
+    /// This is mainly meant for tests, which see the new segment right away, since the
+    /// writes switching the memtable take as long as writing it out.
+    pub fn sync_flush(&mut self, sync: bool) -> &mut Self {
+        self.sync_flush = sync;
+        self
+    }
+
     /// Set merge period.
     pub fn merge_period(&mut self, duration: std::time::Duration) -> &mut Self {
         self.merge_period = duration;
@@ -87,9 +313,278 @@ impl DatabaseBuilder {
         self
     }
 
-    /// Set segment block size.
+    /// Set segment block size, which must not be zero.
     pub fn block_size(&mut self, size: u64) -> &mut Self {
         self.block_size = size;
         self
     }
+
+    /// Set the alignment of the blocks of new segments, e.g. the page size, so each
+    /// indexed seek starts at an aligned offset. `0` turns it off, which is the default.
+    ///
+    /// A block is padded with blank lines from the end of the record reaching the block
+    /// size, so a block size a little less than a multiple of the alignment wastes the
+    /// least space. The padded segments stay readable by all versions, and opening finds
+    /// the blocks aligned as long as the block size is unchanged. Segments in packs are
+    /// only aligned from the start of the segment.
+    pub fn block_alignment(&mut self, alignment: u64) -> &mut Self {
+        self.block_alignment = (alignment > 0).then_some(alignment);
+        self
+    }
+
+    /// Set the size of the buffer the segment files are read through.
+    ///
+    /// A larger buffer takes fewer reads for merges and long scans, while a lookup reads
+    /// at most a block and a buffer more. The size must not be zero.
+    pub fn read_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Set whether the segment files are memory-mapped, so lookups are served from the
+    /// page cache without reads. Defaults to `false`.
+    ///
+    /// A mapped file is unmapped once its segment and all the readers of it are dropped.
+    /// The files must not be changed by other processes while the database is open.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_segments(&mut self, enabled: bool) -> &mut Self {
+        self.mmap_segments = enabled;
+        self
+    }
+
+    pub(crate) fn blocks(&self) -> Blocks {
+        Blocks {
+            size: self.block_size,
+            alignment: self.block_alignment,
+        }
+    }
+
+    /// The options to read the segments with, with a new cache of their indices shared
+    /// by the segments read with them.
+    pub(crate) fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            buffer_size: self.read_buffer_size,
+            #[cfg(feature = "mmap")]
+            mmap: self.mmap_segments,
+            index_cache: self
+                .max_cached_segment_meta
+                .map(|capacity| Arc::new(IndexCache::new(capacity))),
+        }
+    }
+
+    /// Set the max number of segments to merge at once (at least 2).
+    ///
+    /// The newest and smallest segments are merged first, so a merge takes a bounded
+    /// amount of work even when segments pile up under heavy writes.
+    pub fn max_merge_segments(&mut self, count: usize) -> &mut Self {
+        self.max_merge_segments = count;
+        self
+    }
+
+    /// Set the share of tombstones in the records of the only segment at which the
+    /// merging task rewrites it without them.
+    ///
+    /// With a single segment there is nothing to merge, so without this the space of the
+    /// deleted keys is never reclaimed. Only the tombstones count, as a segment holds a
+    /// single entry per key and so has no shadowed values of its own. The ratio must be
+    /// finite and not negative.
+    pub fn self_compact_tombstone_ratio(&mut self, ratio: f64) -> &mut Self {
+        self.self_compact_tombstone_ratio = ratio;
+        self
+    }
+
+    /// Set the max number of segments (at least 2).
+    ///
+    /// Before a new segment would exceed the limit, the newest segments are merged in
+    /// the writing task, so segments never pile up even if the background merge falls
+    /// behind. There is no limit by default.
+    pub fn max_segments(&mut self, count: usize) -> &mut Self {
+        self.max_segments = Some(count);
+        self
+    }
+
+    /// Pack the segments into a single file once `count` (at least 2) of them are in
+    /// files of their own, so there are far fewer files than segments.
+    ///
+    /// A segment keeps its id in a pack and is merged like the others, and a pack file is
+    /// removed once all its segments are merged away. Segments are not packed by default.
+    pub fn pack_segments(&mut self, count: usize) -> &mut Self {
+        self.pack_segments = Some(count);
+        self
+    }
+
+    /// Flush the active memtable to a new segment in a background task once there are no
+    /// writes for `idle`, even if it is not big enough to switch yet.
+    ///
+    /// The task checks for idleness every poll period.
+    pub fn idle_flush(&mut self, idle: std::time::Duration) -> &mut Self {
+        self.idle_flush = Some(idle);
+        self
+    }
+
+    /// Set the hook called with the task and the message when a background task panics,
+    /// such as a flush or a merge.
+    ///
+    /// The hook is called on the thread of the task, after the panic is logged and before
+    /// the thread ends. The task is not restarted.
+    pub fn panic_hook(&mut self, hook: Arc<PanicHookFn>) -> &mut Self {
+        self.panic_hook = Some(PanicHook(hook));
+        self
+    }
+
+    /// Cache the values of up to `entries` keys found in the segments by lookups, so the
+    /// hot keys are not read from the segment files again. There is no cache by default,
+    /// and none with zero entries.
+    ///
+    /// A key is dropped from the cache when it is written, and the whole cache when a
+    /// segment is flushed, ingested or dropped, or the segments are compacted.
+    pub fn value_cache_entries(&mut self, entries: usize) -> &mut Self {
+        self.value_cache_entries = (entries > 0).then_some(entries);
+        self
+    }
+
+    /// Keep the indices of at most `segments` segments in memory, the most recently read
+    /// ones, instead of all of them. An evicted index is built again from the records of
+    /// its segment when the segment is next read.
+    ///
+    /// This bounds the memory taken by databases with many segments, at the cost of
+    /// reading a whole segment to look up a key in it after its index is evicted. The
+    /// number must not be zero.
+    pub fn max_cached_segment_meta(&mut self, segments: usize) -> &mut Self {
+        self.max_cached_segment_meta = Some(segments);
+        self
+    }
+
+    /// Set whether to merge segments in a background task, which is the default.
+    ///
+    /// Without it, segments are only merged by [`Database::compact`] and by the
+    /// [`max_segments`](Self::max_segments) limit.
+    pub fn auto_merge(&mut self, enabled: bool) -> &mut Self {
+        self.auto_merge = enabled;
+        self
+    }
+
+    /// Set the initial max segment id.
+    ///
+    /// New segments will be assigned ids starting from `id + 1`. Opening fails if
+    /// `id` is less than the id of any segment already present in the data folder.
+    pub fn max_segment_id(&mut self, id: u64) -> &mut Self {
+        self.max_segment_id = Some(id);
+        self
+    }
+
+    /// Set the value resolver.
+    ///
+    /// The stored values are then treated as references, and `get` returns the values
+    /// they resolve to.
+    pub fn value_resolver(&mut self, resolver: Arc<dyn ValueResolver>) -> &mut Self {
+        self.value_resolver = Some(resolver);
+        self
+    }
+
+    /// Set the key schema, which `set` checks every key against.
+    pub fn key_schema(&mut self, schema: KeySchema) -> &mut Self {
+        self.key_schema = schema;
+        self
+    }
+
+    /// Set the validator, which every `set` calls with the normalized key and the value
+    /// before writing anything, failing with [`MapError::Rejected`](crate::MapError::Rejected)
+    /// if rejected. Deletes and ingested entries are not validated.
+    pub fn validator(&mut self, validator: Arc<dyn Validator>) -> &mut Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Set the key normalizer, which maps the keys of `set`, `get`, `delete` and
+    /// `ingest_sorted` to the form that is stored, e.g. lowercasing them for case-insensitive keys.
+    ///
+    /// Scans return the normalized keys, and their bounds are not normalized.
+    pub fn key_normalizer(&mut self, normalizer: Arc<NormalizeFn>) -> &mut Self {
+        self.key_normalizer = Some(KeyNormalizer(normalizer));
+        self
+    }
+
+    /// Set the time budget of replaying the logs on open.
+    ///
+    /// Opening fails with [`Error::RecoveryTimedOut`] instead of blocking once the budget
+    /// is used up. The logs are left untouched in that case.
+    pub fn recovery_timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
+        self.recovery_timeout = Some(timeout);
+        self
+    }
+
+    /// Set whether opening fails with [`Error::CorruptWal`] on a corrupt log record that
+    /// is followed by valid ones. Defaults to `false`.
+    ///
+    /// A torn write only leaves corrupt records at the end of a log, which are always
+    /// left out. Other corrupt records are left out with the rest of their log unless
+    /// strict, in which case the logs are left untouched.
+    pub fn strict_recovery(&mut self, strict: bool) -> &mut Self {
+        self.strict_recovery = strict;
+        self
+    }
+
+    /// Keep the logs in `dir` instead of the data folder, e.g. on a faster device than
+    /// the segments.
+    ///
+    /// Logs are only looked for in `dir` when opening, so the logs of a data folder
+    /// opened before without it must be moved there first.
+    pub fn log_dir<P>(&mut self, dir: &P) -> &mut Self
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        self.log_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Log a warning for every get, write, flush and merge taking longer than
+    /// `threshold`, with the operation and the time it took. Off by default.
+    pub fn slow_op_threshold(&mut self, threshold: std::time::Duration) -> &mut Self {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Append a record of every operation to the trace at `path`, which
+    /// [`replay`](crate::replay) re-executes to reproduce a bug.
+    ///
+    /// Meant for debugging, tracing flushes the trace file once per operation.
+    pub fn op_trace<P>(&mut self, path: &P) -> &mut Self
+    where
+        P: AsRef<Path> + ?Sized,
+    {
+        self.op_trace = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set whether a lookup fails with [`MapError::CorruptSegment`] on a segment record
+    /// it cannot read, instead of skipping it.
+    ///
+    /// Lookups skip such records by default, which can turn a corrupt entry into a
+    /// missing key or an older value.
+    ///
+    /// [`MapError::CorruptSegment`]: crate::MapError::CorruptSegment
+    pub fn strict_reads(&mut self, strict: bool) -> &mut Self {
+        self.strict_reads = strict;
+        self
+    }
+
+    /// Set the layout of the segment files written from now on, see [`SegmentFormat`].
+    ///
+    /// Segments of both layouts can be read whatever is set, and merges rewrite them in
+    /// the layout set.
+    pub fn segment_format(&mut self, format: SegmentFormat) -> &mut Self {
+        self.segment_format = format;
+        self
+    }
+
+    /// Set where reads look for a key first, see [`ReadOrder`].
+    ///
+    /// Only meant for migrations and tests, the default is the only order in which newer
+    /// writes always win.
+    #[doc(hidden)]
+    pub fn read_order(&mut self, order: ReadOrder) -> &mut Self {
+        self.read_order = order;
+        self
+    }
 }
@@ -0,0 +1,201 @@
+//! The [`ValueCache`] and [`IndexCache`] structures.
+
+use crate::index::BlockIndex;
+use crate::memtable::Entry;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A cache of the entries found in the segments by point lookups, evicting the least
+/// recently used one when full.
+///
+/// An entry is removed when its key is written, and all of them when the segments change
+/// other than by merges, which keep the values. A lookup takes the epoch before it
+/// starts, and what it finds is only cached if nothing is removed in between, so a
+/// lookup racing with a write never caches the value the write replaces.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Bytes, (Entry, u64)>,
+    /// The keys by the tick of their last use, from the least recent.
+    ticks: BTreeMap<u64, Bytes>,
+    tick: u64,
+    epoch: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// The epoch to pass to [`ValueCache::insert`], taken before the lookup.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .epoch
+    }
+
+    /// The cached entry of the key, which becomes the most recently used.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let (entry, tick) = inner.entries.get_mut(key)?;
+        if let Some(key) = inner.ticks.remove(tick) {
+            inner.ticks.insert(inner.tick, key);
+        }
+        *tick = inner.tick;
+        Some(entry.clone())
+    }
+
+    /// Cache the entry found by a lookup started at `epoch`.
+    pub(crate) fn insert(&self, key: &[u8], entry: Entry, epoch: u64) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.epoch != epoch {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let key = Bytes::copy_from_slice(key);
+        if let Some((_, old_tick)) = inner.entries.insert(key.clone(), (entry, tick)) {
+            inner.ticks.remove(&old_tick);
+        }
+        inner.ticks.insert(tick, key);
+        while inner.entries.len() > self.capacity {
+            match inner.ticks.pop_first() {
+                Some((_, key)) => inner.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    /// Remove the entry of the key.
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.epoch += 1;
+        if let Some((_, tick)) = inner.entries.remove(key) {
+            inner.ticks.remove(&tick);
+        }
+    }
+
+    /// Remove all the entries.
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.epoch += 1;
+        inner.entries.clear();
+        inner.ticks.clear();
+    }
+}
+
+/// A cache of the indices of the segments, keeping only the most recently used ones in
+/// memory. A segment whose index is evicted builds it again from its records when next
+/// read.
+#[derive(Debug)]
+pub(crate) struct IndexCache {
+    capacity: usize,
+    next_id: AtomicU64,
+    inner: Mutex<IndexInner>,
+}
+
+#[derive(Debug, Default)]
+struct IndexInner {
+    indices: HashMap<u64, (Arc<BlockIndex>, u64)>,
+    /// The ids by the tick of their last use, from the least recent.
+    ticks: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl IndexCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next_id: AtomicU64::new(0),
+            inner: Mutex::default(),
+        }
+    }
+
+    /// A new id to cache the index of a segment by.
+    pub(crate) fn register(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The cached index of the id, which becomes the most recently used.
+    pub(crate) fn get(&self, id: u64) -> Option<Arc<BlockIndex>> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let (index, tick) = inner.indices.get_mut(&id)?;
+        inner.ticks.remove(tick);
+        inner.ticks.insert(inner.tick, id);
+        *tick = inner.tick;
+        Some(index.clone())
+    }
+
+    /// Cache the index of the id, evicting the least recently used ones over the capacity.
+    pub(crate) fn insert(&self, id: u64, index: Arc<BlockIndex>) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, old_tick)) = inner.indices.insert(id, (index, tick)) {
+            inner.ticks.remove(&old_tick);
+        }
+        inner.ticks.insert(tick, id);
+        while inner.indices.len() > self.capacity {
+            match inner.ticks.pop_first() {
+                Some((_, id)) => inner.indices.remove(&id),
+                None => break,
+            };
+        }
+    }
+
+    /// Remove the index of the id, once its segment is dropped.
+    pub(crate) fn remove(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, tick)) = inner.indices.remove(&id) {
+            inner.ticks.remove(&tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(key: &[u8]) -> Arc<BlockIndex> {
+        let mut index = BlockIndex::default();
+        index.push(key, 0);
+        Arc::new(index)
+    }
+
+    #[test]
+    fn the_index_cache_keeps_the_most_recently_used_indices() {
+        let cache = IndexCache::new(2);
+        let ids: Vec<u64> = (0..4).map(|_| cache.register()).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+        cache.insert(0, index(b"a"));
+        cache.insert(1, index(b"b"));
+        // Reading the first index makes the second the least recently used.
+        assert_eq!(cache.get(0).unwrap().key(0), b"a");
+        cache.insert(2, index(b"c"));
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(0).unwrap().key(0), b"a");
+        assert_eq!(cache.get(2).unwrap().key(0), b"c");
+        for id in 3..100 {
+            cache.insert(id, index(b"d"));
+            let inner = cache.inner.lock().unwrap();
+            assert!(inner.indices.len() <= 2 && inner.ticks.len() <= 2);
+        }
+        cache.remove(99);
+        assert!(cache.get(99).is_none());
+        assert_eq!(cache.inner.lock().unwrap().indices.len(), 1);
+    }
+}
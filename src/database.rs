@@ -1,20 +1,38 @@
 //! The [`Database`] structure.
 
+use crate::cache::ValueCache;
 use crate::errors::MapError;
-use crate::memtable::Memtable;
+use crate::iter::{glob_match, glob_prefix, prefix_end, MergeIter, Source};
 pub use crate::memtable::MemtableError;
-use crate::segment::{RawSegment, Segment};
+use crate::memtable::{Entry, Memtable, Recovery};
+use crate::merger::Merger;
+use crate::schema::{KeyNormalizer, WriteCheck};
+use crate::segment::{
+    open_pack, record_value, sync_parent_dir, Blocks, BytesPool, OnCorrupt, RawSegment,
+    ReadOptions, Segment, SegmentSet, SegmentWriter, Segments,
+};
+use crate::stats::SlowOps;
+use crate::trace::{Op, OpTrace, Outcome};
 use crate::traits::Map;
-use crate::Get;
+use crate::txn::Txn;
+use crate::{
+    BuilderError, DatabaseBuilder, DatabaseHandle, DiskUsage, Get, Health, KeySchema, LazyValue,
+    RawEntry, ReadStats, ReclaimedBytes, RecoveryReport, SegmentInfo, TuningHint, ValueResolver,
+};
 use bytes::Bytes;
-use csv::WriterBuilder;
-use std::collections::BTreeMap;
-use std::fs::OpenOptions;
+use csv::ByteRecord;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
 
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock, PoisonError, RwLock, RwLockWriteGuard};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::{ffi::OsString, fs::DirBuilder, path::Path};
 use thiserror::Error;
 
@@ -36,324 +54,1784 @@ pub enum Error {
     /// Parse segment id error.
     #[error("error parsing {0} into segment id")]
     ParseSegemntId(String),
+
+    /// The builder is misconfigured.
+    #[error(transparent)]
+    Builder(#[from] BuilderError),
+
+    /// Replaying the logs takes longer than the recovery timeout.
+    #[error("recovery timed out")]
+    RecoveryTimedOut,
+
+    /// A record of a log is corrupt but not at the end of the log, found by a strict
+    /// recovery.
+    #[error("corrupt record at offset {offset} of log {path:?}")]
+    CorruptWal {
+        /// The path of the log.
+        path: PathBuf,
+        /// The offset of the record in the log.
+        offset: u64,
+    },
+
+    /// The given max segment id is less than the id of an existing segment.
+    #[error("max segment id {given} is less than the existing segment id {existing}")]
+    InvalidMaxSegmentId {
+        /// The given max segment id.
+        given: u64,
+        /// The largest existing segment id.
+        existing: u64,
+    },
+
+    /// There is no segment with the given id.
+    #[error("segment {0} not found")]
+    SegmentNotFound(u64),
+
+    /// The data folder is held by another open database, which may be in another
+    /// process.
+    #[error("the data folder is locked by another database: {0:?}")]
+    Locked(PathBuf),
+}
+
+pub(crate) const DOT: char = '.';
+
+/// The file in the data folder locked by the database holding the folder.
+const LOCK_FILE: &str = "LOCK";
+
+/// Where reads look for a key first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// The memtable first, so the newest write wins. This is the default.
+    #[default]
+    MemtableFirst,
+    /// The segments first, so data loaded into segments wins over the memtable.
+    SegmentsFirst,
+}
+
+/// How the records of new segment files are laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// A record per entry holding the key, the value and the sequence number. This is
+    /// the default.
+    #[default]
+    Rows,
+    /// The keys and the sequence numbers in one region and the values in another, so
+    /// scanning the keys only, as [`Database::keys`] does, skips the values.
+    Columns,
 }
 
-const DOT: char = '.';
-const TMP_SUFFIX: &str = "tmp";
+/// What a write does when the memtable reaches the stall threshold, see
+/// [`DatabaseBuilder::stall_threshold`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteStall {
+    /// Wait until the frozen memtable is written out. This is the default.
+    ///
+    /// A failed flush leaves its frozen memtable in place, so the writes stay stalled
+    /// until a later flush, such as one by [`Database::flush`], writes it out.
+    #[default]
+    Block,
+    /// Fail with [`MapError::WriteStalled`], leaving the caller to back off.
+    Fail,
+}
+
+/// The result of [`Database::get_or_reserve`], handing back the key either way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetOrReserve {
+    /// The key has a value.
+    Found {
+        /// The key looked up.
+        key: Bytes,
+        /// The value of the key.
+        value: Arc<Bytes>,
+    },
+    /// The key is missing or deleted.
+    Vacant(Bytes),
+}
+
+impl GetOrReserve {
+    /// The key looked up, to be set without copying it again.
+    pub fn into_key(self) -> Bytes {
+        match self {
+            Self::Found { key, .. } | Self::Vacant(key) => key,
+        }
+    }
+}
 
-type Segments = BTreeMap<u64, Segment>;
+/// A background task of a [`Database`], reported to the panic hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackgroundTask {
+    /// Writing a frozen memtable out to a segment.
+    Flush,
+    /// Merging the segments every merge period.
+    Merge,
+    /// Flushing the memtable once idle, see [`DatabaseBuilder::idle_flush`].
+    IdleFlush,
+    /// Reading ahead the blocks after a key, see [`Database::get_prefetch`].
+    Prefetch,
+}
+
+/// A function called with the task and the message of a panic of a background task, see
+/// [`DatabaseBuilder::panic_hook`].
+pub type PanicHookFn = dyn Fn(BackgroundTask, &str) + Send + Sync;
+
+/// A shared [`PanicHookFn`].
+#[derive(Clone)]
+pub(crate) struct PanicHook(pub(crate) Arc<PanicHookFn>);
+
+impl fmt::Debug for PanicHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PanicHook")
+    }
+}
+
+/// A spawned background task and its thread.
+type Task = (
+    BackgroundTask,
+    thread::JoinHandle<Result<(), std::io::Error>>,
+);
+
+/// A read ahead of [`Database::get_prefetch`]: the key, the number of blocks after it
+/// and the segments to read them in.
+type Prefetch = (Bytes, usize, Arc<Segments>);
+
+/// The number of prefetches waiting for the prefetch task, beyond which new ones are
+/// dropped, as they are only hints.
+const PREFETCH_QUEUE_LEN: usize = 16;
+
+/// The last error of each background task, cleared once the task succeeds again.
+#[derive(Debug, Default)]
+pub(crate) struct BackgroundErrors(Mutex<HashMap<BackgroundTask, String>>);
+
+impl BackgroundErrors {
+    pub(crate) fn record<T, E: fmt::Display>(&self, task: BackgroundTask, res: &Result<T, E>) {
+        let mut errors = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match res {
+            Ok(_) => errors.remove(&task),
+            Err(err) => errors.insert(task, err.to_string()),
+        };
+    }
+
+    fn first(&self) -> Option<(BackgroundTask, String)> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .next()
+            .map(|(task, message)| (*task, message.clone()))
+    }
+}
+
+/// The number of the flushes done, which the stalled writes wait on.
+#[derive(Default)]
+struct Flushes {
+    count: Mutex<u64>,
+    done: Condvar,
+}
+
+impl Flushes {
+    fn count(&self) -> u64 {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn notify(&self) {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.done.notify_all();
+    }
+
+    /// Wait until a flush is done after the `count`th one.
+    fn wait(&self, count: u64) {
+        let guard = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        let _guard = self
+            .done
+            .wait_while(guard, |done| *done == count)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    /// Wait like [`Flushes::wait`], for at most `timeout`.
+    fn wait_timeout(&self, count: u64, timeout: Duration) {
+        let guard = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        let _guard = self
+            .done
+            .wait_timeout_while(guard, timeout, |done| *done == count)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
 
 /// A [`Database`] instance.
 pub struct Database {
-    block_size: u64,
+    blocks: Blocks,
+    read_options: ReadOptions,
+    max_merge_segments: usize,
+    self_compact_tombstone_ratio: f64,
+    max_segments: Option<usize>,
+    pack_segments: Option<usize>,
     merge_period: std::time::Duration,
     poll_period: std::time::Duration,
     data_dir: PathBuf,
     data_suffix: String,
-    exiter: Option<mpsc::Sender<()>>,
+    tmp_suffix: String,
+    pack_suffix: String,
+    exiters: Vec<mpsc::Sender<()>>,
     memtable: Arc<RwLock<Memtable>>,
-    segments: Arc<RwLock<Segments>>,
+    segments: Arc<SegmentSet>,
     max_segment_id: Arc<Mutex<u64>>,
-    tasks: Vec<thread::JoinHandle<Result<(), std::io::Error>>>,
+    tasks: Mutex<Vec<Task>>,
+    /// The queue of the prefetch task, which is started by the first prefetch.
+    prefetches: OnceLock<mpsc::SyncSender<Prefetch>>,
+    background_errors: Arc<BackgroundErrors>,
+    /// The number of the disk probes of [`Database::health`], naming their files apart.
+    health_probes: AtomicU64,
+    stall_threshold: Option<usize>,
+    write_stall: WriteStall,
+    sync_flush: bool,
+    panic_hook: Option<PanicHook>,
+    slow_ops: SlowOps,
+    flushes: Arc<Flushes>,
+    value_resolver: Option<Arc<dyn ValueResolver>>,
+    write_check: WriteCheck,
+    key_normalizer: Option<KeyNormalizer>,
+    read_order: ReadOrder,
+    strict_reads: bool,
+    segment_format: SegmentFormat,
+    value_cache: Option<Arc<ValueCache>>,
+    op_trace: Option<OpTrace>,
+    _lock: File,
 }
 
 impl Database {
     /// Create a new [`Database`] with a data folder path.
-    pub(crate) fn new(
-        path: &Path,
-        log_suffix: &str,
-        data_suffix: &str,
-        switch_mem_size: usize,
-        merge_period: std::time::Duration,
-        poll_period: std::time::Duration,
-        block_size: u64,
-    ) -> Result<Self, Error> {
+    pub(crate) fn new(path: &Path, options: &DatabaseBuilder) -> Result<Self, Error> {
+        let (mut db, frozen) = Self::open_without_tasks(path, options)?;
+        for _ in 0..frozen {
+            db.write_new_segment()?;
+        }
+        if options.auto_merge {
+            db.start_merging_task();
+        }
+        if let Some(idle) = options.idle_flush {
+            db.start_idle_flush_task(idle);
+        }
+        Ok(db)
+    }
+
+    /// Replay the logs and write them all out to segments, see
+    /// [`DatabaseBuilder::recover`].
+    pub(crate) fn recover(path: &Path, options: &DatabaseBuilder) -> Result<RecoveryReport, Error> {
+        let (db, mut frozen) = Self::open_without_tasks(path, options)?;
+        let (keys_recovered, records_skipped) = {
+            let mut memtable = db.memtable.write().unwrap_or_else(PoisonError::into_inner);
+            let keys = memtable
+                .entries(Bound::Unbounded, Bound::Unbounded)
+                .iter()
+                .map(Vec::len)
+                .sum();
+            if !memtable.is_active_empty() {
+                memtable.force_switch()?;
+                frozen += 1;
+            }
+            (keys, memtable.skipped_records())
+        };
+        // The trees are written in the order of the logs, each removing its log, and the
+        // empty active log left is removed on drop.
+        let merger = db.merger();
+        for _ in 0..frozen {
+            write_oldest_frozen(&merger, &db.memtable)?;
+        }
+        tracing::info!(
+            "recovered {} keys into {} segments, skipping {} corrupt records",
+            keys_recovered,
+            frozen,
+            records_skipped
+        );
+        Ok(RecoveryReport {
+            keys_recovered,
+            segments_written: frozen,
+            records_skipped,
+        })
+    }
+
+    /// Open the database with the frozen trees not written out yet and no background
+    /// tasks, returning the number of the frozen trees.
+    fn open_without_tasks(path: &Path, options: &DatabaseBuilder) -> Result<(Self, usize), Error> {
+        let log_suffix = options.log_suffix.as_str();
+        let data_suffix = options.data_suffix.as_str();
+        options.validate()?;
+        let recovery = Recovery {
+            deadline: options
+                .recovery_timeout
+                .map(|timeout| std::time::Instant::now() + timeout),
+            strict: options.strict_recovery,
+        };
         DirBuilder::new().recursive(true).create(path)?;
+        let lock = lock_dir(path)?;
+        let log_dir = options.log_dir.as_deref().unwrap_or(path);
+        DirBuilder::new().recursive(true).create(log_dir)?;
 
         let mut logs = BTreeMap::new();
         let mut segments = BTreeMap::new();
+        let mut packed = Vec::new();
+        let mut max_tmp_id = 0;
+        // Shared by all the segments, so the cache of their indices is.
+        let read_options = options.read_options();
 
-        for entry in path.read_dir()? {
-            if let Ok(entry) = entry {
+        for entry in path.read_dir()?.flatten() {
+            if let Some((id, suffix)) = entry
+                .file_name()
+                .into_string()
+                .map_err(Error::InvalidLogFileName)?
+                .rsplit_once(DOT)
+            {
+                if suffix == log_suffix {
+                    if options.log_dir.is_none() {
+                        logs.insert(id.to_string(), entry.path());
+                    }
+                } else if suffix == data_suffix {
+                    let (id, segment) =
+                        open_segment(id, &entry.path(), &read_options, options.blocks())?;
+                    segments.insert(id, Arc::new(segment));
+                } else if suffix == options.pack_suffix {
+                    packed.extend(open_packed_segments(
+                        &entry.path(),
+                        &read_options,
+                        options.blocks(),
+                    )?);
+                } else if suffix == options.tmp_suffix {
+                    if let Ok(id) = id.parse::<u64>() {
+                        max_tmp_id = max_tmp_id.max(id);
+                    }
+                    // A segment is only complete once renamed from its temporary file, and
+                    // the logs or the segments it is written from are still there, so a
+                    // file left by a stop in the middle of a flush or a merge is dropped.
+                    tracing::warn!("removing unfinished segment file {:?}", entry.path());
+                    if let Err(err) = std::fs::remove_file(entry.path()) {
+                        tracing::warn!("failed to remove {:?}: err={}", entry.path(), err);
+                    }
+                }
+            }
+        }
+        if options.log_dir.is_some() {
+            for entry in log_dir.read_dir()?.flatten() {
                 if let Some((id, suffix)) = entry
                     .file_name()
                     .into_string()
-                    .map_err(|s| Error::InvalidLogFileName(s))?
+                    .map_err(Error::InvalidLogFileName)?
                     .rsplit_once(DOT)
                 {
                     if suffix == log_suffix {
                         logs.insert(id.to_string(), entry.path());
-                    } else if suffix == data_suffix {
-                        let id = id
-                            .parse()
-                            .map_err(|_| Error::ParseSegemntId(id.to_string()))?;
-                        let mut segment = Segment::from_path(&entry.path());
-                        segment.initialize_index(block_size)?;
-                        segments.insert(id, segment);
                     }
                 }
             }
         }
-        let max_segment_id: u64 = segments
-            .iter()
-            .next_back()
-            .map(|(id, _)| *id)
-            .unwrap_or_default();
+        for (id, segment) in packed {
+            if let Some(unpacked) = segments.insert(id, Arc::new(segment)) {
+                // The file is left by a stop right after the pack is written.
+                unpacked.mark_obsolete();
+            }
+        }
+        let (replaces, replaced) = split_off_replaced(&mut segments);
+        for (id, segment) in replaced {
+            tracing::info!("dropping segment {} replaced by segment {}", id, replaces);
+            segment.mark_obsolete();
+        }
+        let ids: Vec<u64> = segments.keys().copied().collect();
+        for pair in ids.windows(2) {
+            if pair[1] > pair[0] + 1 {
+                tracing::warn!(
+                    "segment ids {}..{} are missing, probably left by an unfinished write",
+                    pair[0] + 1,
+                    pair[1]
+                );
+            }
+        }
+        // Ids are never reused, not even the id of an unfinished write, so a new segment
+        // always has a larger id than all the existing ones.
+        let mut max_segment_id: u64 = ids.last().copied().unwrap_or_default().max(max_tmp_id);
+        if let Some(given) = options.max_segment_id {
+            if given < max_segment_id {
+                return Err(Error::InvalidMaxSegmentId {
+                    given,
+                    existing: max_segment_id,
+                });
+            }
+            max_segment_id = given;
+        }
         let data_dir = path.to_owned();
         let data_suffix = data_suffix.to_string();
-        let (memtable, segment) = Memtable::new(logs, path, log_suffix, switch_mem_size)?;
+        let flushed_log_id = segments
+            .values()
+            .map(|segment: &Arc<Segment>| segment.footer().log_id)
+            .max()
+            .unwrap_or_default();
+        let flushed_seq = max_seq(&segments);
+        let memtable = Memtable::new(
+            logs,
+            log_dir,
+            log_suffix,
+            options.switch_mem_size,
+            recovery,
+            flushed_log_id,
+            flushed_seq,
+        )
+        .map_err(|err| match err {
+            MemtableError::RecoveryTimedOut => Error::RecoveryTimedOut,
+            MemtableError::CorruptWal { path, offset } => Error::CorruptWal { path, offset },
+            err => Error::Memtable(err),
+        })?;
+        let op_trace = match &options.op_trace {
+            Some(path) => Some(OpTrace::open(path)?),
+            None => None,
+        };
+        let frozen = memtable.frozen_count();
         let memtable = Arc::new(RwLock::new(memtable));
-        let segments = Arc::new(RwLock::new(segments));
-        let mut db = Self {
-            block_size,
-            exiter: None,
+        let segments = Arc::new(SegmentSet::new(segments));
+        let db = Self {
+            blocks: options.blocks(),
+            read_options,
+            exiters: Vec::new(),
             data_dir,
             memtable,
             data_suffix,
+            tmp_suffix: options.tmp_suffix.clone(),
+            pack_suffix: options.pack_suffix.clone(),
             segments,
             max_segment_id: Arc::new(Mutex::new(max_segment_id)),
-            tasks: Vec::new(),
-            merge_period,
-            poll_period,
+            tasks: Mutex::new(Vec::new()),
+            prefetches: OnceLock::new(),
+            background_errors: Arc::default(),
+            health_probes: AtomicU64::new(0),
+            stall_threshold: options.stall_threshold,
+            write_stall: options.write_stall,
+            sync_flush: options.sync_flush,
+            panic_hook: options.panic_hook.clone(),
+            slow_ops: SlowOps(options.slow_op_threshold),
+            flushes: Arc::default(),
+            max_merge_segments: options.max_merge_segments,
+            self_compact_tombstone_ratio: options.self_compact_tombstone_ratio,
+            max_segments: options.max_segments,
+            pack_segments: options.pack_segments,
+            merge_period: options.merge_period,
+            poll_period: options.poll_period,
+            value_resolver: options.value_resolver.clone(),
+            write_check: WriteCheck {
+                key_schema: options.key_schema,
+                validator: options.validator.clone(),
+            },
+            key_normalizer: options.key_normalizer.clone(),
+            read_order: options.read_order,
+            strict_reads: options.strict_reads,
+            segment_format: options.segment_format,
+            value_cache: options
+                .value_cache_entries
+                .map(|entries| Arc::new(ValueCache::new(entries))),
+            op_trace,
+            _lock: lock,
         };
-        if let Some(segment) = segment {
-            db.write_new_segment(segment)?;
+        Ok((db, frozen))
+    }
+
+    fn merger(&self) -> Merger {
+        Merger {
+            blocks: self.blocks,
+            read_options: self.read_options.clone(),
+            max_merge_segments: self.max_merge_segments,
+            self_compact_tombstone_ratio: self.self_compact_tombstone_ratio,
+            max_segments: self.max_segments,
+            pack_segments: self.pack_segments,
+            max_segment_id: self.max_segment_id.clone(),
+            segments: self.segments.clone(),
+            dir: self.data_dir.clone(),
+            suffix: self.data_suffix.clone(),
+            tmp_suffix: self.tmp_suffix.clone(),
+            pack_suffix: self.pack_suffix.clone(),
+            segment_format: self.segment_format,
+            value_cache: self.value_cache.clone(),
+            slow_ops: self.slow_ops,
+            background_errors: self.background_errors.clone(),
         }
-        db.start_merging_task();
-        Ok(db)
+    }
+
+    /// Spawn a thread running `f`, reporting a panic to the panic hook before it goes on
+    /// to the join.
+    fn spawn_task<F, R>(&self, task: BackgroundTask, f: F) -> thread::JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let hook = self.panic_hook.clone();
+        let errors = self.background_errors.clone();
+        thread::spawn(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(res) => res,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                tracing::error!("the {:?} task panicked: {}", task, message);
+                errors.record::<(), _>(task, &Err(format!("panicked: {}", message)));
+                if let Some(hook) = &hook {
+                    (hook.0)(task, message);
+                }
+                panic::resume_unwind(payload)
+            }
+        })
     }
 
     fn start_merging_task(&mut self) {
         let (tx, rx) = mpsc::channel();
-        let max_segment_id = self.max_segment_id.clone();
-        let segments = self.segments.clone();
-        let dir = self.data_dir.clone();
-        let suffix = self.data_suffix.clone();
+        let merger = self.merger();
         let merge_period = self.merge_period;
         let poll_period = self.poll_period;
-        let block_size = self.block_size;
-        let task = thread::spawn(move || -> Result<(), std::io::Error> {
-            Self::merge_segments(
-                block_size,
-                merge_period,
-                poll_period,
-                rx,
-                max_segment_id,
-                segments,
-                dir,
-                suffix,
-            )
+        let task = self.spawn_task(BackgroundTask::Merge, move || {
+            merger.run(merge_period, poll_period, rx)
+        });
+        self.exiters.push(tx);
+        self.tasks
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((BackgroundTask::Merge, task));
+    }
+
+    /// Spawn a task flushing the active memtable once it has been idle for `idle`.
+    fn start_idle_flush_task(&mut self, idle: std::time::Duration) {
+        let (tx, rx) = mpsc::channel();
+        let merger = self.merger();
+        let memtable = self.memtable.clone();
+        let flushes = self.flushes.clone();
+        let errors = self.background_errors.clone();
+        let poll_period = self.poll_period;
+        let task = self.spawn_task(BackgroundTask::IdleFlush, move || {
+            loop {
+                thread::sleep(poll_period);
+                match rx.try_recv() {
+                    Ok(()) | Err(mpsc::TryRecvError::Disconnected) => break,
+                    Err(mpsc::TryRecvError::Empty) => {}
+                }
+                let idle_for = |memtable: &Memtable| {
+                    !memtable.is_active_empty() && memtable.idle_time() >= idle
+                };
+                if !idle_for(&memtable.read().unwrap_or_else(PoisonError::into_inner)) {
+                    continue;
+                }
+                let switched = {
+                    let mut memtable = memtable.write().unwrap_or_else(PoisonError::into_inner);
+                    // A write may come in between the two locks.
+                    idle_for(&memtable) && memtable.force_switch().is_ok()
+                };
+                if switched {
+                    tracing::info!("flushing the memtable idle for {:?}", idle);
+                    let res = write_oldest_frozen(&merger, &memtable);
+                    if let Err(err) = &res {
+                        tracing::error!("failed to flush the idle memtable: err={}", err);
+                    }
+                    errors.record(BackgroundTask::IdleFlush, &res);
+                    flushes.notify();
+                }
+            }
+            Ok(())
+        });
+        self.exiters.push(tx);
+        self.tasks
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((BackgroundTask::IdleFlush, task));
+    }
+
+    /// Spawn the task reading ahead the blocks of the prefetches sent to the returned
+    /// queue, until the queue is dropped.
+    fn start_prefetch_task(&self) -> mpsc::SyncSender<Prefetch> {
+        let (tx, rx) = mpsc::sync_channel::<Prefetch>(PREFETCH_QUEUE_LEN);
+        let task = self.spawn_task(BackgroundTask::Prefetch, move || {
+            for (key, ahead, segments) in rx {
+                for segment in segments.values() {
+                    if let Err(err) = segment.prefetch(&key, ahead) {
+                        tracing::debug!("failed to prefetch {:?}: err={}", segment.path(), err);
+                    }
+                }
+            }
+            Ok(())
         });
-        self.exiter = Some(tx);
-        self.tasks.push(task);
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((BackgroundTask::Prefetch, task));
+        tx
+    }
+
+    /// Turn the database into a [`DatabaseHandle`] that can be cloned and shared with
+    /// other threads.
+    pub fn into_handle(self) -> DatabaseHandle {
+        DatabaseHandle::from(self)
     }
 
     /// Force close.
     pub fn force_close(&mut self) {
-        if let Some(exiter) = self.exiter.take() {
+        for exiter in self.exiters.drain(..) {
             let _ = exiter.send(());
         }
-        self.tasks.clear();
+        self.prefetches.take();
+        self.tasks
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Write the active memtable out to a new segment, even if it is not big enough to
+    /// switch yet.
+    pub fn flush(&self) -> Result<(), MapError> {
+        let res = self.flush_active();
+        self.trace(Op::Flush, b"", b"", Outcome::of(&res));
+        res
+    }
+
+    /// Wait until the write of the key seen now is in a segment, returning `false` if it
+    /// is not within `timeout`.
+    ///
+    /// The write counts as flushed once a segment has an entry of the key at least as new,
+    /// so a write after the call does not have to be flushed, and a key missing from both
+    /// the memtable and the segments is only flushed if it is written meanwhile.
+    pub fn wait_until_flushed<Q>(&self, key: &Q, timeout: Duration) -> Result<bool, Error>
+    where
+        Q: ?Sized + AsRef<[u8]>,
+    {
+        let normalized = KeyNormalizer::apply(self.key_normalizer.as_ref(), key.as_ref());
+        let key = normalized.as_deref().unwrap_or(key.as_ref());
+        let seq = self
+            .memtable
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .lookup(key)
+            .map_or(0, |entry| entry.seq);
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Taken before the lookup, so a flush done after it ends the wait at once.
+            let count = self.flushes.count();
+            let entry = get_from_segments(&self.segments.snapshot(), key, self.strict_reads)
+                .map_err(|err| match err {
+                    MapError::Io(err) => err,
+                    err => std::io::Error::other(err),
+                })?;
+            if entry.is_some_and(|entry| entry.seq >= seq) {
+                return Ok(true);
+            }
+            match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => self.flushes.wait_timeout(count, left),
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    fn flush_active(&self) -> Result<(), MapError> {
+        {
+            let mut memtable = self.memtable.write().map_err(|_| MapError::WriteLock)?;
+            if memtable.is_active_empty() {
+                return Ok(());
+            }
+            memtable.force_switch()?;
+        }
+        self.write_new_segment()?;
+        Ok(())
+    }
+
+    /// Set the value of the key without writing it to the log, which is faster for data
+    /// that can be regenerated.
+    ///
+    /// The write is lost if the process crashes before the memtable holding it is written
+    /// out to a segment, by a switch, a flush or closing the database, and the previous
+    /// value of the key comes back in that case. Reads see it right away like any write.
+    pub fn set_ephemeral<K: Into<Bytes>, V: Into<Bytes>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<(), MapError> {
+        self.set_ephemeral_shared(key.into(), value.into())
+    }
+
+    /// Insert the key with an empty value, for using the database as a set of keys, and
+    /// return whether it was missing before.
+    ///
+    /// An empty value takes no space in the segments beyond its separator, and `get`
+    /// returns an empty value for a key that was inserted.
+    pub fn insert_key<K: Into<Bytes>>(&mut self, key: K) -> Result<bool, MapError> {
+        let key = key.into();
+        let inserted = self.value_len(&key)?.is_none();
+        if inserted {
+            self.set_shared(key, Bytes::new())?;
+        }
+        Ok(inserted)
+    }
+
+    /// Look up the owned key, handing it back with the value if found and on its own if
+    /// not, so the key can be set next without allocating it again.
+    pub fn get_or_reserve(&self, key: Bytes) -> Result<GetOrReserve, MapError> {
+        Ok(match self.get(&key)? {
+            Some(value) => GetOrReserve::Found { key, value },
+            None => GetOrReserve::Vacant(key),
+        })
+    }
+
+    /// Write the entries directly to a new segment, skipping the memtable and the log.
+    ///
+    /// This is much faster than `set` for bulk loading, but the caller must guarantee
+    /// that the entries are sorted by key without duplicates, or the ingest fails with
+    /// nothing written. With a key normalizer the keys are normalized first, and the
+    /// normalized keys must be sorted without duplicates. The ingested entries are newer than the entries in all existing
+    /// segments, and older than the entries still in the memtable.
+    pub fn ingest_sorted<I: IntoIterator<Item = (Bytes, Bytes)>>(
+        &mut self,
+        sorted: I,
+    ) -> Result<(), Error> {
+        let res = self.ingest(self.traced_entries(Op::IngestEntry, sorted));
+        self.trace(Op::Ingest, b"", b"", Outcome::of(&res));
+        res
+    }
+
+    fn ingest<I: IntoIterator<Item = (Bytes, Bytes)>>(&self, sorted: I) -> Result<(), Error> {
+        let mut sorted = sorted.into_iter().peekable();
+        if sorted.peek().is_none() {
+            return Ok(());
+        }
+        let mut segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.merger().make_room(&mut segment_id)?;
+        *segment_id += 1;
+        let path = self
+            .data_dir
+            .as_path()
+            .join(format!("{}{}{}", segment_id, DOT, self.data_suffix));
+        let tmp_path = self
+            .data_dir
+            .as_path()
+            .join(format!("{}{}{}", segment_id, DOT, self.tmp_suffix));
+        tracing::info!("ingesting segment {} to path {:?}", segment_id, tmp_path);
+        // Taking the largest sequence number of the segments ties with the newest of them,
+        // and a tie is won by the segment with the larger id. Entries in the memtable
+        // always have larger sequence numbers.
+        let seq = max_seq(&self.segments.snapshot());
+        // The keys are stored normalized, so the normalized keys must still be sorted,
+        // which the writer checks.
+        let normalizer = self.key_normalizer.as_ref();
+        let sorted = sorted
+            .map(|(key, value)| (KeyNormalizer::apply(normalizer, &key).unwrap_or(key), value));
+        let result = RawSegment::from_sorted_iter(seq, sorted)
+            .write_to_path(&tmp_path, self.segment_format, self.blocks)
+            .and_then(|mut segment| {
+                segment.set_read_options(&self.read_options)?;
+                segment.initialize_index(self.blocks)?;
+                segment.move_to(&path)?;
+                Ok(segment)
+            });
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        let segment = result?;
+        let id = *segment_id;
+        self.segments
+            .update(|segments| segments.insert(id, Arc::new(segment)));
+        self.clear_value_cache();
+        tracing::info!("ingested segment {} to path {:?}", segment_id, path);
+        self.merger().pack()?;
+        Ok(())
+    }
+
+    /// Replace all the data with the entries, which must be sorted by key without
+    /// duplicates, or the replace fails with nothing changed. The keys are normalized and
+    /// the entries checked like those of `set`, and a rejected entry fails the replace
+    /// with nothing changed as well.
+    ///
+    /// The entries are written to a new segment first, which then takes the place of all
+    /// the segments and the memtable at once, so reads and scans see either the old data
+    /// or the new data. The segment records that it replaces the older ones, so they are
+    /// dropped on open if a stop leaves them behind. Writes made while the entries are
+    /// written are replaced as well.
+    pub fn replace_all<I: IntoIterator<Item = (Bytes, Bytes)>>(
+        &mut self,
+        sorted: I,
+    ) -> Result<(), Error> {
+        self.replace_all_shared(sorted)
+    }
+
+    /// Replace all the data like [`Database::replace_all`], with a shared reference.
+    pub(crate) fn replace_all_shared<I: IntoIterator<Item = (Bytes, Bytes)>>(
+        &self,
+        sorted: I,
+    ) -> Result<(), Error> {
+        let res = self.replace_with(self.traced_entries(Op::ReplaceEntry, sorted));
+        self.trace(Op::ReplaceAll, b"", b"", Outcome::of(&res));
+        res
+    }
+
+    fn replace_with<I: IntoIterator<Item = (Bytes, Bytes)>>(&self, sorted: I) -> Result<(), Error> {
+        // Holding the lock keeps merges and flushes from touching the segments meanwhile.
+        let mut segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *segment_id += 1;
+        let id = *segment_id;
+        let path = self
+            .data_dir
+            .as_path()
+            .join(format!("{}{}{}", id, DOT, self.data_suffix));
+        let tmp_path = self
+            .data_dir
+            .as_path()
+            .join(format!("{}{}{}", id, DOT, self.tmp_suffix));
+        tracing::info!(
+            "replacing all data with segment {} at path {:?}",
+            id,
+            tmp_path
+        );
+        let seq = self
+            .memtable
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .last_seq();
+        let result = SegmentWriter::create(&tmp_path, self.segment_format, self.blocks)
+            .and_then(|mut writer| {
+                for (key, value) in sorted {
+                    let key =
+                        KeyNormalizer::apply(self.key_normalizer.as_ref(), &key).unwrap_or(key);
+                    self.write_check.check(&key, Some(&value)).map_err(|err| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+                    })?;
+                    writer.write(&key, Some(&value), seq)?;
+                }
+                Ok(writer)
+            })
+            .and_then(|mut writer| {
+                let mut memtable = self
+                    .memtable
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner);
+                // The footer is written with the writers blocked, so the logs of all the
+                // writes replaced are covered by its log id.
+                writer.log_id(memtable.active_log_id());
+                writer.replaces(id);
+                let mut segment = writer.finish()?;
+                segment.set_read_options(&self.read_options)?;
+                segment.initialize_index(self.blocks)?;
+                segment.move_to(&path)?;
+                self.segments.update(|segments| {
+                    for (_, old_segment) in std::mem::take(segments) {
+                        old_segment.mark_obsolete();
+                    }
+                    segments.insert(id, Arc::new(segment));
+                });
+                memtable.discard_all()
+            });
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result?;
+        self.clear_value_cache();
+        // The memtables waited for by the stalled writes are gone.
+        self.flushes.notify();
+        tracing::info!("replaced all data with segment {} at path {:?}", id, path);
+        Ok(())
     }
 
-    fn write_new_segment(&mut self, segment: RawSegment) -> Result<(), std::io::Error> {
+    /// Spawn a task writing the oldest frozen tree out to a new segment, or write it
+    /// before returning with a sync flush.
+    ///
+    /// The tasks take the lock of the segment id in turn and always write the oldest
+    /// frozen tree left, so the segments are created in the order of the switches.
+    fn write_new_segment(&self) -> Result<(), std::io::Error> {
         let memtable = self.memtable.clone();
-        let segments = self.segments.clone();
-        let dir = self.data_dir.clone();
-        let suffix = self.data_suffix.clone();
-        let max_segment_id = self.max_segment_id.clone();
-        let block_size = self.block_size;
-        let task = thread::spawn(move || -> Result<(), std::io::Error> {
-            let mut segment_id = max_segment_id.lock().unwrap();
-            *segment_id += 1;
-            let path = dir
-                .as_path()
-                .join(format!("{}{}{}", segment_id, DOT, suffix));
-            let tmp_path = dir
-                .as_path()
-                .join(format!("{}{}{}", segment_id, DOT, TMP_SUFFIX));
-            tracing::info!("writing new segment {} to path {:?}", segment_id, tmp_path);
-            let mut segment = segment.write_to_path(&tmp_path)?;
-            segment.initialize_index(block_size)?;
-            segment.move_to(&path)?;
-            tracing::info!("new segment {} is written to path {:?}", segment_id, path);
-            memtable.write().unwrap().finalize_switch()?;
-            segments.write().unwrap().insert(*segment_id, segment);
-            Ok(())
+        let merger = self.merger();
+        let flushes = self.flushes.clone();
+        let errors = self.background_errors.clone();
+        if self.sync_flush {
+            let res = write_oldest_frozen(&merger, &memtable);
+            flushes.notify();
+            return res;
+        }
+        let task = self.spawn_task(BackgroundTask::Flush, move || {
+            let res = write_oldest_frozen(&merger, &memtable);
+            errors.record(BackgroundTask::Flush, &res);
+            // A failed flush wakes the stalled writes as well, which stall again.
+            flushes.notify();
+            res
         });
-        self.tasks.push(task);
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((BackgroundTask::Flush, task));
         Ok(())
     }
 
-    fn get_from_segments<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
+    /// Run `f` as a transaction, applying its writes as a whole if it returns `Ok`.
+    ///
+    /// Other writers are blocked until the transaction ends, so the reads of the
+    /// transaction are not changed by others, and others see either none or all of its
+    /// writes. The writes are buffered until `f` returns, and reads in the transaction see
+    /// its own pending writes.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R, MapError>
+    where
+        F: FnOnce(&mut Txn<'_>) -> Result<R, MapError>,
+    {
+        let (res, switched) = {
+            let memtable = self.lock_for_write()?;
+            let mut txn = Txn::new(
+                memtable,
+                self.segments.snapshot(),
+                &self.write_check,
+                self.key_normalizer.as_ref(),
+                self.read_order,
+                self.strict_reads,
+                self.value_resolver.as_ref(),
+            );
+            let res = f(&mut txn)?;
+            (
+                res,
+                txn.commit(self.value_cache.as_deref(), self.op_trace.as_ref())?,
+            )
+        };
+        if switched {
+            self.write_new_segment()?;
+        }
+        Ok(res)
+    }
+
+    /// Check whether the database is operational, for readiness probes.
+    ///
+    /// The disk is probed by writing and removing a temporary file in the data folder,
+    /// which is cheap enough to call on every probe.
+    pub fn health(&self) -> Health {
+        let tasks_alive = self
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(task, _)| *task != BackgroundTask::Flush)
+            .all(|(_, handle)| !handle.is_finished());
+        // Probes running at once each write a file of their own.
+        let probe = self.health_probes.fetch_add(1, Ordering::Relaxed);
+        let probe = self
+            .data_dir
+            .join(format!("health-{}{}{}", probe, DOT, self.tmp_suffix));
+        let disk_writable = std::fs::write(&probe, b"")
+            .and_then(|()| std::fs::remove_file(&probe))
+            .is_ok();
+        Health {
+            tasks_alive,
+            background_error: self.background_errors.first(),
+            disk_writable,
+        }
+    }
+
+    /// Information of all segments, from the oldest to the newest.
+    pub fn segment_infos(&self) -> Result<Vec<SegmentInfo>, Error> {
+        Ok(self
+            .segments
+            .snapshot()
+            .iter()
+            .map(|(id, segment)| segment_info(*id, segment))
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Disk usage of the segments and the logs.
+    ///
+    /// The reclaimable bytes are estimated from the share of the entries in the segments
+    /// that are shadowed by newer entries or deleted, so finding them scans all segments.
+    pub fn disk_usage(&self) -> Result<DiskUsage, Error> {
+        let segments = self.segments.snapshot();
+        let mut segment_bytes = 0;
+        let mut total = 0;
+        let mut sources: Vec<Source<Entry>> = Vec::new();
+        for (_, segment) in segments.iter().rev() {
+            segment_bytes += segment.size()?;
+            let footer = segment.footer();
+            total += footer.key_bytes + footer.value_bytes;
+            let entries = segment
+                .entries(Bound::Unbounded, Bound::Unbounded, OnCorrupt::Skip)?
+                .map(|entry| entry.map_err(MapError::from));
+            sources.push(Box::new(entries));
+        }
+        let mut live = 0;
+        for entry in MergeIter::by_seq(sources, Entry::seq) {
+            if let (
+                key,
+                Entry {
+                    value: Some(value), ..
+                },
+            ) = entry.map_err(|err| match err {
+                MapError::Io(err) => err,
+                err => std::io::Error::other(err),
+            })? {
+                live += (key.len() + value.len()) as u64;
+            }
+        }
+        let reclaimable_bytes = if total == 0 {
+            0
+        } else {
+            (segment_bytes as u128 * (total - live) as u128 / total as u128) as u64
+        };
+        let mut log_bytes = 0;
+        let log_paths = self
+            .memtable
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .log_paths();
+        for path in log_paths {
+            log_bytes += std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        }
+        Ok(DiskUsage {
+            segment_bytes,
+            log_bytes,
+            reclaimable_bytes,
+        })
+    }
+
+    /// Suggest changes for the current shape of the database, see [`TuningHint`].
+    ///
+    /// This reads all the segments like [`Database::disk_usage`].
+    pub fn tuning_hints(&self) -> Result<Vec<TuningHint>, Error> {
+        let mut hints = Vec::new();
+        let count = self.segments.snapshot().len();
+        if count > self.max_merge_segments {
+            hints.push(TuningHint::TooManySegments { count });
+        }
+        let usage = self.disk_usage()?;
+        if usage.reclaimable_bytes * 2 > usage.segment_bytes {
+            hints.push(TuningHint::HighSpaceAmplification {
+                reclaimable_bytes: usage.reclaimable_bytes,
+                segment_bytes: usage.segment_bytes,
+            });
+        }
+        let ((size, switch_mem_size), frozen) = {
+            let memtable = self.memtable.read().unwrap_or_else(PoisonError::into_inner);
+            (memtable.active_size(), memtable.frozen_count())
+        };
+        if frozen > 0 && size > switch_mem_size.saturating_mul(2) {
+            hints.push(TuningHint::LargeMemtable {
+                size,
+                switch_mem_size,
+            });
+        }
+        Ok(hints)
+    }
+
+    /// Read all the segment files once, so the first lookups in them are served from the
+    /// page cache of the OS instead of the disk.
+    ///
+    /// The indices of the segments are already built when they are opened or written.
+    pub fn warm_up(&self) -> Result<(), Error> {
+        for (_, segment) in self.segments.snapshot().iter() {
+            std::io::copy(&mut segment.open(0)?, &mut std::io::sink())?;
+        }
+        Ok(())
+    }
+
+    /// Get the value of the key like [`Get::get`], and read the `ahead` blocks following
+    /// the key in every segment in the background.
+    ///
+    /// This is a hint for scanning ahead with lookups, as the next keys are likely in the
+    /// following blocks, which are then served from the page cache of the OS like the
+    /// segments read by [`Database::warm_up`]. The blocks are read by a single task in
+    /// the order of the calls, and a prefetch is dropped if many are already waiting.
+    pub fn get_prefetch<Q>(&self, key: &Q, ahead: usize) -> Result<Option<Arc<Bytes>>, MapError>
     where
         Q: ?Sized,
         Q: AsRef<[u8]>,
     {
-        for (_, segment) in self
+        let value = self.get(key)?;
+        if ahead > 0 {
+            let key = KeyNormalizer::apply(self.key_normalizer.as_ref(), key.as_ref())
+                .unwrap_or_else(|| Bytes::copy_from_slice(key.as_ref()));
+            let segments = self.segments.snapshot();
+            let prefetches = self.prefetches.get_or_init(|| self.start_prefetch_task());
+            if let Err(err) = prefetches.try_send((key, ahead, segments)) {
+                tracing::debug!("dropped a prefetch: err={}", err);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Merge all segments into one, dropping the shadowed entries and the tombstones.
+    ///
+    /// Entries still in the memtable are not touched, call [`Database::flush`] first to
+    /// compact them as well.
+    pub fn compact(&self) -> Result<(), Error> {
+        let res = self.merger().compact();
+        self.clear_value_cache();
+        self.trace(Op::Compact, b"", b"", Outcome::of(&res));
+        Ok(res?)
+    }
+
+    /// Merge only the segments written more than `age` ago, leaving the recent ones
+    /// untouched.
+    ///
+    /// This keeps the cold data apart from the hot data. The merged segment is written
+    /// now, so it is only merged again once it is older than `age` itself.
+    pub fn compact_older_than(&self, age: Duration) -> Result<(), Error> {
+        let res = self.merger().compact_older_than(age);
+        self.clear_value_cache();
+        self.trace(
+            Op::CompactOlderThan,
+            age.as_millis().to_string().as_bytes(),
+            b"",
+            Outcome::of(&res),
+        );
+        Ok(res?)
+    }
+
+    /// Rewrite a single segment without its dead records, without merging it with others.
+    ///
+    /// The records shadowed by newer entries in other segments are dropped, and so are
+    /// the tombstones of keys no other segment has. The rewritten segment takes a new id,
+    /// see [`Database::segment_infos`], and the old file is removed once no snapshot
+    /// holds it, so running reads and scans are not affected. The entries keep their
+    /// sequence numbers, so [`Database::drop_oldest_segments`] still takes the segment
+    /// for as old as before.
+    pub fn vacuum_segment(&self, id: u64) -> Result<ReclaimedBytes, Error> {
+        let res = self
+            .merger()
+            .vacuum(id)
+            .map_err(Error::from)
+            .and_then(|reclaimed| reclaimed.ok_or(Error::SegmentNotFound(id)));
+        self.trace(
+            Op::Vacuum,
+            id.to_string().as_bytes(),
+            b"",
+            Outcome::of(&res),
+        );
+        res
+    }
+
+    /// Rebuild the index of a segment from its records, for one suspected to be stale.
+    ///
+    /// The statistics of the footer are taken from the records as well. The rebuilt
+    /// segment takes the place of the old one at once, so running reads see either.
+    /// Only the copy in memory is rebuilt, which is all there is of the index; vacuum the
+    /// segment to rewrite a file with a wrong footer, see [`Database::vacuum_segment`].
+    pub fn rebuild_segment_index(&mut self, id: u64) -> Result<(), Error> {
+        // Holding the lock keeps merges from replacing the segment in the meantime.
+        let _segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let segment = self
             .segments
+            .snapshot()
+            .get(&id)
+            .cloned()
+            .ok_or(Error::SegmentNotFound(id))?;
+        let rebuilt = segment.reindexed(self.blocks)?;
+        if !rebuilt.is_indexed() {
+            tracing::warn!("the records of segment {} cannot be indexed", id);
+        }
+        self.segments
+            .update(|segments| segments.insert(id, Arc::new(rebuilt)));
+        tracing::info!("rebuilt the index of segment {}", id);
+        Ok(())
+    }
+
+    /// Drop the `n` oldest segments with all their entries, returning how many are
+    /// dropped.
+    ///
+    /// This is meant for expiring time-ordered data wholesale. The files are removed
+    /// once no snapshot holds the segments, so running reads and scans still see them.
+    ///
+    /// The segments are ordered by the newest sequence number in their footers, then by
+    /// their ids, instead of by ids alone: the output of
+    /// [`compact_older_than`](Self::compact_older_than) or of
+    /// [`vacuum_segment`](Self::vacuum_segment) takes a new id while holding old data.
+    pub fn drop_oldest_segments(&self, n: usize) -> Result<usize, Error> {
+        let res = self.drop_oldest(n);
+        self.trace(
+            Op::DropOldest,
+            n.to_string().as_bytes(),
+            b"",
+            Outcome::of(&res),
+        );
+        res
+    }
+
+    fn drop_oldest(&self, n: usize) -> Result<usize, Error> {
+        // Holding the lock keeps merges from picking the segments in the meantime.
+        let _segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let ids = self.segments.update(|segments| {
+            let mut ids: Vec<(u64, u64)> = segments
+                .iter()
+                .map(|(id, segment)| (segment.footer().max_seq, *id))
+                .collect();
+            ids.sort_unstable();
+            let ids: Vec<u64> = ids.into_iter().take(n).map(|(_, id)| id).collect();
+            for id in &ids {
+                if let Some(segment) = segments.remove(id) {
+                    segment.mark_obsolete();
+                }
+            }
+            ids
+        });
+        self.clear_value_cache();
+        tracing::info!("dropped the oldest segments {:?}", ids);
+        Ok(ids.len())
+    }
+
+    /// Append the operation to the trace if there is one.
+    fn trace(&self, op: Op, key: &[u8], value: &[u8], outcome: Outcome) {
+        if let Some(trace) = &self.op_trace {
+            trace.record(op, key, value, &outcome);
+        }
+    }
+
+    /// The entries, appended to the trace as `op` as they are taken.
+    fn traced_entries<'a, I>(
+        &'a self,
+        op: Op,
+        entries: I,
+    ) -> impl Iterator<Item = (Bytes, Bytes)> + 'a
+    where
+        I: IntoIterator<Item = (Bytes, Bytes)>,
+        I::IntoIter: 'a,
+    {
+        entries
+            .into_iter()
+            .inspect(move |(key, value)| self.trace(op, key, value, Outcome::Ok))
+    }
+
+    /// The path of the log that new writes are appended to.
+    pub fn active_log_path(&self) -> PathBuf {
+        self.memtable
             .read()
-            .map_err(|_| MapError::ReadLock)?
+            .unwrap_or_else(PoisonError::into_inner)
+            .active_log_path()
+    }
+
+    /// Check that replaying the logs reproduces the memtable exactly, which is meant for
+    /// catching bugs in tests. Ephemeral writes are not in the logs, so any of them still
+    /// in the memtable makes it `false`.
+    pub fn verify_wal_matches_memtable(&self) -> Result<bool, Error> {
+        Ok(self
+            .memtable
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .verify_logs()?)
+    }
+
+    /// Set the memtable size at which new writes switch to a new log, on the live
+    /// database; it takes effect at the next write.
+    ///
+    /// A zero size is rejected like by [`DatabaseBuilder::switch_mem_size`], leaving the
+    /// size as it was.
+    pub fn set_switch_mem_size(&self, size: usize) -> Result<(), Error> {
+        if size == 0 {
+            return Err(BuilderError::InvalidSwitchMemSize.into());
+        }
+        self.memtable
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_switch_size(size);
+        Ok(())
+    }
+
+    /// The ids and paths of all segments, from the oldest to the newest.
+    pub fn segment_paths(&self) -> Vec<(u64, PathBuf)> {
+        self.segments
+            .snapshot()
             .iter()
-            .rev()
+            .map(|(id, segment)| (*id, segment.path().to_owned()))
+            .collect()
+    }
+
+    /// The length of the value of the key, without copying the value out of the
+    /// segments, or reading it at all from a segment in [`SegmentFormat::Columns`].
+    ///
+    /// With a value resolver, the value is resolved to find its length.
+    pub fn value_len<Q>(&self, key: &Q) -> Result<Option<usize>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        if self.value_resolver.is_some() {
+            return Ok(self.get(key)?.map(|value| value.len()));
+        }
+        let normalized = KeyNormalizer::apply(self.key_normalizer.as_ref(), key.as_ref());
+        let key = normalized.as_deref().unwrap_or(key.as_ref());
+        lookup(
+            self.read_order,
+            || {
+                Ok(self
+                    .memtable
+                    .read()
+                    .map_err(|_| MapError::ReadLock)?
+                    .lookup(key)
+                    .map(|entry| entry.map(|value| value.len())))
+            },
+            || {
+                search_segments(
+                    &self.segments.snapshot(),
+                    self.strict_reads,
+                    &mut ReadStats::default(),
+                    |segment, on_corrupt, bytes_read| {
+                        segment.lookup_len(key, on_corrupt, bytes_read)
+                    },
+                )
+            },
+        )
+    }
+
+    /// Get the value of the key like [`Get::get`], with where the key is found and how
+    /// much is read to find it, for finding out why a lookup is slow.
+    pub fn get_with_stats<Q>(&self, key: &Q) -> Result<(Option<Arc<Bytes>>, ReadStats), MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        let start = std::time::Instant::now();
+        let mut stats = ReadStats::default();
+        let res = self.read(key.as_ref(), &mut stats);
+        stats.elapsed = start.elapsed();
+        self.trace(Op::Get, key.as_ref(), b"", Outcome::of_get(&res));
+        Ok((res?, stats))
+    }
+
+    /// Get the value of the key, or the value computed by `default` if the key is
+    /// missing. The default is not stored.
+    pub fn get_or<Q, F>(&self, key: &Q, default: F) -> Result<Arc<Bytes>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+        F: FnOnce() -> Bytes,
+    {
+        Ok(self.get(key)?.unwrap_or_else(|| Arc::new(default())))
+    }
+
+    /// Count the keys with the given prefix.
+    pub fn count_prefix<Q>(&self, prefix: &Q) -> Result<usize, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        let end = prefix_end(prefix);
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let mut count = 0;
+        for entry in self.scan_with(Bound::Included(prefix), end, false, |_| (), |_, _| ())? {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Estimate the number of the keys in `start..end` without reading the records.
+    ///
+    /// The estimate is approximate: each segment is counted from the blocks of its index
+    /// starting in the range, and a key in more than one segment or tree is counted more
+    /// than once, while the entries in the memtable are counted exactly.
+    pub fn estimate_range_count<Q>(&self, start: &Q, end: &Q) -> Result<usize, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        let (start, end) = (start.as_ref(), end.as_ref());
+        if start >= end {
+            return Ok(0);
+        }
+        let memtable = self
+            .memtable
+            .read()
+            .map_err(|_| MapError::ReadLock)?
+            .count_range(start, end);
+        let segments: u64 = self
+            .segments
+            .snapshot()
+            .values()
+            .map(|segment| segment.estimate_count(start, end))
+            .sum();
+        Ok(memtable + segments as usize)
+    }
+
+    /// Scan the entries with keys in the given range, in key order.
+    pub fn range<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.scan(
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+        )
+    }
+
+    /// Scan the keys in the given range, in key order.
+    ///
+    /// The values are not read from the segments in [`SegmentFormat::Columns`].
+    pub fn keys<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<Bytes, MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let keys = self.scan_with(
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+            false,
+            |_| (),
+            |_, _| (),
+        )?;
+        Ok(keys.map(|entry| entry.map(|(key, ())| key)))
+    }
+
+    /// Scan all the entries in key order, with the deleted keys as tombstones.
+    ///
+    /// Only the newest entry of each key is taken, as in [`Database::range`], so a replica
+    /// can catch up with the deletions as well. A tombstone is dropped once a merge takes
+    /// the oldest segment, so the keys deleted before a compaction are not seen.
+    pub fn scan_raw(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, RawEntry), MapError>>, MapError> {
+        let entries = self.scan_entries(
+            Bound::Unbounded,
+            Bound::Unbounded,
+            true,
+            |value| value,
+            |record, pool| Arc::new(pool.copy(record_value(record))),
+        )?;
+        Ok(entries.map(|entry| {
+            entry.map(|(key, entry)| match entry.value {
+                Some(value) => (key, RawEntry::Value(value)),
+                None => (key, RawEntry::Tombstone),
+            })
+        }))
+    }
+
+    /// The sequence number of the latest write, to be passed to
+    /// [`Database::changes_since`] later.
+    pub fn last_seq(&self) -> Result<u64, MapError> {
+        Ok(self
+            .memtable
+            .read()
+            .map_err(|_| MapError::ReadLock)?
+            .last_seq())
+    }
+
+    /// The writes with sequence numbers greater than `seq`, ordered by the sequence
+    /// numbers, with the writes of a batch sharing one and ordered by keys.
+    ///
+    /// Only the newest write of a key is kept in a tree of the memtable or in a merged
+    /// segment, so an older write of a key written again later may be missing, and a
+    /// delete is missing once a merge takes the oldest segment. The feed is only
+    /// complete as long as `seq` is newer than every segment merged since it was taken.
+    pub fn changes_since(
+        &self,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = (u64, Bytes, RawEntry)>, MapError> {
+        let raw = |value: Option<Arc<Bytes>>| value.map_or(RawEntry::Tombstone, RawEntry::Value);
+        let mut changes = Vec::new();
+        for entries in self
+            .memtable
+            .read()
+            .map_err(|_| MapError::ReadLock)?
+            .entries(Bound::Unbounded, Bound::Unbounded)
         {
-            if let Some(value) = segment.get(key)? {
-                return Ok(Some(value));
+            changes.extend(
+                entries
+                    .into_iter()
+                    .filter(|(_, entry)| entry.seq > seq)
+                    .map(|(key, entry)| (entry.seq, key, raw(entry.value))),
+            );
+        }
+        // The memtable is read before the segments, so a tree flushed in between is found
+        // in its segment, and it is only found twice if it is not dropped yet.
+        for segment in self.segments.snapshot().values() {
+            if segment.footer().max_seq <= seq {
+                continue;
+            }
+            let entries = segment.entries_with(
+                Bound::Unbounded,
+                Bound::Unbounded,
+                true,
+                OnCorrupt::Skip,
+                |record, pool| Arc::new(pool.copy(record_value(record))),
+            )?;
+            for entry in entries {
+                let (key, entry) = entry?;
+                if entry.seq > seq {
+                    changes.push((entry.seq, key, raw(entry.value)));
+                }
             }
         }
-        Ok(None)
+        changes.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        changes.dedup_by(|a, b| (a.0, &a.1) == (b.0, &b.1));
+        Ok(changes.into_iter())
     }
 
-    fn merge_segments(
-        block_size: u64,
-        merge_period: std::time::Duration,
-        poll_period: std::time::Duration,
-        exiter: mpsc::Receiver<()>,
-        max_segment_id: Arc<Mutex<u64>>,
-        segments: Arc<RwLock<Segments>>,
-        dir: PathBuf,
-        suffix: String,
-    ) -> Result<(), std::io::Error> {
-        let mut last_tick = Instant::now();
-        loop {
-            thread::sleep(poll_period);
-            match exiter.try_recv() {
-                Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
-                    break;
-                }
-                Err(_) => {
-                    if last_tick.elapsed() >= merge_period {
-                        if segments.read().unwrap().len() <= 1 {
-                            continue;
-                        }
-                        let mut segment_id = max_segment_id.lock().unwrap();
-                        *segment_id += 1;
-                        last_tick = Instant::now();
-                        let mut segment_readers = BTreeMap::new();
-                        let mut failed = false;
-                        for (id, segment) in segments.read().unwrap().iter() {
-                            if let Ok(reader) = segment.to_reader() {
-                                segment_readers.insert(*id, reader);
-                            } else {
-                                failed = true;
-                                break;
-                            }
-                        }
-                        if !failed {
-                            let path = dir
-                                .as_path()
-                                .join(format!("{}{}{}", segment_id, DOT, suffix));
-                            let tmp_path = dir
-                                .as_path()
-                                .join(format!("{}{}{}", segment_id, DOT, TMP_SUFFIX));
-                            let mut failed = false;
-                            if let Ok(tmp_file) =
-                                OpenOptions::new().create(true).write(true).open(&tmp_path)
-                            {
-                                let mut writer = WriterBuilder::new()
-                                    .has_headers(false)
-                                    .from_writer(tmp_file);
-                                tracing::info!("merging segments to to path {:?}", tmp_path);
-                                let mut segment_records = segment_readers
-                                    .iter_mut()
-                                    .map(|(id, reader)| (id, reader.byte_records().peekable()))
-                                    .collect::<BTreeMap<_, _>>();
-                                loop {
-                                    let mut done = Vec::new();
-                                    let mut smallest = None;
-                                    for (id, segment) in segment_records.iter_mut().rev() {
-                                        if let Some(record) = segment.peek() {
-                                            if let Some(key) = record
-                                                .as_ref()
-                                                .ok()
-                                                .and_then(|record| record.get(0))
-                                            {
-                                                if let Some((_, smallest_key)) = smallest.as_ref() {
-                                                    if key < smallest_key {
-                                                        smallest = Some((
-                                                            **id,
-                                                            Bytes::copy_from_slice(key),
-                                                        ));
-                                                    } else if key == smallest_key {
-                                                        segment.next();
-                                                    }
-                                                } else {
-                                                    smallest =
-                                                        Some((**id, Bytes::copy_from_slice(key)));
-                                                }
-                                            } else {
-                                                segment.next();
-                                            }
-                                        } else {
-                                            done.push(**id);
-                                        }
-                                    }
-                                    if let Some((smallest_id, _)) = smallest {
-                                        if let Some(record) = segment_records
-                                            .get_mut(&smallest_id)
-                                            .and_then(|record| record.next())
-                                            .and_then(|record| record.ok())
-                                        {
-                                            if writer.write_byte_record(&record).is_err() {
-                                                failed = true;
-                                                break;
-                                            }
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                    for id in done {
-                                        segment_records.remove(&id);
-                                    }
-                                }
-                                if !failed {
-                                    let mut segment = Segment::from_path(&tmp_path);
-                                    match segment.initialize_index(block_size) {
-                                        Ok(_) => {
-                                            if let Err(err) = segment.move_to(&path) {
-                                                tracing::error!(
-                                                    "failed to rename the merged segment file: err={}",
-                                                    err
-                                                );
-                                            } else {
-                                                for id in segment_readers.keys() {
-                                                    if let Some(old_segment) =
-                                                        segments.write().unwrap().remove(id)
-                                                    {
-                                                        if let Err(err) = old_segment.remove() {
-                                                            tracing::error!("failed to remove the old segment file in path {:?}, err={}", path, err);
-                                                        }
-                                                    }
-                                                }
-                                                tracing::info!(
-                                                    "merged segments to to path {:?}",
-                                                    path
-                                                );
-                                                segments
-                                                    .write()
-                                                    .unwrap()
-                                                    .insert(*segment_id, segment);
-                                            }
-                                        }
-                                        Err(err) => {
-                                            tracing::error!(
-                                                "failed to initialize the merged segment: err={}",
-                                                err
-                                            );
-                                        }
-                                    }
-                                }
-                                if tmp_path.exists() {
-                                    let _ = std::fs::remove_file(&tmp_path);
-                                }
-                            }
-                        }
-                    }
-                }
+    /// Scan the entries with keys in the given range like [`Database::range`], with the
+    /// values only copied out of the segments when they are loaded.
+    ///
+    /// This saves the copies of the values in scans that skip most of them.
+    pub fn range_lazy<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, LazyValue), MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.scan_with(
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+            true,
+            LazyValue::loaded,
+            |record, _| LazyValue::from_record(record.clone()),
+        )
+    }
+
+    /// Scan the entries with keys in the time window `[start, end)`, in key order.
+    ///
+    /// Only available with [`KeySchema::BigEndianU64`], otherwise [`MapError::KeyNotAllow`]
+    /// is returned.
+    pub fn range_time(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError> {
+        if self.write_check.key_schema != KeySchema::BigEndianU64 {
+            return Err(MapError::KeyNotAllow);
+        }
+        let (start, end) = (start.to_be_bytes(), end.to_be_bytes());
+        self.scan(Bound::Included(&start), Bound::Excluded(&end))
+    }
+
+    /// Scan the entries with keys matching the glob `pattern`, in key order.
+    ///
+    /// `*` matches any bytes and `?` matches a single byte. The literal prefix before the
+    /// first wildcard is used to seek into the segments, so patterns with a fixed prefix
+    /// avoid a full scan.
+    pub fn scan_glob(
+        &self,
+        pattern: &str,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError> {
+        let pattern = pattern.as_bytes().to_vec();
+        let prefix = glob_prefix(&pattern);
+        let end = prefix_end(prefix);
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.scan(Bound::Included(prefix), end)?;
+        Ok(entries.filter(move |entry| match entry {
+            Ok((key, _)) => glob_match(&pattern, key),
+            Err(_) => true,
+        }))
+    }
+
+    /// Look up the key, recording where it is found in `stats`.
+    fn read(&self, key: &[u8], stats: &mut ReadStats) -> Result<Option<Arc<Bytes>>, MapError> {
+        let normalized = KeyNormalizer::apply(self.key_normalizer.as_ref(), key);
+        let key = normalized.as_deref().unwrap_or(key);
+        let epoch = self.value_cache.as_deref().map(ValueCache::epoch);
+        // The memtable hits go through locals, as the stats are borrowed by the other closure.
+        let (mut active_hit, mut frozen_hit) = (false, false);
+        let value = lookup(
+            self.read_order,
+            || {
+                let memtable = self.memtable.read().map_err(|_| MapError::ReadLock)?;
+                Ok(memtable.locate(key).map(|(entry, active)| {
+                    active_hit = active;
+                    frozen_hit = !active;
+                    entry
+                }))
+            },
+            || self.read_from_segments(key, epoch, stats),
+        )?;
+        stats.active_hit = active_hit;
+        stats.frozen_hit = frozen_hit;
+        resolve(self.value_resolver.as_ref(), value)
+    }
+
+    /// Look up the key in the segments through the value cache if there is one, caching
+    /// the entry found by a lookup started at `epoch`.
+    fn read_from_segments(
+        &self,
+        key: &[u8],
+        epoch: Option<u64>,
+        stats: &mut ReadStats,
+    ) -> Result<Option<Entry>, MapError> {
+        let copy = |value: &[u8]| Arc::new(Bytes::copy_from_slice(value));
+        let segments = self.segments.snapshot();
+        let (cache, epoch) = match (&self.value_cache, epoch) {
+            (Some(cache), Some(epoch)) => (cache, epoch),
+            _ => return get_from_segments_with(&segments, key, self.strict_reads, copy, stats),
+        };
+        if let Some(entry) = cache.get(key) {
+            stats.cache_hit = true;
+            return Ok(Some(entry));
+        }
+        let entry = get_from_segments_with(&segments, key, self.strict_reads, copy, stats)?;
+        if let Some(entry) = &entry {
+            cache.insert(key, entry.clone(), epoch);
+        }
+        Ok(entry)
+    }
+
+    /// Drop all the values cached, as the segments have changed.
+    fn clear_value_cache(&self) {
+        if let Some(cache) = &self.value_cache {
+            cache.clear();
+        }
+    }
+
+    /// Set the key to the value, or delete it if the value is `None`, writing it to the
+    /// log if `logged`.
+    fn write(&self, key: Bytes, value: Option<Bytes>, logged: bool) -> Result<(), MapError> {
+        let key = KeyNormalizer::apply(self.key_normalizer.as_ref(), &key).unwrap_or(key);
+        self.write_check.check(&key, value.as_deref())?;
+        let switched = {
+            let mut write = self.lock_for_write()?;
+            let batch = vec![(key.clone(), value)];
+            if logged {
+                write.apply(batch)?;
+            } else {
+                write.apply_unlogged(batch);
+            }
+            if let Some(cache) = &self.value_cache {
+                cache.invalidate(&key);
             }
+            write.try_switch()?
+        };
+        if switched {
+            self.write_new_segment()?;
         }
         Ok(())
     }
+
+    /// Lock the memtable for a write, once it is below the stall threshold or nothing is
+    /// being written out.
+    fn lock_for_write(&self) -> Result<RwLockWriteGuard<'_, Memtable>, MapError> {
+        loop {
+            let memtable = self.memtable.write().map_err(|_| MapError::WriteLock)?;
+            let stalled = self.stall_threshold.is_some_and(|threshold| {
+                memtable.active_size().0 >= threshold && memtable.frozen_count() > 0
+            });
+            if !stalled {
+                return Ok(memtable);
+            }
+            if self.write_stall == WriteStall::Fail {
+                return Err(MapError::WriteStalled);
+            }
+            // Counted with the memtable locked, so the flush of the frozen memtable seen
+            // is not done yet and is waited for.
+            let count = self.flushes.count();
+            drop(memtable);
+            tracing::warn!("write stalled until the memtable is flushed");
+            self.flushes.wait(count);
+        }
+    }
+
+    /// Set the key like [`Map::set`], which only needs a shared reference since the
+    /// memtable is behind a lock.
+    pub(crate) fn set_shared(&self, key: Bytes, value: Bytes) -> Result<(), MapError> {
+        let res = self
+            .slow_ops
+            .time("set", || self.write(key.clone(), Some(value.clone()), true));
+        self.trace(Op::Set, &key, &value, Outcome::of(&res));
+        res
+    }
+
+    /// Set the key like [`Database::set_ephemeral`], with a shared reference.
+    pub(crate) fn set_ephemeral_shared(&self, key: Bytes, value: Bytes) -> Result<(), MapError> {
+        let res = self.slow_ops.time("set", || {
+            self.write(key.clone(), Some(value.clone()), false)
+        });
+        self.trace(Op::Set, &key, &value, Outcome::of(&res));
+        res
+    }
+
+    /// Delete the key like [`Database::delete`], with a shared reference.
+    pub(crate) fn delete_shared(&self, key: Bytes) -> Result<(), MapError> {
+        let res = self
+            .slow_ops
+            .time("delete", || self.write(key.clone(), None, true));
+        self.trace(Op::Delete, &key, b"", Outcome::of(&res));
+        res
+    }
+
+    /// Scan the entries with keys in the given bounds, in key order.
+    pub(crate) fn scan(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError> {
+        self.scan_with(
+            start,
+            end,
+            true,
+            |value| value,
+            |record, pool| Arc::new(pool.copy(record_value(record))),
+        )
+    }
+
+    /// Scan the entries like [`Database::scan`], with the values from the memtable mapped
+    /// by `from_memtable`, and the values in the segments made from their records by
+    /// `from_record`, which are left empty in columnar segments unless `with_values`.
+    fn scan_with<V: Send + 'static>(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        with_values: bool,
+        from_memtable: fn(Arc<Bytes>) -> V,
+        from_record: fn(&ByteRecord, &mut BytesPool) -> V,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, V), MapError>>, MapError> {
+        let entries = self.scan_entries(start, end, with_values, from_memtable, from_record)?;
+        Ok(entries.filter_map(|entry| match entry {
+            Ok((key, entry)) => entry.value.map(|value| Ok((key, value))),
+            Err(err) => Some(Err(err)),
+        }))
+    }
+
+    /// Scan the entries like [`Database::scan_with`], keeping the tombstones.
+    fn scan_entries<V: Send + 'static>(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        with_values: bool,
+        from_memtable: fn(Arc<Bytes>) -> V,
+        from_record: fn(&ByteRecord, &mut BytesPool) -> V,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Entry<V>), MapError>>, MapError> {
+        let mut sources: Vec<Source<Entry<V>>> = Vec::new();
+        // The snapshot is taken with the memtable locked, so a replace of all data is
+        // either seen by both or by neither.
+        let (memtable, segments) = {
+            let memtable = self.memtable.read().map_err(|_| MapError::ReadLock)?;
+            (memtable.entries(start, end), self.segments.snapshot())
+        };
+        for entries in memtable {
+            let entries = entries
+                .into_iter()
+                .map(move |(key, entry)| Ok((key, entry.map(from_memtable))));
+            sources.push(Box::new(entries));
+        }
+        let mut segment_sources: Vec<Source<Entry<V>>> = Vec::new();
+        for (_, segment) in segments.iter().rev() {
+            let entries = segment
+                .entries_with(start, end, with_values, OnCorrupt::Skip, from_record)?
+                .map(|entry| entry.map_err(MapError::from));
+            segment_sources.push(Box::new(entries));
+        }
+        // The segments are merged by sequence numbers, and then merged with the memtable
+        // in the read order.
+        let segment_source: Source<Entry<V>> =
+            Box::new(MergeIter::by_seq(segment_sources, Entry::seq));
+        match self.read_order {
+            ReadOrder::MemtableFirst => sources.push(segment_source),
+            ReadOrder::SegmentsFirst => sources.insert(0, segment_source),
+        }
+        Ok(MergeIter::new(sources))
+    }
 }
 
 impl Get for Database {
@@ -362,57 +1840,313 @@ impl Get for Database {
         Q: ?Sized,
         Q: AsRef<[u8]>,
     {
-        if let Some(value) = self
-            .memtable
-            .read()
-            .map_err(|_| MapError::ReadLock)?
-            .get(key)?
+        let res = self
+            .slow_ops
+            .time("get", || self.read(key.as_ref(), &mut ReadStats::default()));
+        self.trace(Op::Get, key.as_ref(), b"", Outcome::of_get(&res));
+        res
+    }
+}
+
+/// Write the oldest frozen tree left out to a new segment, see
+/// [`Database::write_new_segment`].
+fn write_oldest_frozen(merger: &Merger, memtable: &RwLock<Memtable>) -> Result<(), std::io::Error> {
+    merger
+        .slow_ops
+        .time("flush", || flush_oldest_frozen(merger, memtable))
+}
+
+fn flush_oldest_frozen(merger: &Merger, memtable: &RwLock<Memtable>) -> Result<(), std::io::Error> {
+    let mut segment_id = merger.max_segment_id.lock().unwrap();
+    let (log_id, segment) = match memtable.read().unwrap().oldest_frozen() {
+        Some(frozen) => frozen,
+        None => return Ok(()),
+    };
+    // The span covers the merges making room as well, and has the id of the new segment
+    // and its size recorded once they are known.
+    let span = tracing::info_span!(
+        "flush",
+        log_id,
+        segment_id = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    merger.make_room(&mut segment_id)?;
+    *segment_id += 1;
+    span.record("segment_id", *segment_id);
+    let path = merger
+        .dir
+        .as_path()
+        .join(format!("{}{}{}", segment_id, DOT, merger.suffix));
+    let tmp_path = merger
+        .dir
+        .as_path()
+        .join(format!("{}{}{}", segment_id, DOT, merger.tmp_suffix));
+    tracing::info!("writing new segment {} to path {:?}", segment_id, tmp_path);
+    let mut segment = segment.write_to_path(&tmp_path, merger.segment_format, merger.blocks)?;
+    segment.set_read_options(&merger.read_options)?;
+    segment.initialize_index(merger.blocks)?;
+    segment.move_to(&path)?;
+    span.record("bytes", segment.size().unwrap_or_default());
+    tracing::info!("new segment {} is written to path {:?}", segment_id, path);
+    // The segment is published before the frozen tree is dropped, so a read that misses
+    // the tree always finds the segment, and a write is never briefly invisible.
+    let id = *segment_id;
+    merger
+        .segments
+        .update(|segments| segments.insert(id, Arc::new(segment)));
+    // With the segments read first, the entries flushed can shadow the ones cached.
+    if let Some(cache) = &merger.value_cache {
+        cache.clear();
+    }
+    memtable.write().unwrap().finalize_switch(log_id)?;
+    merger.pack()
+}
+
+/// Lock the data folder, failing with [`Error::Locked`] if another database holds it.
+///
+/// The lock is an advisory lock of the OS, which is released when the file is closed,
+/// so the file left by a crashed process does not keep the folder locked.
+fn lock_dir(path: &Path) -> Result<File, Error> {
+    let path = path.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked(path)),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Split off the segments replaced by a later one, which are left by a stop in the
+/// middle of `replace_all`, or while a snapshot still held them, returning the largest
+/// id a segment replaces and the replaced segments.
+pub(crate) fn split_off_replaced<S: Borrow<Segment>>(
+    segments: &mut BTreeMap<u64, S>,
+) -> (u64, BTreeMap<u64, S>) {
+    let replaces = segments
+        .values()
+        .map(|segment| segment.borrow().footer().replaces)
+        .max()
+        .unwrap_or_default();
+    let kept = segments.split_off(&replaces);
+    (replaces, std::mem::replace(segments, kept))
+}
+
+/// Open the segment file with the given id and build its index.
+pub(crate) fn open_segment(
+    id: &str,
+    path: &Path,
+    read_options: &ReadOptions,
+    blocks: Blocks,
+) -> Result<(u64, Segment), Error> {
+    let id = id
+        .parse()
+        .map_err(|_| Error::ParseSegemntId(id.to_string()))?;
+    let mut segment = Segment::from_path(&path);
+    segment.set_read_options(read_options)?;
+    segment.initialize_index(blocks)?;
+    Ok((id, segment))
+}
+
+/// Information of the segment with the given id.
+pub(crate) fn segment_info(id: u64, segment: &Segment) -> Result<SegmentInfo, std::io::Error> {
+    let footer = segment.footer();
+    let (min_key, max_key) = segment.key_range().unzip();
+    Ok(SegmentInfo {
+        id,
+        path: segment.path().to_owned(),
+        file_size: segment.size()?,
+        record_count: footer.record_count,
+        key_bytes: footer.key_bytes,
+        value_bytes: footer.value_bytes,
+        tombstone_count: footer.tombstone_count,
+        min_key,
+        max_key,
+        created_at: (footer.created_at > 0)
+            .then(|| UNIX_EPOCH + Duration::from_secs(footer.created_at)),
+    })
+}
+
+/// Open the segments in the pack file and build their indices.
+pub(crate) fn open_packed_segments(
+    path: &Path,
+    read_options: &ReadOptions,
+    blocks: Blocks,
+) -> Result<Vec<(u64, Segment)>, Error> {
+    let mut segments = open_pack(&path)?;
+    for (_, segment) in segments.iter_mut() {
+        segment.set_read_options(read_options)?;
+        segment.initialize_index(blocks)?;
+    }
+    Ok(segments)
+}
+
+/// Look up the key in the memtable and in the segments in the read order, with `None` if
+/// the key is missing or deleted.
+pub(crate) fn lookup<V, M, S>(
+    order: ReadOrder,
+    from_memtable: M,
+    from_segments: S,
+) -> Result<Option<V>, MapError>
+where
+    M: FnOnce() -> Result<Option<Entry<V>>, MapError>,
+    S: FnOnce() -> Result<Option<Entry<V>>, MapError>,
+{
+    let entry = match order {
+        ReadOrder::MemtableFirst => match from_memtable()? {
+            Some(entry) => Some(entry),
+            None => from_segments()?,
+        },
+        ReadOrder::SegmentsFirst => match from_segments()? {
+            Some(entry) => Some(entry),
+            None => from_memtable()?,
+        },
+    };
+    Ok(entry.and_then(|entry| entry.value))
+}
+
+/// Look up the key in the segments, taking the entry with the largest sequence number,
+/// and the one in the newest segment between equal sequence numbers.
+///
+/// Segments are searched from the newest, and the ones with no sequence number larger
+/// than the entry found are skipped. A record that cannot be read is skipped as well,
+/// unless `strict`.
+pub(crate) fn get_from_segments(
+    segments: &Segments,
+    key: &[u8],
+    strict: bool,
+) -> Result<Option<Entry>, MapError> {
+    get_from_segments_with(
+        segments,
+        key,
+        strict,
+        |value| Arc::new(Bytes::copy_from_slice(value)),
+        &mut ReadStats::default(),
+    )
+}
+
+/// Look up the key in the segments like [`get_from_segments`], mapping the value in place
+/// with `f` instead of copying it, and counting the segments searched in `stats`.
+fn get_from_segments_with<V>(
+    segments: &Segments,
+    key: &[u8],
+    strict: bool,
+    f: impl Fn(&[u8]) -> V,
+    stats: &mut ReadStats,
+) -> Result<Option<Entry<V>>, MapError> {
+    search_segments(
+        segments,
+        strict,
+        stats,
+        |segment, on_corrupt, bytes_read| segment.lookup_with(key, on_corrupt, &f, bytes_read),
+    )
+}
+
+/// Search the segments like [`get_from_segments`], looking up the key in a segment with
+/// `lookup`.
+fn search_segments<V>(
+    segments: &Segments,
+    strict: bool,
+    stats: &mut ReadStats,
+    lookup: impl Fn(&Segment, OnCorrupt, &mut u64) -> Result<Option<Entry<V>>, MapError>,
+) -> Result<Option<Entry<V>>, MapError> {
+    let mut found: Option<Entry<V>> = None;
+    for (id, segment) in segments.iter().rev() {
+        if found
+            .as_ref()
+            .is_some_and(|found| segment.footer().max_seq <= found.seq)
         {
-            Ok(Some(value))
+            continue;
+        }
+        let on_corrupt = if strict {
+            OnCorrupt::Fail { segment_id: *id }
         } else {
-            Ok(self.get_from_segments(key)?)
+            OnCorrupt::Skip
+        };
+        stats.segments_examined += 1;
+        if let Some(entry) = lookup(segment, on_corrupt, &mut stats.bytes_read)? {
+            if found.as_ref().is_none_or(|found| entry.seq > found.seq) {
+                found = Some(entry);
+            }
         }
     }
+    Ok(found)
+}
+
+/// The largest sequence number of the segments.
+fn max_seq(segments: &Segments) -> u64 {
+    segments
+        .values()
+        .map(|segment| segment.footer().max_seq)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Resolve the stored value with the resolver if there is one.
+pub(crate) fn resolve(
+    resolver: Option<&Arc<dyn ValueResolver>>,
+    value: Option<Arc<Bytes>>,
+) -> Result<Option<Arc<Bytes>>, MapError> {
+    match (value, resolver) {
+        (Some(reference), Some(resolver)) => Ok(Some(Arc::new(resolver.resolve(&reference)?))),
+        (value, _) => Ok(value),
+    }
 }
 
 impl Map for Database {
     fn set<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<(), MapError> {
-        if let Some(segment) = {
-            let mut write = self.memtable.write().map_err(|_| MapError::WriteLock)?;
-            write.set(key, value)?;
-            write.try_switch()?
-        } {
-            self.write_new_segment(segment)?;
-        }
-        Ok(())
+        self.set_shared(key.into(), value.into())
+    }
+}
+
+impl Database {
+    /// Delete the given key, doing nothing if it does not exist.
+    pub fn delete<K: Into<Bytes>>(&mut self, key: K) -> Result<(), MapError> {
+        self.delete_shared(key.into())
     }
 }
 
 impl Drop for Database {
     fn drop(&mut self) {
-        if let Some(exiter) = self.exiter.take() {
+        for exiter in self.exiters.drain(..) {
             let _ = exiter.send(());
         }
-        for task in self.tasks.drain(..) {
+        self.prefetches.take();
+        for (_, task) in self
+            .tasks
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain(..)
+        {
             let _ = task.join();
         }
         if let Ok(mut segment_id) = self.max_segment_id.try_lock() {
             *segment_id += 1;
             if let Ok(mut memtable) = self.memtable.try_write() {
-                if let Some(segment) = memtable.to_raw_segment() {
+                if let Some(segment) = memtable.take_raw_segment() {
                     if segment.is_empty() {
                         let _ = memtable.remove_active_log();
                     } else {
                         let tmp_path = self
                             .data_dir
                             .as_path()
-                            .join(format!("{}{}{}", *segment_id, DOT, TMP_SUFFIX));
+                            .join(format!("{}{}{}", *segment_id, DOT, self.tmp_suffix));
                         let path = self
                             .data_dir
                             .as_path()
                             .join(format!("{}{}{}", *segment_id, DOT, self.data_suffix));
-                        if segment.write_to_path(&tmp_path).is_ok() {
-                            match std::fs::rename(&tmp_path, &path) {
+                        if segment
+                            .write_to_path(&tmp_path, self.segment_format, self.blocks)
+                            .is_ok()
+                        {
+                            // The log can only go once the segment is sure to be there.
+                            match std::fs::rename(&tmp_path, &path)
+                                .and_then(|()| sync_parent_dir(&path))
+                            {
                                 Ok(_) => {
                                     let _ = memtable.remove_active_log();
                                     tracing::info!("created new segment file at path: {:?}", path);
@@ -429,3 +2163,90 @@ impl Drop for Database {
         tracing::info!("database closed");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panic_of_a_background_task_reaches_the_hook() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-panic-hook-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let panics = panics.clone();
+            Arc::new(move |task: BackgroundTask, message: &str| {
+                panics.lock().unwrap().push((task, message.to_string()));
+            })
+        };
+        let db = DatabaseBuilder::default()
+            .auto_merge(false)
+            .panic_hook(hook)
+            .open(&dir)
+            .unwrap();
+        let id = 7;
+        let task = db.spawn_task(BackgroundTask::Flush, move || {
+            panic!("segment {} is gone", id);
+        });
+        assert!(task.join().is_err());
+        let task = db.spawn_task(BackgroundTask::Merge, || panic!("static message"));
+        assert!(task.join().is_err());
+        assert_eq!(
+            *panics.lock().unwrap(),
+            [
+                (BackgroundTask::Flush, "segment 7 is gone".to_string()),
+                (BackgroundTask::Merge, "static message".to_string()),
+            ]
+        );
+        // A task that returns is not reported.
+        assert_eq!(
+            db.spawn_task(BackgroundTask::Flush, || 1).join().unwrap(),
+            1
+        );
+        assert_eq!(panics.lock().unwrap().len(), 2);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn health_reports_background_errors_until_the_task_succeeds() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-health-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = DatabaseBuilder::default().open(&dir).unwrap();
+        assert!(db.health().is_healthy(), "{:?}", db.health());
+
+        db.background_errors
+            .record::<(), _>(BackgroundTask::Flush, &Err("no space left on device"));
+        let health = db.health();
+        assert!(!health.is_healthy());
+        assert_eq!(
+            health.background_error,
+            Some((BackgroundTask::Flush, "no space left on device".to_string()))
+        );
+        assert!(health.tasks_alive && health.disk_writable);
+        db.background_errors
+            .record::<_, String>(BackgroundTask::Flush, &Ok(()));
+        assert!(db.health().is_healthy());
+
+        // Probes running at once write files of their own, and leave none behind.
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        assert!(db.health().disk_writable);
+                    }
+                });
+            }
+        });
+        let leftovers = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("health")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,18 +1,24 @@
 //! All error types.
 
+use std::path::PathBuf;
 use thiserror::Error;
 
 /// [`Map`] operations errors.
 #[derive(Debug, Error)]
 pub enum MapError {
-    /// Write log error.
-    #[error("write log error")]
-    WriteLog,
+    /// Write log error, with nothing of the failed write applied.
+    #[error("write log error: {0}")]
+    WriteLog(#[source] std::io::Error),
 
     /// Key is not allowed.
     #[error("key is not allowed")]
     KeyNotAllow,
 
+    /// The key is longer than [`MAX_KEY_LEN`](crate::schema::MAX_KEY_LEN) bytes. Values
+    /// have no limit other than memory.
+    #[error("key of {0} bytes is too large")]
+    KeyTooLarge(usize),
+
     /// Io errors.
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
@@ -24,4 +30,30 @@ pub enum MapError {
     /// Write lock error.
     #[error("write lock error")]
     WriteLock,
+
+    /// A record of a segment cannot be read, found by a strict lookup or a merge.
+    #[error("corrupt record at offset {offset} of segment {segment_id}")]
+    CorruptSegment {
+        /// The id of the segment.
+        segment_id: u64,
+        /// The offset of the record in the segment.
+        offset: u64,
+    },
+
+    /// A key is not greater than the one written before it to the segment at the path.
+    #[error("keys written out of order to segment {0:?}")]
+    UnorderedKeys(PathBuf),
+
+    /// The write is rejected by the validator for the given reason.
+    #[error("write rejected: {0}")]
+    Rejected(String),
+
+    /// The active memtable has reached the stall threshold while a frozen one is being
+    /// written out, with nothing of the write applied.
+    #[error("write stalled until the memtable is flushed")]
+    WriteStalled,
+
+    /// Value resolving error.
+    #[error("failed to resolve value: {0}")]
+    Resolve(String),
 }
@@ -0,0 +1,56 @@
+//! The csv format of the logs, the segments and the traces.
+//!
+//! All records are read and written with the settings here, so the files of every kind
+//! agree on headers, delimiters, quoting and record lengths.
+
+use csv::{Reader, ReaderBuilder, Writer, WriterBuilder};
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Records of different kinds have different lengths, and no file has a header.
+fn reader_builder() -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder.has_headers(false).flexible(true);
+    builder
+}
+
+fn writer_builder() -> WriterBuilder {
+    let mut builder = WriterBuilder::new();
+    builder.has_headers(false).flexible(true);
+    builder
+}
+
+/// A record reader of `rdr`.
+pub(crate) fn reader<R: Read>(rdr: R) -> Reader<R> {
+    reader_builder().from_reader(rdr)
+}
+
+/// A record reader of `rdr` reading `capacity` bytes at once.
+pub(crate) fn buffered_reader<R: Read>(rdr: R, capacity: usize) -> Reader<R> {
+    reader_builder().buffer_capacity(capacity).from_reader(rdr)
+}
+
+/// A record reader of the file at `path`.
+pub(crate) fn reader_from_path<P: AsRef<Path>>(
+    path: P,
+) -> Result<Reader<std::fs::File>, csv::Error> {
+    reader_builder().from_path(path)
+}
+
+/// Encode a single record, appending it to `buf`.
+pub(crate) fn encode_record<I, T>(buf: &mut Vec<u8>, record: I) -> Result<(), csv::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    // The record goes straight into `buf`, so a small buffer in between is enough.
+    let mut writer = writer_builder().buffer_capacity(256).from_writer(buf);
+    writer.write_record(record)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A record writer to `wtr`.
+pub(crate) fn writer<W: Write>(wtr: W) -> Writer<W> {
+    writer_builder().from_writer(wtr)
+}
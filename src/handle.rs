@@ -0,0 +1,204 @@
+//! The [`DatabaseHandle`] and [`ReadHandle`] structures.
+
+use crate::{Database, Error, Get, LazyValue, MapError, RawEntry, ReadStats};
+use bytes::Bytes;
+use std::ops::{Deref, RangeBounds};
+use std::sync::Arc;
+
+/// A handle to a [`Database`] that can be cloned and shared between threads.
+///
+/// The clones share the same database, and writes through any of them only need a
+/// shared reference. The database is closed when the last handle is dropped.
+#[derive(Clone)]
+pub struct DatabaseHandle(Arc<Database>);
+
+impl DatabaseHandle {
+    /// Set the value of the given key, overwritten the previous value if it exists.
+    pub fn set<K: Into<Bytes>, V: Into<Bytes>>(&self, key: K, value: V) -> Result<(), MapError> {
+        self.0.set_shared(key.into(), value.into())
+    }
+
+    /// Set the value of the key without writing it to the log, see
+    /// [`Database::set_ephemeral`].
+    pub fn set_ephemeral<K: Into<Bytes>, V: Into<Bytes>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(), MapError> {
+        self.0.set_ephemeral_shared(key.into(), value.into())
+    }
+
+    /// Delete the given key, doing nothing if it does not exist.
+    pub fn delete<K: Into<Bytes>>(&self, key: K) -> Result<(), MapError> {
+        self.0.delete_shared(key.into())
+    }
+
+    /// Replace all the data with the sorted entries, see [`Database::replace_all`].
+    pub fn replace_all<I: IntoIterator<Item = (Bytes, Bytes)>>(
+        &self,
+        sorted: I,
+    ) -> Result<(), Error> {
+        self.0.replace_all_shared(sorted)
+    }
+
+    /// A handle sharing the database that can only read from it.
+    pub fn read_handle(&self) -> ReadHandle {
+        ReadHandle(self.0.clone())
+    }
+}
+
+impl From<Database> for DatabaseHandle {
+    fn from(db: Database) -> Self {
+        Self(Arc::new(db))
+    }
+}
+
+impl Deref for DatabaseHandle {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.0
+    }
+}
+
+impl Get for DatabaseHandle {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.get(key)
+    }
+}
+
+/// A handle to a [`Database`] that can only read from it, made by
+/// [`DatabaseHandle::read_handle`].
+///
+/// Like [`DatabaseHandle`], it can be cloned and shared between threads, and it keeps
+/// the database open while it lives.
+#[derive(Clone)]
+pub struct ReadHandle(Arc<Database>);
+
+impl ReadHandle {
+    /// Scan the entries with keys in the given range, see [`Database::range`].
+    pub fn range<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.0.range(range)
+    }
+
+    /// Scan the keys in the given range, see [`Database::keys`].
+    pub fn keys<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<Bytes, MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.0.keys(range)
+    }
+
+    /// Scan the entries with keys in the given range, loading the values on demand, see
+    /// [`Database::range_lazy`].
+    pub fn range_lazy<K, R>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, LazyValue), MapError>>, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.0.range_lazy(range)
+    }
+
+    /// Scan the entries with keys matching the glob `pattern`, see
+    /// [`Database::scan_glob`].
+    pub fn scan_glob(
+        &self,
+        pattern: &str,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Arc<Bytes>), MapError>>, MapError> {
+        self.0.scan_glob(pattern)
+    }
+
+    /// Scan all the entries with the tombstones, see [`Database::scan_raw`].
+    pub fn scan_raw(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, RawEntry), MapError>>, MapError> {
+        self.0.scan_raw()
+    }
+
+    /// The writes after the sequence number `seq`, see [`Database::changes_since`].
+    pub fn changes_since(
+        &self,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = (u64, Bytes, RawEntry)>, MapError> {
+        self.0.changes_since(seq)
+    }
+
+    /// Count the keys with the given prefix.
+    pub fn count_prefix<Q>(&self, prefix: &Q) -> Result<usize, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.count_prefix(prefix)
+    }
+
+    /// Estimate the number of the keys in `start..end`, see
+    /// [`Database::estimate_range_count`].
+    pub fn estimate_range_count<Q>(&self, start: &Q, end: &Q) -> Result<usize, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.estimate_range_count(start, end)
+    }
+
+    /// Get the value of the key, reading the blocks following it in the background, see
+    /// [`Database::get_prefetch`].
+    pub fn get_prefetch<Q>(&self, key: &Q, ahead: usize) -> Result<Option<Arc<Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.get_prefetch(key, ahead)
+    }
+
+    /// The length of the value of the key, see [`Database::value_len`].
+    pub fn value_len<Q>(&self, key: &Q) -> Result<Option<usize>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.value_len(key)
+    }
+
+    /// Get the value of the key with the diagnostics of the lookup, see
+    /// [`Database::get_with_stats`].
+    pub fn get_with_stats<Q>(&self, key: &Q) -> Result<(Option<Arc<Bytes>>, ReadStats), MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.get_with_stats(key)
+    }
+}
+
+impl Get for ReadHandle {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        self.0.get(key)
+    }
+}
@@ -0,0 +1,149 @@
+//! The [`BlockIndex`] structure.
+
+use std::fmt;
+
+/// The size of the key length of an entry.
+const KEY_LEN_SIZE: usize = 4;
+
+/// The size of the offset of an entry.
+const OFFSET_SIZE: usize = 8;
+
+/// The first key and the offset of each block of a segment, with the keys strictly
+/// increasing.
+///
+/// The entries are laid out one after another in a single buffer as `(key_len, key,
+/// offset)`, with the lengths and the offsets in little endian, so the index takes two
+/// allocations however many blocks there are. Lookups are binary searches over the
+/// starts of the entries.
+#[derive(Clone, Default)]
+pub(crate) struct BlockIndex {
+    data: Vec<u8>,
+    starts: Vec<usize>,
+}
+
+impl BlockIndex {
+    /// Add an entry, with a key greater than all the keys before.
+    pub(crate) fn push(&mut self, key: &[u8], offset: u64) {
+        self.starts.push(self.data.len());
+        self.data
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(key);
+        self.data.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    /// The key of the entry at `idx`.
+    pub(crate) fn key(&self, idx: usize) -> &[u8] {
+        let start = self.starts[idx];
+        let len = u32::from_le_bytes(
+            self.data[start..start + KEY_LEN_SIZE]
+                .try_into()
+                .expect("key length"),
+        ) as usize;
+        &self.data[start + KEY_LEN_SIZE..start + KEY_LEN_SIZE + len]
+    }
+
+    /// The offset of the entry at `idx`.
+    pub(crate) fn offset(&self, idx: usize) -> u64 {
+        let end = self.starts.get(idx + 1).copied().unwrap_or(self.data.len());
+        u64::from_le_bytes(
+            self.data[end - OFFSET_SIZE..end]
+                .try_into()
+                .expect("offset"),
+        )
+    }
+
+    /// The number of the entries with keys less than `key`.
+    pub(crate) fn partition_point(&self, key: &[u8]) -> usize {
+        self.search(|k| k < key)
+    }
+
+    /// The last entry with a key not greater than `key`, which starts the block that
+    /// holds `key` if any does.
+    pub(crate) fn floor(&self, key: &[u8]) -> Option<usize> {
+        self.search(|k| k <= key).checked_sub(1)
+    }
+
+    fn search(&self, pred: impl Fn(&[u8]) -> bool) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.key(mid)) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+impl fmt::Debug for BlockIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries((0..self.len()).map(|idx| {
+                (
+                    bytes::Bytes::copy_from_slice(self.key(idx)),
+                    self.offset(idx),
+                )
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    #[test]
+    fn lookups_match_a_btree_map() {
+        let mut index = BlockIndex::default();
+        let mut map = BTreeMap::new();
+        for i in (0..1000u64).step_by(3) {
+            let key = format!("key{:05}", i).into_bytes();
+            index.push(&key, i * 100);
+            map.insert(key, i * 100);
+        }
+        assert_eq!(index.len(), map.len());
+        for (idx, (key, offset)) in map.iter().enumerate() {
+            assert_eq!(index.key(idx), key.as_slice());
+            assert_eq!(index.offset(idx), *offset);
+        }
+        let probes = (0..1002u64)
+            .map(|i| format!("key{:05}", i).into_bytes())
+            .chain([
+                b"".to_vec(),
+                b"key".to_vec(),
+                b"key00001a".to_vec(),
+                b"z".to_vec(),
+            ]);
+        for probe in probes {
+            let floor = map
+                .range::<[u8], _>((Bound::Unbounded, Bound::Included(probe.as_slice())))
+                .next_back()
+                .map(|(_, offset)| *offset);
+            assert_eq!(index.floor(&probe).map(|idx| index.offset(idx)), floor);
+            let less = map
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(probe.as_slice())))
+                .count();
+            assert_eq!(index.partition_point(&probe), less);
+        }
+    }
+
+    #[test]
+    fn an_empty_index_finds_nothing() {
+        let index = BlockIndex::default();
+        assert!(index.is_empty());
+        assert_eq!(index.floor(b"key"), None);
+        assert_eq!(index.partition_point(b"key"), 0);
+    }
+}
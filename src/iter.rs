@@ -0,0 +1,254 @@
+//! Iterators over sorted entries.
+
+use crate::MapError;
+use bytes::Bytes;
+use std::cmp::{Ordering, Reverse};
+use std::collections::binary_heap::{BinaryHeap, PeekMut};
+use std::ops::Bound;
+
+/// A sorted source of entries.
+pub(crate) type Source<V> = Box<dyn Iterator<Item = Result<(Bytes, V), MapError>> + Send>;
+
+/// Merge several sorted sources into one sorted iterator without duplicated keys.
+///
+/// Sources are given from the newest to the oldest, so the entry from the earliest
+/// source wins when a key appears in more than one source, unless the entries are
+/// compared by their sequence numbers. The next entry of each source is kept in a heap,
+/// so each step takes time logarithmic in the number of sources.
+pub(crate) struct MergeIter<V> {
+    sources: Vec<Source<V>>,
+    heap: BinaryHeap<Head<V>>,
+    /// The sources whose next entry is not in the heap yet.
+    pending: Vec<usize>,
+    seq: Option<fn(&V) -> u64>,
+}
+
+/// The next entry of a source, ordered so that the greatest one is the smallest key and,
+/// between equal keys, the one that wins.
+struct Head<V> {
+    key: Bytes,
+    value: V,
+    seq: u64,
+    source: usize,
+}
+
+impl<V> Head<V> {
+    fn rank(&self) -> (Reverse<&Bytes>, u64, Reverse<usize>) {
+        (Reverse(&self.key), self.seq, Reverse(self.source))
+    }
+}
+
+impl<V> PartialEq for Head<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl<V> Eq for Head<V> {}
+
+impl<V> PartialOrd for Head<V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<V> Ord for Head<V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+impl<V> MergeIter<V> {
+    pub(crate) fn new(sources: Vec<Source<V>>) -> Self {
+        Self {
+            pending: (0..sources.len()).rev().collect(),
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            seq: None,
+        }
+    }
+
+    /// Merge with the entry of the largest sequence number winning, and the earliest
+    /// source winning between equal sequence numbers.
+    pub(crate) fn by_seq(sources: Vec<Source<V>>, seq: fn(&V) -> u64) -> Self {
+        Self {
+            seq: Some(seq),
+            ..Self::new(sources)
+        }
+    }
+}
+
+impl<V> Iterator for MergeIter<V> {
+    type Item = Result<(Bytes, V), MapError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(source) = self.pending.pop() {
+            match self.sources[source].next() {
+                Some(Ok((key, value))) => self.heap.push(Head {
+                    seq: self.seq.map_or(0, |seq| seq(&value)),
+                    key,
+                    value,
+                    source,
+                }),
+                Some(Err(err)) => {
+                    self.pending.push(source);
+                    return Some(Err(err));
+                }
+                None => {}
+            }
+        }
+        let head = self.heap.pop()?;
+        while let Some(other) = self.heap.peek_mut() {
+            if other.key != head.key {
+                break;
+            }
+            self.pending.push(PeekMut::pop(other).source);
+        }
+        self.pending.push(head.source);
+        Some(Ok((head.key, head.value)))
+    }
+}
+
+/// Whether `key` is not less than the `start` bound.
+pub(crate) fn after_start(key: &[u8], start: Bound<&[u8]>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether `key` is not greater than the `end` bound.
+pub(crate) fn before_end(key: &[u8], end: Bound<&[u8]>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+/// The smallest key that is greater than all keys with the given prefix.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// The literal prefix of a glob pattern before its first wildcard.
+pub(crate) fn glob_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|c| *c == b'*' || *c == b'?')
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Whether `key` matches the glob `pattern`, where `*` matches any bytes and `?` matches
+/// a single byte.
+pub(crate) fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    k = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A source of `(key, value)` entries, with `Err` for a failing read.
+    fn source(entries: Vec<Result<(&'static str, u64), MapError>>) -> Source<u64> {
+        Box::new(
+            entries
+                .into_iter()
+                .map(|entry| entry.map(|(key, value)| (Bytes::from(key), value))),
+        )
+    }
+
+    fn ok(entries: &[(&'static str, u64)]) -> Source<u64> {
+        source(entries.iter().copied().map(Ok).collect())
+    }
+
+    fn collect(iter: MergeIter<u64>) -> Vec<(Bytes, u64)> {
+        iter.map(Result::unwrap).collect()
+    }
+
+    fn expected(entries: &[(&'static str, u64)]) -> Vec<(Bytes, u64)> {
+        entries
+            .iter()
+            .map(|(key, value)| (Bytes::from(*key), *value))
+            .collect()
+    }
+
+    #[test]
+    fn the_earliest_source_wins_between_equal_keys() {
+        let merged = MergeIter::new(vec![
+            ok(&[("b", 1), ("d", 1)]),
+            ok(&[("a", 2), ("b", 2), ("c", 2)]),
+            ok(&[]),
+            ok(&[("a", 3), ("c", 3), ("d", 3), ("e", 3)]),
+        ]);
+        assert_eq!(
+            collect(merged),
+            expected(&[("a", 2), ("b", 1), ("c", 2), ("d", 1), ("e", 3)])
+        );
+    }
+
+    #[test]
+    fn the_largest_seq_wins_when_merged_by_seq() {
+        let merged = MergeIter::by_seq(
+            vec![
+                ok(&[("a", 1), ("b", 5), ("c", 4)]),
+                ok(&[("a", 3), ("b", 2), ("c", 4)]),
+                ok(&[("a", 2), ("d", 1)]),
+            ],
+            |seq| *seq,
+        );
+        assert_eq!(
+            collect(merged),
+            expected(&[("a", 3), ("b", 5), ("c", 4), ("d", 1)])
+        );
+    }
+
+    #[test]
+    fn no_sources_merge_into_nothing() {
+        assert!(MergeIter::<u64>::new(Vec::new()).next().is_none());
+        assert!(MergeIter::new(vec![ok(&[]), ok(&[])]).next().is_none());
+    }
+
+    #[test]
+    fn an_error_is_returned_and_the_merge_goes_on() {
+        let mut merged = MergeIter::new(vec![
+            source(vec![Ok(("a", 1)), Err(MapError::ReadLock), Ok(("c", 1))]),
+            ok(&[("b", 2), ("c", 2)]),
+        ]);
+        assert_eq!(merged.next().unwrap().unwrap(), (Bytes::from("a"), 1));
+        assert!(matches!(merged.next(), Some(Err(MapError::ReadLock))));
+        let rest: Vec<_> = merged.map(Result::unwrap).collect();
+        assert_eq!(rest, expected(&[("b", 2), ("c", 1)]));
+    }
+}
@@ -3,13 +3,39 @@
 #![deny(missing_docs)]
 
 pub mod builder;
+mod cache;
 pub mod database;
 pub mod errors;
+mod format;
+pub mod handle;
+mod index;
+mod iter;
 mod memtable;
+mod merger;
+pub mod output;
+pub mod reader;
+pub mod schema;
 mod segment;
+pub mod stats;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod trace;
 pub mod traits;
+pub mod txn;
+pub mod value;
 
-pub use builder::DatabaseBuilder;
-pub use database::{Database, Error};
+pub use builder::{BuilderError, DatabaseBuilder};
+pub use database::{Database, Error, GetOrReserve};
 pub use errors::MapError;
-pub use traits::{Get, Map};
+pub use handle::{DatabaseHandle, ReadHandle};
+pub use reader::{inspect_directory, read_all_records, SegmentSetReader};
+pub use schema::{KeySchema, NormalizeFn};
+pub use stats::{
+    DiskUsage, Health, ReadStats, ReclaimedBytes, RecoveryReport, SegmentInfo, TuningHint,
+};
+#[cfg(feature = "tokio")]
+pub use stream::RangeStream;
+pub use trace::{replay, ReplayError};
+pub use traits::{Get, Map, Validator, ValueResolver};
+pub use txn::Txn;
+pub use value::{LazyValue, RawEntry, Value};
@@ -1,16 +1,56 @@
-use crate::segment::RawSegment;
+use crate::format;
+use crate::segment::{sync_parent_dir, RawSegment};
 use crate::{Get, Map, MapError};
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
 use crc::{Crc, CRC_32_AIXM};
-use csv::{ByteRecord, ReaderBuilder, Writer, WriterBuilder};
+use csv::ByteRecord;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::Seek;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::{collections::BTreeMap, fs::File};
+use std::time::Instant;
 use thiserror::Error;
 
-pub(crate) type Tree = BTreeMap<Bytes, Arc<Bytes>>;
+/// A value with the sequence number of the write, with `None` as the value of a deleted
+/// key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry<V = Arc<Bytes>> {
+    pub(crate) seq: u64,
+    pub(crate) value: Option<V>,
+}
+
+impl<V> Entry<V> {
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub(crate) fn map<U>(self, f: impl FnOnce(V) -> U) -> Entry<U> {
+        Entry {
+            seq: self.seq,
+            value: self.value.map(f),
+        }
+    }
+}
+
+/// A sorted tree of entries.
+pub(crate) type Tree = BTreeMap<Bytes, Entry>;
+
+/// Writes applied as a whole, with `None` as the value of a delete.
+pub(crate) type Batch = Vec<(Bytes, Option<Bytes>)>;
+
+/// The tag of a set in a batch record of the log.
+const SET_TAG: &[u8] = b"s";
+
+/// The tag of a delete in a batch record of the log.
+const DELETE_TAG: &[u8] = b"d";
+
+/// The checksum algorithm of log records, with its table built at compile time.
+///
+/// A [`crc::Digest`] only borrows the table, so making one per record is free.
+static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_AIXM);
 
 /// Memtable Errors.
 #[derive(Debug, Error)]
@@ -22,77 +62,222 @@ pub enum MemtableError {
     /// Parse log id error.
     #[error("error parsing {0} into log id")]
     ParseLogId(String),
+
+    /// Replaying the logs is not finished before the deadline.
+    #[error("log replay timed out")]
+    RecoveryTimedOut,
+
+    /// A corrupt record is followed by valid ones, found by a strict replay.
+    #[error("corrupt record at offset {offset} of log {path:?}")]
+    CorruptWal {
+        /// The path of the log.
+        path: PathBuf,
+        /// The offset of the record in the log.
+        offset: u64,
+    },
 }
 
+/// How the logs are replayed.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Recovery {
+    /// The time the replay must be finished by.
+    pub(crate) deadline: Option<Instant>,
+    /// Whether to fail on a corrupt record followed by valid ones, instead of leaving out
+    /// the rest of the log.
+    pub(crate) strict: bool,
+}
+
+/// The number of log records replayed between two checks of the recovery deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
 /// Memtable.
 pub struct Memtable {
-    log: Writer<File>,
+    log: File,
+    log_len: u64,
+    log_buf: Vec<u8>,
     active_tree: Tree,
-    freeze_tree: Option<Arc<Tree>>,
+    freeze_trees: VecDeque<(u64, Arc<Tree>)>,
     active_size: usize,
     active_log_id: u64,
-    freeze_log_id: Option<u64>,
+    last_seq: u64,
+    skipped_records: usize,
+    last_write: Instant,
 
-    crc: Crc<u32>,
     log_dir: PathBuf,
     log_suffix: String,
     switch_active_size: usize,
 }
 
 impl Memtable {
-    fn read_record(crc: &Crc<u32>, record: &ByteRecord) -> Option<(Bytes, Bytes)> {
-        let mut digest = crc.digest();
-        let crc = Bytes::copy_from_slice(record.get(0)?).get_u32_le();
-        let key = Bytes::copy_from_slice(record.get(1)?);
-        let value = Bytes::copy_from_slice(record.get(2)?);
-        digest.update(&key);
-        digest.update(&value);
-        let check = digest.finalize();
-        if check == crc {
-            Some((key, value))
+    /// Read the sequence number and the writes of a log record.
+    ///
+    /// A record is a batch of `[crc, seq, (tag, key, value)*]` applied as a whole, with
+    /// all the writes sharing the sequence number. The crc covers all the other fields,
+    /// so a torn batch is never applied in part. Records written by older versions are
+    /// `[crc, key, value]` for a single set and `[crc, (tag, key, value)*]`, which have
+    /// no sequence number.
+    fn read_record(record: &ByteRecord) -> Option<(Option<u64>, Batch)> {
+        let mut digest = CRC.digest();
+        let crc = u32::from_le_bytes(record.get(0)?.try_into().ok()?);
+        for field in record.iter().skip(1) {
+            digest.update(field);
+        }
+        if digest.finalize() != crc {
+            return None;
+        }
+        let mut fields: Vec<&[u8]> = record.iter().skip(1).collect();
+        if fields.len() == 2 {
+            let (key, value) = (fields[0], fields[1]);
+            return Some((
+                None,
+                vec![(
+                    Bytes::copy_from_slice(key),
+                    Some(Bytes::copy_from_slice(value)),
+                )],
+            ));
+        }
+        let seq = if fields.len() % 3 == 1 {
+            let seq = fields.remove(0);
+            Some(std::str::from_utf8(seq).ok()?.parse().ok()?)
         } else {
             None
+        };
+        if fields.is_empty() || !fields.len().is_multiple_of(3) {
+            return None;
         }
+        let batch = fields
+            .chunks(3)
+            .map(|op| match op[0] {
+                SET_TAG => Some((
+                    Bytes::copy_from_slice(op[1]),
+                    Some(Bytes::copy_from_slice(op[2])),
+                )),
+                DELETE_TAG => Some((Bytes::copy_from_slice(op[1]), None)),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        Some((seq, batch))
     }
 
-    fn parse_record(&self, key: &[u8], value: &[u8]) -> ByteRecord {
-        let mut digest = self.crc.digest();
-        digest.update(key);
-        digest.update(value);
+    /// Encode a batch of writes as a single log record into `buf`, borrowing the keys and
+    /// the values as fields instead of copying them into a [`ByteRecord`].
+    fn encode_batch(
+        buf: &mut Vec<u8>,
+        seq: u64,
+        batch: &[(Bytes, Option<Bytes>)],
+    ) -> Result<(), csv::Error> {
+        let seq = seq.to_string();
+        let mut fields: Vec<&[u8]> = Vec::with_capacity(batch.len() * 3 + 1);
+        fields.push(seq.as_bytes());
+        for (key, value) in batch {
+            match value {
+                Some(value) => fields.extend([SET_TAG, key, value]),
+                None => fields.extend([DELETE_TAG, key, b""]),
+            }
+        }
+        let mut digest = CRC.digest();
+        for field in fields.iter() {
+            digest.update(field);
+        }
         let crc = digest.finalize().to_le_bytes();
-        let mut record = ByteRecord::from(vec![&crc]);
-        record.push_field(key);
-        record.push_field(value);
-        record
+        format::encode_record(buf, std::iter::once(&crc[..]).chain(fields))
     }
 
+    /// Append an encoded record to the log.
+    ///
+    /// If the write fails, the log is cut back to where it was, so a part of the record
+    /// is never left for later records to be appended to.
+    fn append_log(&mut self, record: &[u8]) -> Result<(), std::io::Error> {
+        match self.log.write_all(record).and_then(|_| self.log.flush()) {
+            Ok(()) => {
+                self.log_len += record.len() as u64;
+                Ok(())
+            }
+            Err(err) => {
+                let len = self.log_len;
+                if let Err(err) = self
+                    .log
+                    .set_len(len)
+                    .and_then(|_| self.log.seek(SeekFrom::Start(len)))
+                {
+                    tracing::error!("failed to cut the log back after a failed write: {}", err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Insert into the tree, returning the new size of the tree.
+    fn insert(tree: &mut Tree, size: usize, key: Bytes, seq: u64, value: Option<Bytes>) -> usize {
+        let len = |entry: &Entry| entry.value.as_ref().map_or(0, |value| value.len());
+        let key_size = key.len();
+        let entry = Entry {
+            seq,
+            value: value.map(Arc::new),
+        };
+        let value_size = len(&entry);
+        let size = match tree.insert(key, entry) {
+            Some(old_entry) => size - len(&old_entry),
+            None => size + key_size,
+        };
+        size + value_size
+    }
+
+    /// Replay the log into a tree, with `last_seq` updated to the largest sequence number
+    /// seen. Records without a sequence number take the next one.
+    ///
+    /// The replay stops at the first corrupt record, and `skipped` is increased by the
+    /// number of the records it leaves out. A torn write only leaves corrupt records at
+    /// the end of a log, so a strict replay fails with [`MemtableError::CorruptWal`] if
+    /// a valid record follows.
     fn build_tree_from_path<P: AsRef<Path>>(
-        crc: &Crc<u32>,
         path: &P,
-    ) -> Result<(Tree, u64, usize), std::io::Error> {
+        recovery: Recovery,
+        last_seq: &mut u64,
+        skipped: &mut usize,
+    ) -> Result<(Tree, u64, usize), MemtableError> {
         let mut tree = BTreeMap::new();
         let mut next_pos = 0;
         let mut size = 0;
-        if let Ok(mut reader) = ReaderBuilder::new()
-            .has_headers(false)
-            .flexible(true)
-            .from_path(&path)
-        {
+        let mut replayed = 0;
+        if let Ok(mut reader) = format::reader_from_path(path) {
             let mut record = ByteRecord::new();
             loop {
+                replayed += 1;
+                if replayed % DEADLINE_CHECK_INTERVAL == 0
+                    && recovery
+                        .deadline
+                        .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    return Err(MemtableError::RecoveryTimedOut);
+                }
                 match reader.read_byte_record(&mut record) {
                     Ok(more) => {
-                        if let Some((key, value)) = Self::read_record(&crc, &record) {
-                            let key_size = key.len();
-                            let value_size = value.len();
-                            if let Some(old_value) = tree.insert(key, Arc::new(value)) {
-                                size -= old_value.len();
-                            } else {
-                                size += key_size;
+                        if let Some((seq, batch)) = Self::read_record(&record) {
+                            let seq = seq.unwrap_or(*last_seq + 1);
+                            *last_seq = (*last_seq).max(seq);
+                            for (key, value) in batch {
+                                size = Self::insert(&mut tree, size, key, seq, value);
                             }
-                            size += value_size;
                             next_pos = reader.position().byte();
                         } else {
+                            if more {
+                                let offset = record
+                                    .position()
+                                    .map_or(next_pos, |position| position.byte());
+                                let mut torn = true;
+                                *skipped += 1;
+                                while let Ok(true) = reader.read_byte_record(&mut record) {
+                                    *skipped += 1;
+                                    torn &= Self::read_record(&record).is_none();
+                                }
+                                if recovery.strict && !torn {
+                                    return Err(MemtableError::CorruptWal {
+                                        path: path.as_ref().to_owned(),
+                                        offset,
+                                    });
+                                }
+                            }
                             break;
                         }
                         if !more {
@@ -100,6 +285,7 @@ impl Memtable {
                         }
                     }
                     Err(err) => {
+                        *skipped += 1;
                         tracing::error!("read record error: {}", err);
                     }
                 }
@@ -108,126 +294,354 @@ impl Memtable {
         Ok((tree, next_pos, size))
     }
 
+    /// Replay the logs, failing with [`MemtableError::RecoveryTimedOut`] if the replay is
+    /// not finished before the deadline of `recovery`.
+    ///
+    /// Logs with ids not greater than `flushed_log_id` are already written out to
+    /// segments, which happens if the process stops before removing them, so they are
+    /// removed instead of replayed. New writes take sequence numbers greater than
+    /// `flushed_seq` and the ones in the logs.
     pub fn new<P: AsRef<Path>>(
         logs: BTreeMap<String, PathBuf>,
         log_dir: P,
         log_suffix: &str,
         switch_mem_size: usize,
-    ) -> Result<(Self, Option<RawSegment>), MemtableError> {
-        let crc = Crc::<u32>::new(&CRC_32_AIXM);
-        let mut logs = logs.into_iter();
-        let mut active_tree = None;
-        let mut freeze_tree = None;
-        let mut log_file = None;
-        let mut active_log_id = 1;
-        let mut freeze_log_id = None;
-        let mut active_size = 0;
-        let mut segment = None;
-        while let Some((id, path)) = logs.next_back() {
-            if active_tree.is_none() {
-                let log_id = id.parse().map_err(|_| MemtableError::ParseLogId(id))?;
-                let (tree, next_pos, size) = Self::build_tree_from_path(&crc, &path)?;
-                active_size = size;
-                active_tree = Some(tree);
-                let mut file = OpenOptions::new().create(true).write(true).open(path)?;
-                file.seek(std::io::SeekFrom::Start(next_pos))?;
-                file.set_len(next_pos)?;
-                log_file = Some(file);
-                active_log_id = log_id;
-            } else if freeze_tree.is_none() {
-                let log_id = id.parse().map_err(|_| MemtableError::ParseLogId(id))?;
-                let (tree, _, _) = Self::build_tree_from_path(&crc, &path)?;
-                let tree = Arc::new(tree);
-                freeze_tree = Some(tree.clone());
-                freeze_log_id = Some(log_id);
-                segment = Some(RawSegment::from(tree));
-            } else {
-                let _ = std::fs::remove_file(path);
+        recovery: Recovery,
+        flushed_log_id: u64,
+        flushed_seq: u64,
+    ) -> Result<Self, MemtableError> {
+        let mut parsed = BTreeMap::new();
+        for (id, path) in logs {
+            let log_id: u64 = id.parse().map_err(|_| MemtableError::ParseLogId(id))?;
+            if log_id <= flushed_log_id {
+                std::fs::remove_file(&path)?;
+                tracing::info!("removed the log {} already written to a segment.", log_id);
+                continue;
             }
+            parsed.insert(log_id, path);
+        }
+        let active = parsed.pop_last();
+        let mut active_tree = Tree::new();
+        let mut freeze_trees = VecDeque::new();
+        let mut active_log_id = flushed_log_id + 1;
+        let mut active_size = 0;
+        let mut last_seq = flushed_seq;
+        let mut skipped_records = 0;
+        // The logs are replayed from the oldest, so records without sequence numbers are
+        // numbered in the order of the writes.
+        for (log_id, path) in parsed {
+            let (tree, _, _) =
+                Self::build_tree_from_path(&path, recovery, &mut last_seq, &mut skipped_records)?;
+            freeze_trees.push_back((log_id, Arc::new(tree)));
         }
-        let file = if let Some(log_file) = log_file {
-            log_file
+        let (log, log_len) = if let Some((log_id, path)) = active {
+            let (tree, next_pos, size) =
+                Self::build_tree_from_path(&path, recovery, &mut last_seq, &mut skipped_records)?;
+            active_size = size;
+            active_tree = tree;
+            active_log_id = log_id;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(path)?;
+            file.seek(SeekFrom::Start(next_pos))?;
+            file.set_len(next_pos)?;
+            (file, next_pos)
         } else {
             let path = log_dir
                 .as_ref()
                 .join(format!("{}.{}", active_log_id, log_suffix));
-            OpenOptions::new().create(true).write(true).open(path)?
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&path)?;
+            file.sync_all()?;
+            sync_parent_dir(&path)?;
+            (file, 0)
         };
-        let log = WriterBuilder::new().has_headers(false).from_writer(file);
-        let active_tree = active_tree.unwrap_or_default();
-        Ok((
-            Self {
-                active_size,
-                log,
-                active_tree,
-                crc,
-                freeze_tree,
-                freeze_log_id,
-                log_dir: log_dir.as_ref().to_owned(),
-                log_suffix: log_suffix.to_string(),
-                active_log_id,
-                switch_active_size: switch_mem_size,
-            },
-            segment,
-        ))
-    }
-
-    fn force_switch(&mut self) -> Result<RawSegment, std::io::Error> {
-        self.freeze_log_id = Some(self.active_log_id);
+        Ok(Self {
+            active_size,
+            log,
+            log_len,
+            log_buf: Vec::new(),
+            active_tree,
+            freeze_trees,
+            log_dir: log_dir.as_ref().to_owned(),
+            log_suffix: log_suffix.to_string(),
+            active_log_id,
+            last_seq,
+            skipped_records,
+            last_write: Instant::now(),
+            switch_active_size: switch_mem_size,
+        })
+    }
+
+    /// Freeze the active tree and switch to a new one, even if there are frozen trees
+    /// still waiting to be written out.
+    pub(crate) fn force_switch(&mut self) -> Result<(), std::io::Error> {
+        let freeze_log_id = self.active_log_id;
         self.active_log_id += 1;
         let path = self
             .log_dir
             .as_path()
             .join(format!("{}.{}", self.active_log_id, self.log_suffix));
-        let file = OpenOptions::new().create(true).write(true).open(path)?;
-        let log = WriterBuilder::new().has_headers(false).from_writer(file);
+        let log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        log.sync_all()?;
+        sync_parent_dir(&path)?;
         let mut active_tree = BTreeMap::new();
         std::mem::swap(&mut self.active_tree, &mut active_tree);
-        let tree = Arc::new(active_tree);
-        self.freeze_tree = Some(tree.clone());
+        self.freeze_trees
+            .push_back((freeze_log_id, Arc::new(active_tree)));
+        self.active_size = 0;
         self.log = log;
+        self.log_len = 0;
         tracing::info!("swithced to new memtable {}.", self.active_log_id);
-        Ok(RawSegment::from(tree))
+        Ok(())
     }
 
-    pub(crate) fn try_switch(&mut self) -> Result<Option<RawSegment>, std::io::Error> {
+    /// Switch to a new tree if the active one is too big and no frozen tree is waiting.
+    pub(crate) fn try_switch(&mut self) -> Result<bool, std::io::Error> {
         tracing::info!(
             "active_size={} switch_size={}",
             self.active_size,
             self.switch_active_size
         );
-        if self.active_size > self.switch_active_size && self.freeze_tree.is_none() {
-            let segment = self.force_switch()?;
-            self.active_size = 0;
-            Ok(Some(segment))
+        if self.active_size > self.switch_active_size && self.freeze_trees.is_empty() {
+            self.force_switch()?;
+            Ok(true)
         } else {
-            Ok(None)
+            Ok(false)
         }
     }
 
-    pub(crate) fn finalize_switch(&mut self) -> Result<(), std::io::Error> {
-        self.freeze_tree = None;
-        if let Some(log_id) = self.freeze_log_id.take() {
+    /// Whether the active tree is empty.
+    pub(crate) fn is_active_empty(&self) -> bool {
+        self.active_tree.is_empty()
+    }
+
+    /// The time since the last write, or since the memtable is created if there is none.
+    pub(crate) fn idle_time(&self) -> std::time::Duration {
+        self.last_write.elapsed()
+    }
+
+    /// Set the size above which the active tree is switched out, checked by the next
+    /// [`Memtable::try_switch`].
+    pub(crate) fn set_switch_size(&mut self, size: usize) {
+        self.switch_active_size = size;
+    }
+
+    /// The size of the active tree and the size above which it is switched out.
+    pub(crate) fn active_size(&self) -> (usize, usize) {
+        (self.active_size, self.switch_active_size)
+    }
+
+    /// The sequence number of the latest write.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// The number of the log records left out of the replay for being corrupt.
+    pub(crate) fn skipped_records(&self) -> usize {
+        self.skipped_records
+    }
+
+    /// The number of frozen trees waiting to be written out.
+    pub(crate) fn frozen_count(&self) -> usize {
+        self.freeze_trees.len()
+    }
+
+    /// The oldest frozen tree with the id of its log.
+    pub(crate) fn oldest_frozen(&self) -> Option<(u64, RawSegment<'static>)> {
+        self.freeze_trees
+            .front()
+            .map(|(log_id, tree)| (*log_id, RawSegment::new(*log_id, tree.clone())))
+    }
+
+    /// The id of the log of the active tree.
+    pub(crate) fn active_log_id(&self) -> u64 {
+        self.active_log_id
+    }
+
+    /// Drop all the trees, whose data is replaced by a segment with the id of the active
+    /// log as its log id, and switch to a new log.
+    ///
+    /// The old logs are skipped by the next replay once the segment is written, so one
+    /// left by a failed removal does no harm.
+    pub(crate) fn discard_all(&mut self) -> Result<(), std::io::Error> {
+        self.force_switch()?;
+        for (log_id, _) in self.freeze_trees.drain(..) {
             let path = self
                 .log_dir
                 .as_path()
                 .join(format!("{}.{}", log_id, self.log_suffix));
-            std::fs::remove_file(path)?;
-            tracing::info!("removed the log for freeze memtable {}.", log_id);
+            if let Err(err) = std::fs::remove_file(&path) {
+                tracing::warn!("failed to remove the log {:?}: err={}", path, err);
+            }
         }
         Ok(())
     }
 
-    pub(crate) fn to_raw_segment(&mut self) -> Option<RawSegment> {
-        if self.freeze_tree.is_none() {
+    /// Drop the frozen tree of the given log and remove the log.
+    pub(crate) fn finalize_switch(&mut self, log_id: u64) -> Result<(), std::io::Error> {
+        self.freeze_trees.retain(|(id, _)| *id != log_id);
+        let path = self
+            .log_dir
+            .as_path()
+            .join(format!("{}.{}", log_id, self.log_suffix));
+        std::fs::remove_file(path)?;
+        tracing::info!("removed the log for freeze memtable {}.", log_id);
+        Ok(())
+    }
+
+    pub(crate) fn take_raw_segment(&mut self) -> Option<RawSegment<'static>> {
+        if self.freeze_trees.is_empty() {
             let mut tree = Tree::new();
             std::mem::swap(&mut tree, &mut self.active_tree);
-            Some(RawSegment::from(Arc::new(tree)))
+            Some(RawSegment::new(self.active_log_id, Arc::new(tree)))
         } else {
             None
         }
     }
 
+    /// The number of the entries with keys in `start..end` that are not deletions, counting
+    /// a key once per tree.
+    pub(crate) fn count_range(&self, start: &[u8], end: &[u8]) -> usize {
+        let count = |tree: &Tree| {
+            tree.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+                .filter(|(_, entry)| entry.value.is_some())
+                .count()
+        };
+        count(&self.active_tree)
+            + self
+                .freeze_trees
+                .iter()
+                .map(|(_, tree)| count(tree))
+                .sum::<usize>()
+    }
+
+    /// Entries with keys in the given bounds, one sorted list per tree from the newest to
+    /// the oldest.
+    pub(crate) fn entries(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Vec<Vec<(Bytes, Entry)>> {
+        let collect = |tree: &Tree| {
+            tree.range::<[u8], _>((start, end))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        let mut entries = vec![collect(&self.active_tree)];
+        for (_, tree) in self.freeze_trees.iter().rev() {
+            entries.push(collect(tree));
+        }
+        entries
+    }
+
+    /// The path of the log of the active tree.
+    pub(crate) fn active_log_path(&self) -> PathBuf {
+        self.log_dir
+            .as_path()
+            .join(format!("{}.{}", self.active_log_id, self.log_suffix))
+    }
+
+    /// The paths of all the logs, from the oldest to the newest.
+    pub(crate) fn log_paths(&self) -> Vec<PathBuf> {
+        self.freeze_trees
+            .iter()
+            .map(|(log_id, _)| *log_id)
+            .chain(std::iter::once(self.active_log_id))
+            .map(|log_id| {
+                self.log_dir
+                    .as_path()
+                    .join(format!("{}.{}", log_id, self.log_suffix))
+            })
+            .collect()
+    }
+
+    /// Replay the logs into new trees and check that they are the same as the live trees.
+    ///
+    /// Only the keys and the values are compared, since records written by older versions
+    /// have no sequence numbers.
+    pub(crate) fn verify_logs(&self) -> Result<bool, MemtableError> {
+        let trees = self
+            .freeze_trees
+            .iter()
+            .map(|(_, tree)| tree.as_ref())
+            .chain(std::iter::once(&self.active_tree));
+        for (tree, path) in trees.zip(self.log_paths()) {
+            let (replayed, _, _) =
+                Self::build_tree_from_path(&path, Recovery::default(), &mut 0, &mut 0)?;
+            let live = tree.iter().map(|(key, entry)| (key, &entry.value));
+            if !live.eq(replayed.iter().map(|(key, entry)| (key, &entry.value))) {
+                tracing::warn!("the log {:?} does not match its tree", path);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Look up the key, with no value if the key is deleted in the memtable.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Entry> {
+        self.locate(key).map(|(entry, _)| entry)
+    }
+
+    /// Look up the key like [`Memtable::lookup`], with whether the entry is in the active
+    /// tree rather than a frozen one.
+    pub(crate) fn locate(&self, key: &[u8]) -> Option<(Entry, bool)> {
+        if let Some(entry) = self.active_tree.get(key) {
+            return Some((entry.clone(), true));
+        }
+        self.freeze_trees
+            .iter()
+            .rev()
+            .find_map(|(_, tree)| tree.get(key))
+            .map(|entry| (entry.clone(), false))
+    }
+
+    /// Apply the writes as a whole.
+    pub(crate) fn apply(&mut self, batch: Batch) -> Result<(), MapError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let seq = self.last_seq + 1;
+        let mut buf = std::mem::take(&mut self.log_buf);
+        buf.clear();
+        let res = Self::encode_batch(&mut buf, seq, &batch)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.append_log(&buf));
+        self.log_buf = buf;
+        res.map_err(MapError::WriteLog)?;
+        self.last_seq = seq;
+        self.last_write = Instant::now();
+        for (key, value) in batch {
+            self.active_size =
+                Self::insert(&mut self.active_tree, self.active_size, key, seq, value);
+        }
+        Ok(())
+    }
+
+    /// Apply the batch like [`Memtable::apply`] without writing it to the log, so it is
+    /// lost if the process stops before the tree is written out.
+    pub(crate) fn apply_unlogged(&mut self, batch: Batch) {
+        if batch.is_empty() {
+            return;
+        }
+        let seq = self.last_seq + 1;
+        self.last_seq = seq;
+        self.last_write = Instant::now();
+        for (key, value) in batch {
+            self.active_size =
+                Self::insert(&mut self.active_tree, self.active_size, key, seq, value);
+        }
+    }
+
     pub(crate) fn remove_active_log(&mut self) -> Result<bool, std::io::Error> {
         if self.active_tree.is_empty() {
             let path = self
@@ -252,37 +666,51 @@ impl Get for Memtable {
         Q: ?Sized,
         Q: AsRef<[u8]>,
     {
-        if let Some(value) = self.active_tree.get(key.as_ref()) {
-            Ok(Some(value.clone()))
-        } else if let Some(value) = self
-            .freeze_tree
-            .as_ref()
-            .and_then(|tree| tree.get(key.as_ref()))
-        {
-            Ok(Some(value.clone()))
-        } else {
-            Ok(None)
-        }
+        Ok(self.lookup(key.as_ref()).and_then(|entry| entry.value))
     }
 }
 
 impl Map for Memtable {
     fn set<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<(), MapError> {
-        let key = key.into();
-        let value = value.into();
-        let record = self.parse_record(&key, &value);
-        self.log
-            .write_record(&record)
-            .map_err(|_| MapError::WriteLog)?;
-        self.log.flush().map_err(|_| MapError::WriteLog)?;
-        let key_size = key.len();
-        let value_size = value.len();
-        if let Some(old_value) = self.active_tree.insert(key, Arc::new(value)) {
-            self.active_size -= old_value.len();
-        } else {
-            self.active_size += key_size;
-        }
-        self.active_size += value_size;
-        Ok(())
+        self.apply(vec![(key.into(), Some(value.into()))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memtable(dir: &Path) -> Memtable {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        Memtable::new(
+            BTreeMap::new(),
+            dir,
+            "log",
+            1 << 20,
+            Recovery::default(),
+            0,
+            0,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn a_failed_log_write_leaves_the_memtable_untouched() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-memtable-{}", std::process::id()));
+        let mut memtable = memtable(&dir);
+        memtable.set("kept", "1").unwrap();
+        let size = memtable.active_size;
+        let seq = memtable.last_seq();
+
+        // Writes to a file opened for reading only fail like the ones to a full disk.
+        memtable.log = File::open(memtable.active_log_path()).unwrap();
+        let err = memtable.set("lost", "2").unwrap_err();
+        assert!(matches!(err, MapError::WriteLog(_)));
+        assert!(memtable.get("lost").unwrap().is_none());
+        assert_eq!(memtable.active_size, size);
+        assert_eq!(memtable.last_seq(), seq);
+        assert_eq!(memtable.get("kept").unwrap().unwrap().as_ref(), "1");
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
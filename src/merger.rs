@@ -0,0 +1,502 @@
+//! Merging process of the segment files.
+
+use crate::cache::ValueCache;
+use crate::database::{get_from_segments, BackgroundErrors, BackgroundTask, SegmentFormat, DOT};
+use crate::iter::{MergeIter, Source};
+use crate::memtable::Entry;
+use crate::segment::{
+    write_pack, Blocks, OnCorrupt, ReadOptions, Segment, SegmentSet, SegmentWriter, Segments,
+};
+use crate::stats::SlowOps;
+use crate::{MapError, ReclaimedBytes};
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Merger of the segment files.
+pub(crate) struct Merger {
+    pub(crate) blocks: Blocks,
+    pub(crate) read_options: ReadOptions,
+    pub(crate) max_merge_segments: usize,
+    pub(crate) self_compact_tombstone_ratio: f64,
+    pub(crate) max_segments: Option<usize>,
+    pub(crate) pack_segments: Option<usize>,
+    pub(crate) max_segment_id: Arc<Mutex<u64>>,
+    pub(crate) segments: Arc<SegmentSet>,
+    pub(crate) dir: PathBuf,
+    pub(crate) suffix: String,
+    pub(crate) tmp_suffix: String,
+    pub(crate) pack_suffix: String,
+    pub(crate) segment_format: SegmentFormat,
+    pub(crate) value_cache: Option<Arc<ValueCache>>,
+    pub(crate) slow_ops: SlowOps,
+    pub(crate) background_errors: Arc<BackgroundErrors>,
+}
+
+impl Merger {
+    /// Merge segments every `merge_period` until receiving from `exiter`.
+    ///
+    /// A merge only rewrites a bounded number of segments, so when more segments are left
+    /// than a single merge can take, the next merge starts at the next poll instead of
+    /// waiting for another `merge_period`.
+    pub(crate) fn run(
+        &self,
+        merge_period: Duration,
+        poll_period: Duration,
+        exiter: mpsc::Receiver<()>,
+    ) -> Result<(), std::io::Error> {
+        let mut last_tick = Instant::now();
+        let mut backlog = false;
+        loop {
+            thread::sleep(poll_period);
+            match exiter.try_recv() {
+                Ok(()) | Err(mpsc::TryRecvError::Disconnected) => {
+                    break;
+                }
+                Err(_) => {
+                    if backlog || last_tick.elapsed() >= merge_period {
+                        if self.segments.snapshot().len() <= 1 {
+                            backlog = false;
+                            if self.self_compact() {
+                                last_tick = Instant::now();
+                            }
+                            continue;
+                        }
+                        last_tick = Instant::now();
+                        backlog = self.merge_once();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge the picked segments into a new segment.
+    ///
+    /// Return whether there are still more segments than a single merge can take.
+    pub(crate) fn merge_once(&self) -> bool {
+        let mut segment_id = self.max_segment_id.lock().unwrap();
+        let ids = self.pick(&self.segments.snapshot());
+        if ids.len() <= 1 {
+            return false;
+        }
+        *segment_id += 1;
+        let res = self.merge(*segment_id, &ids);
+        if let Err(err) = &res {
+            tracing::error!("failed to merge segments {:?}: err={}", ids, err);
+        }
+        self.background_errors.record(BackgroundTask::Merge, &res);
+        self.segments.snapshot().len() > self.max_merge_segments
+    }
+
+    /// Rewrite the only segment without its tombstones if they make up more than
+    /// `self_compact_tombstone_ratio` of its records, returning whether it is rewritten.
+    fn self_compact(&self) -> bool {
+        let mut segment_id = self.max_segment_id.lock().unwrap();
+        let segments = self.segments.snapshot();
+        let id = match segments.iter().next() {
+            Some((id, segment)) if segments.len() == 1 => {
+                let footer = segment.footer();
+                if footer.tombstone_count == 0
+                    || (footer.tombstone_count as f64)
+                        <= footer.record_count as f64 * self.self_compact_tombstone_ratio
+                {
+                    return false;
+                }
+                *id
+            }
+            _ => return false,
+        };
+        *segment_id += 1;
+        let res = self.merge(*segment_id, &[id]);
+        if let Err(err) = &res {
+            tracing::error!("failed to compact segment {}: err={}", id, err);
+        }
+        self.background_errors.record(BackgroundTask::Merge, &res);
+        true
+    }
+
+    /// Merge all the segments into one, dropping the shadowed entries and the tombstones.
+    pub(crate) fn compact(&self) -> Result<(), std::io::Error> {
+        let mut segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let segments = self.segments.snapshot();
+        let ids: Vec<u64> = segments.keys().rev().copied().collect();
+        let has_tombstones = segments
+            .values()
+            .any(|segment| segment.footer().tombstone_count > 0);
+        if ids.len() <= 1 && !has_tombstones {
+            return Ok(());
+        }
+        *segment_id += 1;
+        self.merge(*segment_id, &ids)
+    }
+
+    /// Merge the segments written more than `age` ago into one, leaving the newer ones
+    /// untouched.
+    ///
+    /// Segments written before the creation time was recorded are taken as old.
+    pub(crate) fn compact_older_than(&self, age: Duration) -> Result<(), std::io::Error> {
+        let mut segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let cutoff = SystemTime::now()
+            .checked_sub(age)
+            .and_then(|cutoff| cutoff.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let segments = self.segments.snapshot();
+        let ids: Vec<u64> = segments
+            .iter()
+            .rev()
+            .filter(|(_, segment)| segment.footer().created_at <= cutoff)
+            .map(|(id, _)| *id)
+            .collect();
+        // A lone segment is only worth rewriting when its tombstones can be dropped.
+        let drops_tombstones = ids.len() == segments.len()
+            && segments
+                .values()
+                .any(|segment| segment.footer().tombstone_count > 0);
+        if ids.len() <= 1 && !drops_tombstones {
+            return Ok(());
+        }
+        *segment_id += 1;
+        self.merge(*segment_id, &ids)
+    }
+
+    /// Rewrite the segment with the given id without its dead records, returning `None`
+    /// if there is no such segment.
+    ///
+    /// A record is dead if another segment has a newer entry of the key, or one of the
+    /// same sequence number in a segment with a larger id, which wins the tie. A tombstone
+    /// is dead as well if no other segment has the key at all. The rewritten segment takes
+    /// a new id, which is fine as entries are resolved by their sequence numbers once the
+    /// tied ones are dropped.
+    pub(crate) fn vacuum(&self, id: u64) -> Result<Option<ReclaimedBytes>, std::io::Error> {
+        let mut segment_id = self
+            .max_segment_id
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut older = (*self.segments.snapshot()).clone();
+        let segment = match older.remove(&id) {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let newer = older.split_off(&id);
+        let size = segment.size()?;
+        *segment_id += 1;
+        let new_id = *segment_id;
+        let path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", new_id, DOT, self.suffix));
+        let tmp_path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", new_id, DOT, self.tmp_suffix));
+        tracing::info!("vacuuming segment {} to path {:?}", id, tmp_path);
+        let result = self
+            .write_vacuumed(id, &segment, &older, &newer, &tmp_path)
+            .and_then(|(mut vacuumed, dropped_records)| {
+                if vacuumed.footer().record_count == 0 {
+                    return Ok((None, dropped_records));
+                }
+                vacuumed.set_read_options(&self.read_options)?;
+                vacuumed.initialize_index(self.blocks)?;
+                vacuumed.move_to(&path)?;
+                Ok((Some(vacuumed), dropped_records))
+            });
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        let (vacuumed, dropped_records) = result?;
+        let vacuumed_id = vacuumed.as_ref().map(|_| new_id);
+        let new_size = match &vacuumed {
+            Some(vacuumed) => vacuumed.size()?,
+            None => 0,
+        };
+        self.segments.update(|segments| {
+            if let Some(old_segment) = segments.remove(&id) {
+                old_segment.mark_obsolete();
+            }
+            if let Some(vacuumed) = vacuumed {
+                segments.insert(new_id, Arc::new(vacuumed));
+            }
+        });
+        tracing::info!("vacuumed segment {} into {:?}", id, vacuumed_id);
+        Ok(Some(ReclaimedBytes {
+            segment_id: vacuumed_id,
+            dropped_records,
+            bytes: size.saturating_sub(new_size),
+        }))
+    }
+
+    /// Merge the newest segments until a new segment can be added without exceeding
+    /// `max_segments`, with the lock of the segment id held by the caller.
+    pub(crate) fn make_room(&self, segment_id: &mut u64) -> Result<(), std::io::Error> {
+        let max_segments = match self.max_segments {
+            Some(max_segments) => max_segments,
+            None => return Ok(()),
+        };
+        let segments = self.segments.snapshot();
+        if segments.len() < max_segments {
+            return Ok(());
+        }
+        // Merging `count` segments into one leaves room for the new one.
+        let count = segments.len() + 2 - max_segments;
+        let ids: Vec<u64> = segments.keys().rev().take(count).copied().collect();
+        tracing::info!(
+            "{} segments reach the limit {}, merging {:?} first",
+            segments.len(),
+            max_segments,
+            ids
+        );
+        *segment_id += 1;
+        self.merge(*segment_id, &ids)
+    }
+
+    /// Pack the segments in files of their own into a new pack file once there are
+    /// `pack_segments` of them, with the lock of the segment id held by the caller.
+    ///
+    /// The pack is named after the largest id in it, which no other pack can have since
+    /// packed segments are never packed again.
+    pub(crate) fn pack(&self) -> Result<(), std::io::Error> {
+        let count = match self.pack_segments {
+            Some(count) => count,
+            None => return Ok(()),
+        };
+        let segments = self.segments.snapshot();
+        let unpacked: Vec<(u64, &Segment)> = segments
+            .iter()
+            .filter(|(_, segment)| !segment.is_packed())
+            .map(|(id, segment)| (*id, segment.as_ref()))
+            .collect();
+        let id = match unpacked.last() {
+            Some((id, _)) if unpacked.len() >= count => *id,
+            _ => return Ok(()),
+        };
+        let ids: Vec<u64> = unpacked.iter().map(|(id, _)| *id).collect();
+        let path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", id, DOT, self.pack_suffix));
+        let tmp_path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", id, DOT, self.tmp_suffix));
+        tracing::info!("packing segments {:?} to path {:?}", ids, tmp_path);
+        let result = write_pack(&unpacked, &tmp_path, &path);
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        let packed = result?;
+        self.segments.update(|segments| {
+            for (id, segment) in packed {
+                if let Some(old_segment) = segments.insert(id, Arc::new(segment)) {
+                    old_segment.mark_obsolete();
+                }
+            }
+        });
+        tracing::info!("packed segments {:?} to path {:?}", ids, path);
+        Ok(())
+    }
+
+    /// Pick the segments to merge.
+    ///
+    /// Segments are picked from the newest one, so the merged segment can take a new id
+    /// without shadowing newer data. Picking stops at `max_merge_segments` segments, or
+    /// at a segment larger than all the picked ones together, so small segments are
+    /// merged first and the work of a single merge stays bounded.
+    fn pick(&self, segments: &Segments) -> Vec<u64> {
+        let mut ids = Vec::new();
+        let mut total = 0;
+        for (id, segment) in segments.iter().rev() {
+            if ids.len() >= self.max_merge_segments {
+                break;
+            }
+            let size = segment.size().unwrap_or_default();
+            if ids.len() >= 2 && size > total {
+                break;
+            }
+            total += size;
+            ids.push(*id);
+        }
+        ids
+    }
+
+    fn merge(&self, segment_id: u64, ids: &[u64]) -> Result<(), std::io::Error> {
+        self.slow_ops
+            .time("merge", || self.merge_into(segment_id, ids))
+    }
+
+    /// Merge the segments `ids` into a new segment with id `segment_id`.
+    fn merge_into(&self, segment_id: u64, ids: &[u64]) -> Result<(), std::io::Error> {
+        let input_bytes: u64 = {
+            let segments = self.segments.snapshot();
+            ids.iter()
+                .filter_map(|id| segments.get(id))
+                .map(|segment| segment.size().unwrap_or_default())
+                .sum()
+        };
+        let span = tracing::info_span!(
+            "merge",
+            segment_id,
+            inputs = ?ids,
+            input_bytes,
+            bytes = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", segment_id, DOT, self.suffix));
+        let tmp_path = self
+            .dir
+            .as_path()
+            .join(format!("{}{}{}", segment_id, DOT, self.tmp_suffix));
+        tracing::info!("merging segments {:?} to path {:?}", ids, tmp_path);
+        let result = self.write_merged(ids, &tmp_path).and_then(|mut segment| {
+            segment.set_read_options(&self.read_options)?;
+            segment.initialize_index(self.blocks)?;
+            segment.move_to(&path)?;
+            Ok(segment)
+        });
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        let segment = Arc::new(result?);
+        span.record("bytes", segment.size().unwrap_or_default());
+        // The merged segment replaces its inputs in one snapshot, and their files outlive
+        // every snapshot still holding them, so a reader never misses a merged key.
+        self.segments.update(|segments| {
+            for id in ids {
+                if let Some(old_segment) = segments.remove(id) {
+                    old_segment.mark_obsolete();
+                }
+            }
+            segments.insert(segment_id, segment);
+        });
+        tracing::info!("merged segments {:?} to path {:?}", ids, path);
+        Ok(())
+    }
+
+    /// Write the live records of the segment `id` with `older` and `newer` being the other
+    /// segments with smaller and larger ids, returning the written segment with the
+    /// number of the dropped records.
+    fn write_vacuumed<P: AsRef<Path>>(
+        &self,
+        id: u64,
+        segment: &Segment,
+        older: &Segments,
+        newer: &Segments,
+        path: &P,
+    ) -> Result<(Segment, u64), std::io::Error> {
+        let mut writer = SegmentWriter::create(path, self.segment_format, self.blocks)?;
+        writer.inherit(segment.footer());
+        let mut dropped_records = 0;
+        let on_corrupt = OnCorrupt::Fail { segment_id: id };
+        for entry in segment.entries(Bound::Unbounded, Bound::Unbounded, on_corrupt)? {
+            let (key, entry) = entry?;
+            // A corrupted block of another segment fails the vacuum, instead of hiding a
+            // newer entry, or an older one that a tombstone still has to hide.
+            // The rewritten segment takes the largest id, so an entry tied with a segment
+            // of a larger id, e.g. an ingested one, is dead as it would win the tie now.
+            let newer = get_from_segments(newer, &key, true).map_err(into_io_error)?;
+            let older = get_from_segments(older, &key, true).map_err(into_io_error)?;
+            let dead = match (newer, older) {
+                (Some(newer), _) if newer.seq >= entry.seq => true,
+                (_, Some(older)) if older.seq > entry.seq => true,
+                (None, None) => entry.value.is_none(),
+                _ => false,
+            };
+            if dead {
+                dropped_records += 1;
+            } else {
+                writer.write(&key, entry.value.as_deref().map(AsRef::as_ref), entry.seq)?;
+            }
+        }
+        Ok((writer.finish()?, dropped_records))
+    }
+
+    fn write_merged<P: AsRef<Path>>(
+        &self,
+        ids: &[u64],
+        path: &P,
+    ) -> Result<Segment, std::io::Error> {
+        let mut sources: Vec<Source<(u64, Entry)>> = Vec::new();
+        let segments = self.segments.snapshot();
+        // A tombstone still hides the entries in the older segments, so it can only be
+        // dropped when the oldest segment is merged as well.
+        let drop_tombstones = ids.len() == segments.len();
+        // An entry loses a tie of sequence numbers to the same key in a segment with a
+        // larger id, e.g. an ingested one. The merged segment takes the largest id, so an
+        // entry is dropped instead if a segment left out with a larger id than its own
+        // has the key with at least its sequence number.
+        let left_out: Segments = segments
+            .iter()
+            .filter(|(id, _)| !ids.contains(id))
+            .map(|(id, segment)| (*id, segment.clone()))
+            .collect();
+        let mut newer = HashMap::new();
+        for id in ids {
+            if let Some(segment) = segments.get(id) {
+                let id = *id;
+                // A record that cannot be read fails the merge, which is tried again later,
+                // instead of leaving the merged segment without it.
+                let entries = segment
+                    .entries(
+                        Bound::Unbounded,
+                        Bound::Unbounded,
+                        OnCorrupt::Fail { segment_id: id },
+                    )?
+                    .map(move |entry| {
+                        entry
+                            .map(|(key, entry)| (key, (id, entry)))
+                            .map_err(MapError::from)
+                    });
+                sources.push(Box::new(entries));
+                let newer_left_out: Segments = left_out
+                    .range(id + 1..)
+                    .map(|(id, segment)| (*id, segment.clone()))
+                    .collect();
+                newer.insert(id, newer_left_out);
+            }
+        }
+        let mut writer = SegmentWriter::create(path, self.segment_format, self.blocks)?;
+        for id in ids {
+            if let Some(segment) = segments.get(id) {
+                writer.inherit(segment.footer());
+            }
+        }
+        for entry in MergeIter::by_seq(sources, |(_, entry)| entry.seq) {
+            let (key, (id, entry)) = entry.map_err(into_io_error)?;
+            let shadowed = match newer.get(&id) {
+                Some(newer) if !newer.is_empty() => get_from_segments(newer, &key, true)
+                    .map_err(into_io_error)?
+                    .is_some_and(|other| other.seq >= entry.seq),
+                _ => false,
+            };
+            if shadowed {
+                continue;
+            }
+            if entry.value.is_some() || !drop_tombstones {
+                writer.write(&key, entry.value.as_deref().map(AsRef::as_ref), entry.seq)?;
+            }
+        }
+        writer.finish()
+    }
+}
+
+/// Take a [`MapError`] of reading the segments as an io error, as it is one but for a
+/// corrupted segment.
+fn into_io_error(err: MapError) -> std::io::Error {
+    match err {
+        MapError::Io(err) => err,
+        err => std::io::Error::other(err),
+    }
+}
@@ -0,0 +1,128 @@
+//! JSON lines for the results of commands, as printed by the `nouz` REPL with `--json`.
+//!
+//! Each function returns a single line of JSON without the trailing newline. Values that
+//! are valid UTF-8 are written as strings under `value`, and other values are written
+//! base64-encoded under `value_base64`, so a script reading the lines gets the exact
+//! bytes back either way.
+
+use std::fmt::{self, Write};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The result of a get, `{"found":false}` for a missing key.
+pub fn format_get_result(value: Option<&[u8]>) -> String {
+    match value {
+        Some(value) => match std::str::from_utf8(value) {
+            Ok(value) => format!("{{\"found\":true,\"value\":{}}}", json_string(value)),
+            Err(_) => format!("{{\"found\":true,\"value_base64\":\"{}\"}}", base64(value)),
+        },
+        None => "{\"found\":false}".to_string(),
+    }
+}
+
+/// The result of a successful set.
+pub fn format_set_result() -> String {
+    "{\"ok\":true}".to_string()
+}
+
+/// A failed command, with the message of the error.
+pub fn format_error(err: &dyn fmt::Display) -> String {
+    format!("{{\"error\":{}}}", json_string(&err.to_string()))
+}
+
+/// A JSON string literal of `s`, escaping the quotes, the backslashes and the control
+/// characters.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The standard base64 encoding of `bytes`, with padding.
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (idx, byte)| n | (*byte as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_the_last_chunk() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+    }
+
+    #[test]
+    fn json_strings_escape_quotes_and_control_characters() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(
+            json_string("a \"b\" \\ c\n\t\r"),
+            "\"a \\\"b\\\" \\\\ c\\n\\t\\r\""
+        );
+        assert_eq!(json_string("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+        assert_eq!(json_string("é✓"), "\"é✓\"");
+    }
+
+    #[test]
+    fn get_results_are_strings_or_base64() {
+        assert_eq!(
+            format_get_result(Some(b"hello")),
+            "{\"found\":true,\"value\":\"hello\"}"
+        );
+        assert_eq!(
+            format_get_result(Some(b"")),
+            "{\"found\":true,\"value\":\"\"}"
+        );
+        // Not valid UTF-8.
+        assert_eq!(
+            format_get_result(Some(&[0x00, 0xc3, 0x28])),
+            "{\"found\":true,\"value_base64\":\"AMMo\"}"
+        );
+        assert_eq!(format_get_result(None), "{\"found\":false}");
+    }
+
+    #[test]
+    fn sets_and_errors_are_single_lines() {
+        assert_eq!(format_set_result(), "{\"ok\":true}");
+        let err = crate::MapError::KeyNotAllow;
+        assert_eq!(format_error(&err), "{\"error\":\"key is not allowed\"}");
+        let err = std::io::Error::other("line one\nline \"two\"");
+        let line = format_error(&err);
+        assert_eq!(line, "{\"error\":\"line one\\nline \\\"two\\\"\"}");
+        assert!(!line.contains('\n'));
+    }
+}
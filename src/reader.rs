@@ -0,0 +1,176 @@
+//! The [`SegmentSetReader`] structure, [`read_all_records`] and [`inspect_directory`].
+
+use crate::database::{
+    get_from_segments, open_packed_segments, open_segment, resolve, segment_info,
+    split_off_replaced, Error, DOT,
+};
+use crate::schema::KeyNormalizer;
+use crate::segment::{record_value, Blocks, OnCorrupt, Segment, Segments};
+use crate::{DatabaseBuilder, Get, MapError, SegmentInfo, ValueResolver};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Read the keys and the values of a single segment file in key order, without opening
+/// the database.
+///
+/// The deleted keys are left out. Segments have no checksums, so the file is checked
+/// for malformed or unordered records before it is read, which fail with
+/// [`std::io::ErrorKind::InvalidData`].
+///
+/// ```
+/// use nouzdb::{read_all_records, DatabaseBuilder, Map};
+///
+/// let dir = std::env::temp_dir().join(format!("nouzdb-doc-{}", std::process::id()));
+/// let mut db = DatabaseBuilder::default().sync_flush(true).open(&dir)?;
+/// db.set("b", "2")?;
+/// db.set("a", "1")?;
+/// db.delete("c")?;
+/// db.flush()?;
+/// let (_, path) = db.segment_paths().pop().unwrap();
+///
+/// let records = read_all_records(&path)?.collect::<Result<Vec<_>, _>>()?;
+/// assert_eq!(records, [("a".into(), "1".into()), ("b".into(), "2".into())]);
+/// # drop(db);
+/// # std::fs::remove_dir_all(&dir)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn read_all_records<P: AsRef<Path>>(
+    path: &P,
+) -> Result<impl Iterator<Item = Result<(Bytes, Bytes), std::io::Error>>, std::io::Error> {
+    let mut segment = Segment::from_path(path);
+    segment.initialize_index(Blocks::default())?;
+    if !segment.is_indexed() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "malformed or unordered records in segment {:?}",
+                path.as_ref()
+            ),
+        ));
+    }
+    let entries = segment.entries_with(
+        Bound::Unbounded,
+        Bound::Unbounded,
+        true,
+        OnCorrupt::Skip,
+        |record, pool| pool.copy(record_value(record)),
+    )?;
+    Ok(entries.filter_map(|entry| match entry {
+        Ok((key, entry)) => entry.value.map(|value| Ok((key, value))),
+        Err(err) => Some(Err(err)),
+    }))
+}
+
+/// Information of the segment files with the given suffix in a data folder, from the
+/// oldest to the newest, without opening the database.
+///
+/// Each file is read through to find its statistics and its smallest and largest keys,
+/// so this takes as long as scanning all segments. Packs, logs and the segments replaced
+/// by a later one, which a stop in the middle of `replace_all` leaves, are left out.
+pub fn inspect_directory<P: AsRef<Path>>(
+    path: &P,
+    data_suffix: &str,
+) -> Result<Vec<SegmentInfo>, Error> {
+    let mut segments = BTreeMap::new();
+    for entry in path.as_ref().read_dir()?.flatten() {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(Error::InvalidLogFileName)?;
+        if let Some((id, suffix)) = name.rsplit_once(DOT) {
+            if suffix == data_suffix {
+                let id = id
+                    .parse::<u64>()
+                    .map_err(|_| Error::ParseSegemntId(id.to_string()))?;
+                let mut segment = Segment::from_path(&entry.path());
+                segment.initialize_index(Blocks::default())?;
+                segments.insert(id, segment);
+            }
+        }
+    }
+    split_off_replaced(&mut segments);
+    Ok(segments
+        .iter()
+        .map(|(id, segment)| segment_info(*id, segment))
+        .collect::<Result<_, _>>()?)
+}
+
+/// A read-only view over the segments of a data folder.
+///
+/// Only the segments are read, so writes still in the logs are not seen. There is no
+/// memtable and no merging task, and the files are never modified.
+#[derive(Debug)]
+pub struct SegmentSetReader {
+    segments: Segments,
+    key_normalizer: Option<KeyNormalizer>,
+    value_resolver: Option<Arc<dyn ValueResolver>>,
+    strict_reads: bool,
+}
+
+impl SegmentSetReader {
+    pub(crate) fn new(path: &Path, options: &DatabaseBuilder) -> Result<Self, Error> {
+        options.validate()?;
+        let mut segments = Segments::new();
+        let mut packed = Vec::new();
+        let read_options = options.read_options();
+        for entry in path.read_dir()?.flatten() {
+            if let Some((id, suffix)) = entry
+                .file_name()
+                .into_string()
+                .map_err(Error::InvalidLogFileName)?
+                .rsplit_once(DOT)
+            {
+                if suffix == options.data_suffix {
+                    let (id, segment) =
+                        open_segment(id, &entry.path(), &read_options, options.blocks())?;
+                    segments.insert(id, Arc::new(segment));
+                } else if suffix == options.pack_suffix {
+                    packed.extend(open_packed_segments(
+                        &entry.path(),
+                        &read_options,
+                        options.blocks(),
+                    )?);
+                }
+            }
+        }
+        // A segment in a pack may still have its own file, which has the same content.
+        for (id, segment) in packed {
+            segments.insert(id, Arc::new(segment));
+        }
+        // The replaced segments are left in place, as the files are never modified.
+        split_off_replaced(&mut segments);
+        Ok(Self {
+            segments,
+            key_normalizer: options.key_normalizer.clone(),
+            value_resolver: options.value_resolver.clone(),
+            strict_reads: options.strict_reads,
+        })
+    }
+
+    /// The number of segments.
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Whether there are no segments.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+impl Get for SegmentSetReader {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        let normalized = KeyNormalizer::apply(self.key_normalizer.as_ref(), key.as_ref());
+        let key = normalized.as_deref().unwrap_or(key.as_ref());
+        let value = get_from_segments(&self.segments, key, self.strict_reads)?
+            .and_then(|entry| entry.value);
+        resolve(self.value_resolver.as_ref(), value)
+    }
+}
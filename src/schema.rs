@@ -0,0 +1,83 @@
+//! Schema of keys.
+
+use crate::{MapError, Validator};
+use bytes::Bytes;
+use std::fmt;
+use std::sync::Arc;
+
+/// The schema that all keys must follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeySchema {
+    /// Any keys.
+    #[default]
+    Any,
+
+    /// Keys of a fixed width in bytes.
+    FixedWidth(usize),
+
+    /// Big-endian encoded `u64`s, such as timestamps, which sort by their values.
+    BigEndianU64,
+}
+
+impl KeySchema {
+    /// Whether the key follows the schema.
+    pub fn validate(&self, key: &[u8]) -> bool {
+        match self {
+            Self::Any => true,
+            Self::FixedWidth(width) => key.len() == *width,
+            Self::BigEndianU64 => key.len() == std::mem::size_of::<u64>(),
+        }
+    }
+}
+
+/// The largest length of a key in bytes, which the segment index stores in 32 bits.
+///
+/// Keys and values are held whole in memory while they are read and written, but are
+/// otherwise not limited, a value of many megabytes round-trips like any other.
+pub const MAX_KEY_LEN: usize = u32::MAX as usize;
+
+/// The checks of the writes, by the key schema and the validator.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WriteCheck {
+    pub(crate) key_schema: KeySchema,
+    pub(crate) validator: Option<Arc<dyn Validator>>,
+}
+
+impl WriteCheck {
+    /// Check the normalized key and the value, with `None` for a delete, which the
+    /// validator does not see.
+    pub(crate) fn check(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), MapError> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(MapError::KeyTooLarge(key.len()));
+        }
+        if !self.key_schema.validate(key) {
+            return Err(MapError::KeyNotAllow);
+        }
+        match (&self.validator, value) {
+            (Some(validator), Some(value)) => {
+                validator.validate(key, value).map_err(MapError::Rejected)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A function mapping keys to the normalized form that is stored.
+pub type NormalizeFn = dyn Fn(&[u8]) -> Bytes + Send + Sync;
+
+/// A shared [`NormalizeFn`].
+#[derive(Clone)]
+pub(crate) struct KeyNormalizer(pub(crate) Arc<NormalizeFn>);
+
+impl KeyNormalizer {
+    /// The normalized key, or `None` if there is no normalizer.
+    pub(crate) fn apply(normalizer: Option<&Self>, key: &[u8]) -> Option<Bytes> {
+        normalizer.map(|normalizer| (normalizer.0)(key))
+    }
+}
+
+impl fmt::Debug for KeyNormalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyNormalizer")
+    }
+}
@@ -1,58 +1,841 @@
-use crate::memtable::Tree;
+use crate::builder::{DEFAULT_BLOCK_SIZE, DEFAULT_READ_BUFFER_SIZE};
+use crate::cache::IndexCache;
+use crate::database::SegmentFormat;
+use crate::format;
+use crate::index::BlockIndex;
+use crate::iter::{after_start, before_end};
+use crate::memtable::{Entry, Tree};
+use crate::schema::MAX_KEY_LEN;
 use crate::{Get, MapError};
-use bytes::Bytes;
-use csv::{ByteRecord, Reader, ReaderBuilder, WriterBuilder};
+use bytes::{Bytes, BytesMut};
+use csv::{ByteRecord, Reader, Writer};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Raw Segment.
-pub struct RawSegment {
-    freeze: Arc<Tree>,
+pub struct RawSegment<'a> {
+    entries: RawEntries<'a>,
+    log_id: u64,
 }
 
-impl RawSegment {
+/// The entries of a [`RawSegment`].
+enum RawEntries<'a> {
+    /// A frozen tree of the memtable.
+    Tree(Arc<Tree>),
+    /// Entries sorted by key without duplicates, all with the sequence number `seq`.
+    Sorted {
+        seq: u64,
+        iter: Box<dyn Iterator<Item = (Bytes, Bytes)> + 'a>,
+    },
+}
+
+impl RawSegment<'static> {
+    pub(crate) fn new(log_id: u64, freeze: Arc<Tree>) -> Self {
+        Self {
+            entries: RawEntries::Tree(freeze),
+            log_id,
+        }
+    }
+}
+
+impl<'a> RawSegment<'a> {
+    /// A segment of the entries, which must be sorted by key without duplicates, all with
+    /// the sequence number `seq`.
+    ///
+    /// The entries are written as they come, so unlike a frozen tree they are never held
+    /// in memory. The order is checked as they are written, and writing fails with
+    /// [`MapError::UnorderedKeys`] at the first entry out of order or duplicated.
+    pub(crate) fn from_sorted_iter<I>(seq: u64, iter: I) -> Self
+    where
+        I: IntoIterator<Item = (Bytes, Bytes)>,
+        I::IntoIter: 'a,
+    {
+        Self {
+            entries: RawEntries::Sorted {
+                seq,
+                iter: Box::new(iter.into_iter()),
+            },
+            log_id: 0,
+        }
+    }
+
     /// Write to path.
-    pub fn write_to_path<P: AsRef<Path>>(&self, path: &P) -> Result<Segment, std::io::Error> {
-        let file = OpenOptions::new().create(true).write(true).open(path)?;
-        let mut writer = WriterBuilder::new().has_headers(false).from_writer(file);
-        for (key, value) in self.freeze.iter() {
-            let mut record = ByteRecord::new();
-            record.push_field(key);
-            record.push_field(value);
-            writer.write_byte_record(&record)?;
+    pub fn write_to_path<P: AsRef<Path>>(
+        self,
+        path: &P,
+        format: SegmentFormat,
+        blocks: Blocks,
+    ) -> Result<Segment, std::io::Error> {
+        let mut writer = SegmentWriter::create(path, format, blocks)?;
+        writer.log_id(self.log_id);
+        match self.entries {
+            RawEntries::Tree(freeze) => {
+                for (key, entry) in freeze.iter() {
+                    writer.write(key, entry.value.as_deref().map(AsRef::as_ref), entry.seq)?;
+                }
+            }
+            RawEntries::Sorted { seq, iter } => {
+                for (key, value) in iter {
+                    writer.write(&key, Some(&value), seq)?;
+                }
+            }
         }
-        Ok(Segment::from_path(path))
+        writer.finish()
     }
 
+    /// Whether there are no entries, which sorted entries are never taken for, as they
+    /// are only known once written.
     pub(crate) fn is_empty(&self) -> bool {
-        self.freeze.is_empty()
+        match &self.entries {
+            RawEntries::Tree(freeze) => freeze.is_empty(),
+            RawEntries::Sorted { .. } => false,
+        }
+    }
+}
+
+/// Sync the directory holding `path`, so that a file just created or renamed at `path`
+/// is still there after a crash.
+///
+/// Directories cannot be opened as files on every platform, so this only syncs on unix.
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// The first field of the footer record.
+const FOOTER_MAGIC: &[u8] = b"\0footer";
+
+/// The only field of the first record of a columnar segment.
+const COLUMNS_MAGIC: &[u8] = b"\0columns";
+
+/// The third field of a tombstone record, which is `[key, "", TOMBSTONE_TAG, seq]`.
+const TOMBSTONE_TAG: &[u8] = b"\0tombstone";
+
+/// Statistics of a segment, stored in the footer record at the end of its file.
+///
+/// The footer record has more fields than an entry record, so it can never be taken for
+/// an entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Footer {
+    pub(crate) record_count: u64,
+    pub(crate) key_bytes: u64,
+    pub(crate) value_bytes: u64,
+    pub(crate) tombstone_count: u64,
+    /// The id of the newest log whose data is in the segment, or 0 if there is none.
+    pub(crate) log_id: u64,
+    /// The largest sequence number of the entries.
+    pub(crate) max_seq: u64,
+    /// The time the segment file is written, in seconds since the Unix epoch, or 0 if it
+    /// is unknown.
+    pub(crate) created_at: u64,
+    /// The id of the segment that replaces all the segments with smaller ids, see
+    /// [`Database::replace_all`](crate::Database::replace_all), or 0 if there is none.
+    pub(crate) replaces: u64,
+}
+
+impl Footer {
+    fn add(&mut self, key: &[u8], value_len: Option<usize>, seq: u64) {
+        self.record_count += 1;
+        self.max_seq = self.max_seq.max(seq);
+        self.key_bytes += key.len() as u64;
+        match value_len {
+            Some(len) => self.value_bytes += len as u64,
+            None => self.tombstone_count += 1,
+        }
+    }
+
+    fn to_record(self) -> ByteRecord {
+        let mut record = ByteRecord::new();
+        record.push_field(FOOTER_MAGIC);
+        for stat in [
+            self.record_count,
+            self.key_bytes,
+            self.value_bytes,
+            self.tombstone_count,
+            self.log_id,
+            self.max_seq,
+            self.created_at,
+            self.replaces,
+        ] {
+            record.push_field(stat.to_string().as_bytes());
+        }
+        record
+    }
+
+    fn from_record(record: &ByteRecord) -> Option<Self> {
+        if record.len() < 4 || record.get(0)? != FOOTER_MAGIC {
+            return None;
+        }
+        let stat = |idx| std::str::from_utf8(record.get(idx)?).ok()?.parse().ok();
+        // Fields added later are missing in the footers of older segments.
+        let optional_stat = |idx| {
+            if record.len() > idx {
+                stat(idx)
+            } else {
+                Some(0)
+            }
+        };
+        Some(Self {
+            record_count: stat(1)?,
+            key_bytes: stat(2)?,
+            value_bytes: stat(3)?,
+            tombstone_count: optional_stat(4)?,
+            log_id: optional_stat(5)?,
+            max_seq: optional_stat(6)?,
+            created_at: optional_stat(7)?,
+            replaces: optional_stat(8)?,
+        })
+    }
+}
+
+/// Writer of a new segment file.
+///
+/// The fields of a record are borrowed from the entry, and the sequence number is
+/// formatted into a buffer reused across records, so writing an entry allocates nothing.
+///
+/// A columnar segment starts with a `[COLUMNS_MAGIC]` record, and its entry records are
+/// `[key, seq, offset, len]` and its tombstones `[key, seq]`. The values are written as
+/// they come to a file of their own, which is appended after the footer once finished,
+/// each value at `offset` from the end of the footer.
+///
+/// With aligned blocks, a record starting a block is preceded by blank lines up to the
+/// alignment, which all readers skip.
+pub(crate) struct SegmentWriter {
+    writer: Writer<CountingWriter<BufWriter<File>>>,
+    blocks: Blocks,
+    /// The offset of the block being written, `None` before the first record.
+    block_start: Option<u64>,
+    footer: Footer,
+    path: PathBuf,
+    seq_buf: String,
+    values: Option<ValueColumn>,
+    /// The last key written, to check the order.
+    last_key: Option<Vec<u8>>,
+}
+
+impl SegmentWriter {
+    pub(crate) fn create<P: AsRef<Path>>(
+        path: &P,
+        format: SegmentFormat,
+        blocks: Blocks,
+    ) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = format::writer(CountingWriter {
+            inner: BufWriter::new(file),
+            written: 0,
+            padding: Cell::new(0),
+        });
+        let values = match format {
+            SegmentFormat::Rows => None,
+            SegmentFormat::Columns => {
+                writer.write_record([COLUMNS_MAGIC])?;
+                Some(ValueColumn::create(path.as_ref())?)
+            }
+        };
+        Ok(Self {
+            writer,
+            blocks,
+            block_start: None,
+            footer: Footer::default(),
+            path: path.as_ref().to_owned(),
+            seq_buf: String::new(),
+            values,
+            last_key: None,
+        })
+    }
+
+    /// Record that the data of the log with the given id is in the segment.
+    pub(crate) fn log_id(&mut self, log_id: u64) {
+        self.footer.log_id = self.footer.log_id.max(log_id);
+    }
+
+    /// Record that the segment replaces all the segments with ids less than `id`.
+    pub(crate) fn replaces(&mut self, id: u64) {
+        self.footer.replaces = self.footer.replaces.max(id);
+    }
+
+    /// Carry over what the footer of a segment rewritten into this one records beyond
+    /// the statistics of its records.
+    pub(crate) fn inherit(&mut self, footer: Footer) {
+        self.log_id(footer.log_id);
+        self.replaces(footer.replaces);
+    }
+
+    /// Write an entry, or a tombstone if `value` is `None`. The keys must be written in
+    /// order without duplicates, or the write fails with [`MapError::UnorderedKeys`]
+    /// and the segment must be discarded, as it does with [`MapError::KeyTooLarge`].
+    pub(crate) fn write(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        seq: u64,
+    ) -> Result<(), std::io::Error> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                MapError::UnorderedKeys(self.path.clone()),
+            ));
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                MapError::KeyTooLarge(key.len()),
+            ));
+        }
+        let last_key = self.last_key.get_or_insert_with(Vec::new);
+        last_key.clear();
+        last_key.extend_from_slice(key);
+        self.align_block()?;
+        self.seq_buf.clear();
+        let _ = write!(self.seq_buf, "{}", seq);
+        let seq_end = self.seq_buf.len();
+        match (value, &mut self.values) {
+            (Some(value), None) => {
+                self.writer
+                    .write_record([key, value, self.seq_buf.as_bytes()])?;
+            }
+            (None, None) => {
+                self.writer
+                    .write_record([key, b"", TOMBSTONE_TAG, self.seq_buf.as_bytes()])?;
+            }
+            (Some(value), Some(values)) => {
+                let _ = write!(self.seq_buf, "{}", values.len);
+                let offset_end = self.seq_buf.len();
+                let _ = write!(self.seq_buf, "{}", value.len());
+                let fields = self.seq_buf.as_bytes();
+                self.writer.write_record([
+                    key,
+                    &fields[..seq_end],
+                    &fields[seq_end..offset_end],
+                    &fields[offset_end..],
+                ])?;
+                values.write(value)?;
+            }
+            (None, Some(_)) => self.writer.write_record([key, self.seq_buf.as_bytes()])?,
+        }
+        self.footer.add(key, value.map(<[u8]>::len), seq);
+        Ok(())
+    }
+
+    /// Pad the file up to the alignment if the next record starts a block, where it is
+    /// found to start by [`Segment::initialize_index`] with the same block size.
+    fn align_block(&mut self) -> Result<(), std::io::Error> {
+        let alignment = match self.blocks.alignment {
+            Some(alignment) => alignment,
+            None => return Ok(()),
+        };
+        // The records are only counted once the csv writer hands them over.
+        self.writer.flush()?;
+        let offset = self.writer.get_ref().written;
+        if self
+            .block_start
+            .is_some_and(|start| offset - start < self.blocks.size)
+        {
+            return Ok(());
+        }
+        let aligned = offset.next_multiple_of(alignment);
+        self.writer.get_ref().padding.set(aligned - offset);
+        self.block_start = Some(aligned);
+        Ok(())
+    }
+
+    /// Write the footer and flush the file.
+    pub(crate) fn finish(mut self) -> Result<Segment, std::io::Error> {
+        self.footer.created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.writer.write_byte_record(&self.footer.to_record())?;
+        self.writer.flush()?;
+        let mut segment = Segment::from_path(&self.path);
+        let counting = self.writer.into_inner().map_err(|err| err.into_error())?;
+        let mut file = counting.inner;
+        if let Some(values) = self.values.take() {
+            segment.layout = Layout::Columns {
+                values: counting.written,
+            };
+            values.copy_to(&mut file)?;
+        }
+        file.flush()?;
+        // The segment is renamed into place next, which must not be seen after a crash
+        // before the bytes themselves.
+        file.get_ref().sync_all()?;
+        segment.footer = self.footer;
+        Ok(segment)
+    }
+}
+
+/// The value column of a columnar segment being written, in a file next to the segment
+/// named `<stem>.values.<extension>`, so the leftover of a stopped write is removed on
+/// open like the segment itself. The file is removed once dropped.
+struct ValueColumn {
+    file: BufWriter<File>,
+    path: PathBuf,
+    len: u64,
+}
+
+impl ValueColumn {
+    fn create(segment_path: &Path) -> Result<Self, std::io::Error> {
+        let mut name = segment_path.file_stem().unwrap_or_default().to_owned();
+        name.push(".values");
+        if let Some(extension) = segment_path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let path = segment_path.with_file_name(name);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            path,
+            len: 0,
+        })
+    }
+
+    fn write(&mut self, value: &[u8]) -> Result<(), std::io::Error> {
+        self.file.write_all(value)?;
+        self.len += value.len() as u64;
+        Ok(())
+    }
+
+    /// Append all the values to `out`.
+    fn copy_to<W: Write>(mut self, out: &mut W) -> Result<(), std::io::Error> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut file.take(self.len), out)?;
+        Ok(())
+    }
+}
+
+impl Drop for ValueColumn {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!("failed to remove {:?}: err={}", self.path, err);
+        }
+    }
+}
+
+/// The key, the value and the sequence number of an entry record.
+type RecordEntry<'a> = (&'a [u8], Option<&'a [u8]>, u64);
+
+/// Read an entry record, with `None` as the value of a tombstone.
+///
+/// An entry is `[key, value, seq]` and a tombstone is `[key, "", TOMBSTONE_TAG, seq]`,
+/// so an empty value is never confused with a tombstone. Records written before sequence
+/// numbers existed lack the last field, and are taken as the oldest writes.
+pub(crate) fn record_to_entry(record: &ByteRecord) -> Option<RecordEntry<'_>> {
+    let seq = |idx| std::str::from_utf8(record.get(idx)?).ok()?.parse().ok();
+    let is_tombstone =
+        record.len() >= 3 && record.get(1)? == b"" && record.get(2)? == TOMBSTONE_TAG;
+    match record.len() {
+        2 => Some((record.get(0)?, record.get(1), 0)),
+        3 if is_tombstone => Some((record.get(0)?, None, 0)),
+        3 => Some((record.get(0)?, record.get(1), seq(2)?)),
+        4 if is_tombstone => Some((record.get(0)?, None, seq(3)?)),
+        _ => None,
     }
 }
 
-impl From<Arc<Tree>> for RawSegment {
-    fn from(freeze: Arc<Tree>) -> Self {
-        Self { freeze }
+/// The value of an entry record, which is in the value column of a columnar segment.
+#[derive(Debug, Clone, Copy)]
+enum RecordValue<'a> {
+    Inline(&'a [u8]),
+    Column { offset: u64, len: u64 },
+}
+
+impl RecordValue<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Inline(value) => value.len(),
+            Self::Column { len, .. } => *len as usize,
+        }
+    }
+}
+
+/// Read an entry record of a columnar segment, see [`SegmentWriter`].
+fn column_record_to_entry(record: &ByteRecord) -> Option<(&[u8], Option<RecordValue<'_>>, u64)> {
+    let num = |idx| std::str::from_utf8(record.get(idx)?).ok()?.parse().ok();
+    match record.len() {
+        2 => Some((record.get(0)?, None, num(1)?)),
+        4 => Some((
+            record.get(0)?,
+            Some(RecordValue::Column {
+                offset: num(2)?,
+                len: num(3)?,
+            }),
+            num(1)?,
+        )),
+        _ => None,
     }
 }
 
-pub(crate) fn record_to_kv(record: &ByteRecord) -> Option<(&[u8], Bytes)> {
-    let key = record.get(0)?;
-    let value = Bytes::copy_from_slice(record.get(1)?);
-    Some((key, value))
+fn is_columns_header(record: &ByteRecord) -> bool {
+    record.len() == 1 && record.get(0) == Some(COLUMNS_MAGIC)
 }
 
-pub(crate) fn record_to_key(record: &ByteRecord) -> Option<Bytes> {
-    let key = record.get(0)?;
-    Some(Bytes::copy_from_slice(key))
+/// The value of an entry record.
+pub(crate) fn record_value(record: &ByteRecord) -> &[u8] {
+    record.get(1).unwrap_or_default()
 }
 
 /// Segment.
 #[derive(Debug)]
 pub struct Segment {
-    index: Option<Vec<(Bytes, u64)>>,
+    /// The key and the offset of the first record of each block. The record starting at
+    /// an offset has the key it is indexed by, so a key in the segment is found by
+    /// scanning from the last indexed key not greater than it. `None` if the records are
+    /// malformed or out of order, or if the index is kept by `index_cache`.
+    index: Option<Arc<BlockIndex>>,
+    /// Whether the records are indexed, see [`Segment::is_indexed`].
+    indexed: bool,
+    /// The cache keeping the index instead, with the id the index is kept by.
+    index_cache: Option<(Arc<IndexCache>, u64)>,
+    /// The blocks the index is built with, to build it again once evicted.
+    blocks: Blocks,
+    footer: Footer,
+    /// The smallest and the largest key of the records, `None` if there is none.
+    key_range: Option<(Bytes, Bytes)>,
+    layout: Layout,
+    path: PathBuf,
+    /// Dropped before `packed`, so a pack is never removed while mapped.
+    #[cfg(feature = "mmap")]
+    mapped: Option<Mapped>,
+    packed: Option<Packed>,
+    obsolete: AtomicBool,
+    read_buffer_size: usize,
+}
+
+/// What [`Segment::scan`] finds in the records of a segment.
+struct Scan {
+    index: Option<BlockIndex>,
+    footer: Footer,
+    key_range: Option<(Bytes, Bytes)>,
+    layout: Layout,
+}
+
+/// How the records of a segment are grouped into the blocks of its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Blocks {
+    /// The size a block grows to before the next record starts a new one.
+    pub(crate) size: u64,
+    /// The alignment of the offsets of the blocks of new segments.
+    pub(crate) alignment: Option<u64>,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_BLOCK_SIZE,
+            alignment: None,
+        }
+    }
+}
+
+/// A writer counting the bytes written to it.
+///
+/// Flushing does not flush `inner`, so the csv writer can be flushed before each record
+/// to know where the record starts. The blank lines set by `padding` are written right
+/// before the bytes written next.
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+    padding: Cell<u64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut padding = self.padding.take();
+        while padding > 0 {
+            let len = padding.min(BLANK_LINES.len() as u64);
+            self.inner.write_all(&BLANK_LINES[..len as usize])?;
+            self.written += len;
+            padding -= len;
+        }
+        let len = self.inner.write(buf)?;
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+const BLANK_LINES: [u8; 64] = [b'\n'; 64];
+
+/// How the segment files are read.
+#[derive(Debug, Clone)]
+pub(crate) struct ReadOptions {
+    /// The size of the buffer the file is read through.
+    pub(crate) buffer_size: usize,
+    /// Whether the file is memory-mapped.
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap: bool,
+    /// The cache keeping the indices of the segments, if their number is bounded.
+    pub(crate) index_cache: Option<Arc<IndexCache>>,
+}
+
+/// A memory-mapped segment file, kept by the readers reading it, so it is only unmapped
+/// once all of them are dropped.
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone)]
+pub(crate) struct Mapped(Arc<memmap2::Mmap>);
+
+#[cfg(feature = "mmap")]
+impl Mapped {
+    fn new(path: &Path) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+        // SAFETY: A segment file is never written once it has a name the database reads,
+        // and a removed file is kept by the OS until it is unmapped. Only a change by
+        // another process can break the mapping.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Self(Arc::new(mmap)))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for Mapped {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Where the bytes of a segment file are read from.
+#[derive(Debug, Clone)]
+enum FileSource {
+    Path(PathBuf),
+    #[cfg(feature = "mmap")]
+    Mapped(Mapped),
+}
+
+impl FileSource {
+    fn open(&self) -> Result<SegmentFile, std::io::Error> {
+        match self {
+            Self::Path(path) => Ok(SegmentFile::File(File::open(path)?)),
+            #[cfg(feature = "mmap")]
+            Self::Mapped(mapped) => Ok(SegmentFile::Mapped(std::io::Cursor::new(mapped.clone()))),
+        }
+    }
+}
+
+/// An opened segment file.
+pub(crate) enum SegmentFile {
+    File(File),
+    #[cfg(feature = "mmap")]
+    Mapped(std::io::Cursor<Mapped>),
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.read(buf),
+            #[cfg(feature = "mmap")]
+            Self::Mapped(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            #[cfg(feature = "mmap")]
+            Self::Mapped(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+/// Where the values of a segment are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Layout {
+    /// In the entry records.
+    #[default]
+    Rows,
+    /// In the value column starting at the offset `values`, right after the footer.
+    Columns { values: u64 },
+}
+
+/// Where a segment is in a pack.
+#[derive(Debug)]
+struct Packed {
+    pack: Arc<Pack>,
+    offset: u64,
+    len: u64,
+}
+
+/// A file holding several segments, which is removed once all of them are obsolete and
+/// dropped.
+#[derive(Debug)]
+struct Pack {
     path: PathBuf,
+    live: AtomicUsize,
+}
+
+impl Drop for Pack {
+    fn drop(&mut self) {
+        if self.live.load(Ordering::SeqCst) == 0 {
+            match std::fs::remove_file(&self.path) {
+                Ok(()) => tracing::info!("removed the obsolete pack file {:?}", self.path),
+                Err(err) => tracing::error!(
+                    "failed to remove the obsolete pack file {:?}, err={}",
+                    self.path,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// The first field of the directory record of a pack.
+const PACK_MAGIC: &[u8] = b"\0pack";
+
+/// Write the segments one after another to a pack file at `tmp_path` and move it to
+/// `path`, returning the segments as they are in the pack.
+///
+/// The pack starts with a directory record `[PACK_MAGIC, (id, len)*]`, followed by the
+/// bytes of the segments in the order of the directory. The offsets in the indices are
+/// relative to the start of a segment, so they are kept as they are.
+pub(crate) fn write_pack<P: AsRef<Path>>(
+    segments: &[(u64, &Segment)],
+    tmp_path: &P,
+    path: &P,
+) -> Result<Vec<(u64, Segment)>, std::io::Error> {
+    let mut lens = Vec::with_capacity(segments.len());
+    for (_, segment) in segments {
+        lens.push(segment.size()?);
+    }
+    let mut fields = vec![PACK_MAGIC.to_vec()];
+    for ((id, _), len) in segments.iter().zip(lens.iter()) {
+        fields.push(id.to_string().into_bytes());
+        fields.push(len.to_string().into_bytes());
+    }
+    let mut directory = Vec::new();
+    format::encode_record(&mut directory, &fields)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(tmp_path)?;
+    file.write_all(&directory)?;
+    for (_, segment) in segments {
+        std::io::copy(&mut segment.open(0)?, &mut file)?;
+    }
+    file.flush()?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    sync_parent_dir(path.as_ref())?;
+    let pack = Arc::new(Pack {
+        path: path.as_ref().to_owned(),
+        live: AtomicUsize::new(segments.len()),
+    });
+    // The pack is mapped once for all its segments, if they were mapped.
+    #[cfg(feature = "mmap")]
+    let mapped = match segments.iter().any(|(_, segment)| segment.mapped.is_some()) {
+        true => Some(Mapped::new(path.as_ref())?),
+        false => None,
+    };
+    let mut offset = directory.len() as u64;
+    let mut packed = Vec::with_capacity(segments.len());
+    for ((id, segment), len) in segments.iter().zip(lens) {
+        packed.push((
+            *id,
+            Segment {
+                index: segment.index(),
+                indexed: segment.indexed,
+                index_cache: None,
+                blocks: segment.blocks,
+                footer: segment.footer,
+                key_range: segment.key_range.clone(),
+                layout: segment.layout,
+                path: pack.path.clone(),
+                #[cfg(feature = "mmap")]
+                mapped: mapped.clone(),
+                packed: Some(Packed {
+                    pack: pack.clone(),
+                    offset,
+                    len,
+                }),
+                obsolete: AtomicBool::new(false),
+                read_buffer_size: segment.read_buffer_size,
+            },
+        ));
+        offset += len;
+    }
+    // The indices of the packed segments are kept by the same cache, under new ids.
+    for ((_, segment), (_, copy)) in segments.iter().zip(packed.iter_mut()) {
+        if let Some((cache, _)) = &segment.index_cache {
+            copy.index_cache = Some((cache.clone(), cache.register()));
+            let index = copy.index.take();
+            copy.set_index(index);
+        }
+    }
+    Ok(packed)
+}
+
+/// Open the segments in the pack file at `path`, without their indices.
+pub(crate) fn open_pack<P: AsRef<Path>>(path: &P) -> Result<Vec<(u64, Segment)>, std::io::Error> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid pack directory in {:?}", path.as_ref()),
+        )
+    };
+    let mut reader = format::reader(File::open(path)?);
+    let mut record = ByteRecord::new();
+    if !reader.read_byte_record(&mut record)? || record.get(0) != Some(PACK_MAGIC) {
+        return Err(invalid());
+    }
+    let mut offset = reader.position().byte();
+    let fields = record
+        .iter()
+        .skip(1)
+        .map(|field| std::str::from_utf8(field).ok()?.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()
+        .filter(|fields| fields.len().is_multiple_of(2))
+        .ok_or_else(invalid)?;
+    let pack = Arc::new(Pack {
+        path: path.as_ref().to_owned(),
+        live: AtomicUsize::new(fields.len() / 2),
+    });
+    let mut segments = Vec::with_capacity(fields.len() / 2);
+    for pair in fields.chunks(2) {
+        let (id, len) = (pair[0], pair[1]);
+        let mut segment = Segment::from_path(path);
+        segment.packed = Some(Packed {
+            pack: pack.clone(),
+            offset,
+            len,
+        });
+        segments.push((id, segment));
+        offset += len;
+    }
+    Ok(segments)
 }
 
 impl Segment {
@@ -60,92 +843,1006 @@ impl Segment {
         Self {
             path: path.as_ref().to_owned(),
             index: None,
+            indexed: false,
+            index_cache: None,
+            blocks: Blocks::default(),
+            footer: Footer::default(),
+            key_range: None,
+            layout: Layout::Rows,
+            #[cfg(feature = "mmap")]
+            mapped: None,
+            packed: None,
+            obsolete: AtomicBool::new(false),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+        }
+    }
+
+    /// Set how the file is read, mapping it if `options.mmap`. The file must be fully
+    /// written.
+    pub(crate) fn set_read_options(&mut self, options: &ReadOptions) -> Result<(), std::io::Error> {
+        self.read_buffer_size = options.buffer_size;
+        // The id is kept for the same cache, so is its cached index, and removed from
+        // another one, which would keep the index until evicted otherwise.
+        self.index_cache = match (self.index_cache.take(), options.index_cache.clone()) {
+            (Some((old, id)), Some(cache)) if Arc::ptr_eq(&old, &cache) => Some((cache, id)),
+            (registered, cache) => {
+                if let Some((old, id)) = registered {
+                    old.remove(id);
+                }
+                cache.map(|cache| {
+                    let id = cache.register();
+                    (cache, id)
+                })
+            }
+        };
+        #[cfg(feature = "mmap")]
+        if options.mmap && self.mapped.is_none() {
+            self.mapped = Some(Mapped::new(&self.path)?);
+        }
+        Ok(())
+    }
+
+    fn source(&self) -> FileSource {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &self.mapped {
+            return FileSource::Mapped(mapped.clone());
+        }
+        FileSource::Path(self.path.clone())
+    }
+
+    /// Whether the index is built, which it is not when a record is malformed or out of
+    /// order.
+    pub(crate) fn is_indexed(&self) -> bool {
+        self.indexed
+    }
+
+    /// The index, built again from the records if evicted from the cache.
+    fn index(&self) -> Option<Arc<BlockIndex>> {
+        let (cache, id) = match &self.index_cache {
+            Some(_) if !self.indexed => return None,
+            Some((cache, id)) => (cache, *id),
+            None => return self.index.clone(),
+        };
+        if let Some(index) = cache.get(id) {
+            return Some(index);
+        }
+        tracing::debug!("loading the evicted index of segment {:?}", self.path);
+        match self.scan(self.blocks) {
+            Ok(scan) => {
+                let index = Arc::new(scan.index?);
+                cache.insert(id, index.clone());
+                Some(index)
+            }
+            Err(err) => {
+                // Lookups fall back to scanning the whole segment.
+                tracing::warn!(
+                    "failed to load the index of segment {:?}, err={}",
+                    self.path,
+                    err
+                );
+                None
+            }
         }
     }
 
-    pub(crate) fn initialize_index(&mut self, block_size: u64) -> Result<(), std::io::Error> {
+    /// Keep the index, in the cache if there is one.
+    fn set_index(&mut self, index: Option<Arc<BlockIndex>>) {
+        self.indexed = index.is_some();
+        self.index = match (&self.index_cache, index) {
+            (Some((cache, id)), Some(index)) => {
+                cache.insert(*id, index);
+                None
+            }
+            (_, index) => index,
+        };
+    }
+
+    /// Whether the segment is in a pack.
+    pub(crate) fn is_packed(&self) -> bool {
+        self.packed.is_some()
+    }
+
+    /// A copy of the segment reading the same bytes, with its index and footer built anew
+    /// from the records.
+    pub(crate) fn reindexed(&self, blocks: Blocks) -> Result<Segment, std::io::Error> {
+        let mut segment = Segment {
+            index: None,
+            indexed: false,
+            index_cache: self
+                .index_cache
+                .as_ref()
+                .map(|(cache, _)| (cache.clone(), cache.register())),
+            blocks,
+            footer: Footer::default(),
+            key_range: None,
+            layout: Layout::Rows,
+            path: self.path.clone(),
+            #[cfg(feature = "mmap")]
+            mapped: self.mapped.clone(),
+            packed: self.packed.as_ref().map(|packed| Packed {
+                pack: packed.pack.clone(),
+                offset: packed.offset,
+                len: packed.len,
+            }),
+            obsolete: AtomicBool::new(false),
+            read_buffer_size: self.read_buffer_size,
+        };
+        segment.initialize_index(blocks)?;
+        Ok(segment)
+    }
+
+    pub(crate) fn initialize_index(&mut self, blocks: Blocks) -> Result<(), std::io::Error> {
+        let scan = self.scan(blocks)?;
+        self.layout = scan.layout;
+        self.key_range = scan.key_range;
+        self.footer = scan.footer;
+        self.blocks = blocks;
+        self.set_index(scan.index.map(Arc::new));
+        tracing::debug!("index={:?}", self.index());
+        Ok(())
+    }
+
+    /// Read all the records, building the index and checking the footer against them.
+    fn scan(&self, blocks: Blocks) -> Result<Scan, std::io::Error> {
         let mut record = ByteRecord::new();
         let mut reader = self.to_reader()?;
-        let mut index = Vec::new();
+        let mut index = BlockIndex::default();
         let mut last_block_offset = 0;
+        let mut scanned = Footer::default();
+        let mut footer = None;
+        let mut columnar = false;
+        let mut values = None;
+        let mut malformed = false;
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut key_range: Option<(Vec<u8>, Vec<u8>)> = None;
+        let mut unordered = false;
+        let mut padding = None;
         loop {
+            // Taken before the record is read, so it is where the record starts, whatever
+            // the size of the record and of the blocks.
             let offset = reader.position().byte();
             tracing::debug!("offset: {}", offset);
-            let more = reader.read_byte_record(&mut record)?;
-            if offset == 0 || offset - last_block_offset >= block_size {
-                last_block_offset = offset;
-                if let Some(key) = record_to_key(&record) {
-                    tracing::debug!("key: {:?}", key);
-                    index.push((key, offset));
-                } else {
-                    tracing::debug!("not key in this record");
+            if !reader.read_byte_record(&mut record)? {
+                break;
+            }
+            if offset == 0 && is_columns_header(&record) {
+                columnar = true;
+                continue;
+            }
+            if footer.is_none() {
+                footer = Footer::from_record(&record);
+                if footer.is_some() {
+                    if columnar {
+                        // The value column follows, which is not made of records.
+                        values = Some(reader.position().byte());
+                        break;
+                    }
+                    continue;
                 }
             }
-            if !more {
+            let entry = if columnar {
+                column_record_to_entry(&record)
+            } else {
+                record_to_entry(&record)
+                    .map(|(key, value, seq)| (key, value.map(RecordValue::Inline), seq))
+            };
+            if footer.is_some() || entry.is_none() {
+                // The blocks can no longer be trusted to start where the index says,
+                // so lookups fall back to scanning the whole segment.
+                tracing::warn!(
+                    "malformed record at offset {} of segment {:?}, the index is dropped",
+                    offset,
+                    self.path
+                );
+                malformed = true;
+                if columnar {
+                    // The footer still has to be found to know where the values are.
+                    continue;
+                }
                 break;
             }
+            if let Some((key, value, seq)) = entry {
+                scanned.add(key, value.map(|value| value.len()), seq);
+                if !unordered && last_key.as_deref().is_some_and(|last| last >= key) {
+                    // A binary search of the index would miss keys.
+                    tracing::warn!(
+                        "key out of order at offset {} of segment {:?}, the index is dropped",
+                        offset,
+                        self.path
+                    );
+                    unordered = true;
+                }
+                let last_key = last_key.get_or_insert_with(Vec::new);
+                last_key.clear();
+                last_key.extend_from_slice(key);
+                match &mut key_range {
+                    Some((min, max)) => {
+                        if key < min.as_slice() {
+                            *min = key.to_vec();
+                        } else if key > max.as_slice() {
+                            max.clear();
+                            max.extend_from_slice(key);
+                        }
+                    }
+                    None => key_range = Some((key.to_vec(), key.to_vec())),
+                }
+            }
+            // A record larger than a block starts a block of its own, so with tiny blocks
+            // every record is indexed.
+            if index.is_empty() || offset - last_block_offset >= blocks.size {
+                // The record starts after the padding of an aligned block.
+                let offset = match blocks.alignment {
+                    Some(alignment) => self.skip_padding(&mut padding, offset, alignment)?,
+                    None => offset,
+                };
+                last_block_offset = offset;
+                if let Some(key) = record.get(0) {
+                    tracing::debug!("key: {:?}", Bytes::copy_from_slice(key));
+                    index.push(key, offset);
+                }
+            }
+        }
+        let layout = match (columnar, values) {
+            (false, _) => Layout::Rows,
+            (true, Some(values)) => Layout::Columns { values },
+            (true, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("the footer of columnar segment {:?} is missing", self.path),
+                ))
+            }
+        };
+        let key_range = key_range.map(|(min, max)| (Bytes::from(min), Bytes::from(max)));
+        if malformed {
+            return Ok(Scan {
+                index: None,
+                footer: scanned,
+                key_range,
+                layout,
+            });
+        }
+        // The log id, the creation time and the replaced ids are not statistics of the
+        // records, so they can only come from the footer.
+        scanned.log_id = footer.map(|footer| footer.log_id).unwrap_or_default();
+        scanned.created_at = footer.map(|footer| footer.created_at).unwrap_or_default();
+        scanned.replaces = footer.map(|footer| footer.replaces).unwrap_or_default();
+        let footer = match footer {
+            Some(footer) if footer != scanned => {
+                tracing::warn!(
+                    "the footer of segment {:?} does not match its records",
+                    self.path
+                );
+                scanned
+            }
+            Some(footer) => footer,
+            None => scanned,
+        };
+        Ok(Scan {
+            index: (!unordered).then_some(index),
+            footer,
+            key_range,
+            layout,
+        })
+    }
+
+    /// The offset of the next multiple of `alignment` if the bytes up to it from `offset`
+    /// are the blank lines padding a block, or `offset` if they are not. The bytes are
+    /// read through `file`, which is opened at the first call.
+    fn skip_padding(
+        &self,
+        file: &mut Option<SegmentFile>,
+        offset: u64,
+        alignment: u64,
+    ) -> Result<u64, std::io::Error> {
+        let aligned = offset.next_multiple_of(alignment);
+        if aligned == offset {
+            return Ok(offset);
+        }
+        let file = match file {
+            Some(file) => file,
+            None => file.insert(self.source().open()?),
+        };
+        let base = self.packed.as_ref().map_or(0, |packed| packed.offset);
+        file.seek(SeekFrom::Start(base + offset))?;
+        let mut padding = vec![0; (aligned - offset) as usize];
+        match file.read_exact(&mut padding) {
+            Ok(()) if padding.iter().all(|byte| *byte == b'\n') => Ok(aligned),
+            Ok(()) => Ok(offset),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(offset),
+            Err(err) => Err(err),
         }
-        self.index = Some(index);
-        tracing::debug!("index={:?}", self.index);
-        Ok(())
     }
 
     pub(crate) fn move_to<P: AsRef<Path>>(&mut self, path: &P) -> Result<(), std::io::Error> {
         std::fs::rename(&self.path, path)?;
+        sync_parent_dir(path.as_ref())?;
         self.path = path.as_ref().to_owned();
         Ok(())
     }
 
-    pub(crate) fn to_reader(&self) -> Result<Reader<File>, std::io::Error> {
-        Ok(ReaderBuilder::new()
-            .has_headers(false)
-            .flexible(true)
-            .from_path(&self.path)?)
+    /// Open the bytes of the segment from the offset `start`.
+    pub(crate) fn open(&self, start: u64) -> Result<Take<SegmentFile>, std::io::Error> {
+        let mut file = self.source().open()?;
+        let (offset, len) = match &self.packed {
+            Some(packed) => (packed.offset, packed.len),
+            None => (0, u64::MAX),
+        };
+        file.seek(SeekFrom::Start(offset + start))?;
+        Ok(file.take(len.saturating_sub(start)))
+    }
+
+    pub(crate) fn to_reader(&self) -> Result<Reader<impl Read>, std::io::Error> {
+        Ok(format::buffered_reader(
+            self.open(0)?,
+            self.read_buffer_size,
+        ))
+    }
+
+    /// The reader of the records from the offset `start`, which end at the footer of a
+    /// columnar segment.
+    fn record_reader(&self, start: u64) -> Result<Reader<Take<Take<SegmentFile>>>, std::io::Error> {
+        let end = match self.layout {
+            Layout::Rows => u64::MAX,
+            Layout::Columns { values } => values,
+        };
+        Ok(format::buffered_reader(
+            self.open(start)?.take(end.saturating_sub(start)),
+            self.read_buffer_size,
+        ))
     }
 
+    /// The records from the offset `start`, see [`Segment::record_reader`].
     pub(crate) fn records(
         &self,
         start: u64,
     ) -> Result<impl Iterator<Item = Result<ByteRecord, std::io::Error>>, std::io::Error> {
-        let mut file = File::open(&self.path)?;
-        file.seek(SeekFrom::Start(start))?;
-        let reader = ReaderBuilder::new().has_headers(false).from_reader(file);
-        Ok(reader
+        Ok(self
+            .record_reader(start)?
             .into_byte_records()
             .map(|res| res.map_err(std::io::Error::from)))
     }
 
-    pub(crate) fn remove(self) -> Result<(), std::io::Error> {
-        std::fs::remove_file(&self.path)
+    /// The reader of the records from the offset `start` like [`Segment::records`], with
+    /// the entries of a columnar segment turned into the records of the row format.
+    ///
+    /// Unless `with_values`, the values are left empty, so the value column is not read.
+    fn rows(&self, start: u64, with_values: bool) -> Result<RowReader, std::io::Error> {
+        let columns = match self.layout {
+            Layout::Rows => None,
+            Layout::Columns { values } => Some(ColumnReader {
+                source: self.source(),
+                base: self.packed.as_ref().map_or(0, |packed| packed.offset) + values,
+                with_values,
+                buffer_size: self.read_buffer_size,
+                column: None,
+                value: Vec::new(),
+            }),
+        };
+        Ok(RowReader {
+            records: self.record_reader(start)?,
+            columns,
+            key_record: ByteRecord::new(),
+        })
     }
-}
 
-impl Get for Segment {
-    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<bytes::Bytes>>, MapError>
-    where
-        Q: ?Sized,
-        Q: AsRef<[u8]>,
+    /// Read a value out of the value column.
+    fn read_column(&self, offset: u64, len: u64) -> Result<Vec<u8>, std::io::Error> {
+        let values = match self.layout {
+            Layout::Rows => 0,
+            Layout::Columns { values } => values,
+        };
+        let mut value = vec![0; len as usize];
+        self.open(values + offset)?.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    /// The offset to start scanning from to find the keys in the `start` bound.
+    pub(crate) fn seek(&self, start: Bound<&[u8]>) -> u64 {
+        let key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => return 0,
+        };
+        self.index()
+            .and_then(|index| index.floor(key).map(|idx| index.offset(idx)))
+            .unwrap_or_default()
+    }
+
+    /// Read the block that may hold `key` and the `ahead` blocks after it, so the lookups
+    /// of the keys following it are served from the page cache of the OS.
+    ///
+    /// Without an index, which a malformed segment has, nothing is read.
+    pub(crate) fn prefetch(&self, key: &[u8], ahead: usize) -> Result<(), std::io::Error> {
+        let index = match self.index() {
+            Some(index) if !index.is_empty() => index,
+            _ => return Ok(()),
+        };
+        let idx = index.floor(key).unwrap_or_default();
+        let start = index.offset(idx);
+        let len = match idx + 1 + ahead {
+            end if end < index.len() => index.offset(end) - start,
+            _ => u64::MAX,
+        };
+        std::io::copy(&mut self.open(start)?.take(len), &mut std::io::sink())?;
+        Ok(())
+    }
+
+    /// Entries with keys in the given bounds, in key order.
+    ///
+    /// Segments are not supposed to have duplicated keys, but if one does, only the last
+    /// entry of the key is taken, as in [`Segment::lookup`]. The malformed records are
+    /// skipped or fail the iteration as `on_corrupt` says, with the error holding the
+    /// [`MapError::CorruptSegment`].
+    pub(crate) fn entries(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        on_corrupt: OnCorrupt,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Entry), std::io::Error>>, std::io::Error> {
+        self.entries_with(start, end, true, on_corrupt, |record, pool| {
+            Arc::new(pool.copy(record_value(record)))
+        })
+    }
+
+    /// Entries like [`Segment::entries`], with the values made from their records by `f`
+    /// instead of copied.
+    ///
+    /// The records are read into a single reused buffer, and the keys are copied out of
+    /// it with a [`BytesPool`], which `f` can use for the values as well. Unless
+    /// `with_values`, the records of a columnar segment have empty values, see
+    /// [`Segment::rows`].
+    pub(crate) fn entries_with<V>(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        with_values: bool,
+        on_corrupt: OnCorrupt,
+        f: impl Fn(&ByteRecord, &mut BytesPool) -> V,
+    ) -> Result<impl Iterator<Item = Result<(Bytes, Entry<V>), std::io::Error>>, std::io::Error>
     {
-        let offset = if let Some(index) = self.index.as_ref() {
-            match index.binary_search_by_key(&key.as_ref(), |(k, _)| k) {
-                Ok(idx) => index.get(idx).map(|(_, p)| *p),
-                Err(0) => None,
-                Err(idx) => index.get(idx - 1).map(|(_, p)| *p),
+        let owned = |bound: Bound<&[u8]>| bound.map(Bytes::copy_from_slice);
+        let (start_bound, end_bound) = (owned(start), owned(end));
+        let offset = self.seek(start);
+        let mut rows = self.rows(offset, with_values)?;
+        let mut record = ByteRecord::new();
+        let mut pool = BytesPool::default();
+        let entries = std::iter::from_fn(move || loop {
+            match rows.read(&mut record) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+            if let Some((key, value, seq)) = record_to_entry(&record) {
+                let key = pool.copy(key);
+                let value = value.is_some().then(|| f(&record, &mut pool));
+                return Some(Ok((key, Entry { seq, value })));
+            }
+            if let OnCorrupt::Fail { segment_id } = on_corrupt {
+                if Footer::from_record(&record).is_none() && !is_columns_header(&record) {
+                    let position = record.position().map_or(0, |position| position.byte());
+                    let err = MapError::CorruptSegment {
+                        segment_id,
+                        offset: offset + position,
+                    };
+                    return Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        err,
+                    )));
+                }
+            }
+        })
+        .skip_while(move |entry| match entry {
+            Ok((key, _)) => !after_start(key, start_bound.as_ref().map(|k| k.as_ref())),
+            Err(_) => false,
+        })
+        .take_while(move |entry| match entry {
+            Ok((key, _)) => before_end(key, end_bound.as_ref().map(|k| k.as_ref())),
+            Err(_) => true,
+        });
+        let mut entries = entries.peekable();
+        Ok(std::iter::from_fn(move || {
+            let mut entry = entries.next()?;
+            while let (Ok((key, _)), Some(Ok((next, _)))) = (&entry, entries.peek()) {
+                if key != next {
+                    break;
+                }
+                entry = entries.next()?;
+            }
+            Some(entry)
+        }))
+    }
+
+    /// Estimate the number of the live records with keys in `start..end` from the index,
+    /// taking the records to be spread evenly over the blocks. A segment without an index
+    /// counts as empty.
+    pub(crate) fn estimate_count(&self, start: &[u8], end: &[u8]) -> u64 {
+        let index = match self.index() {
+            Some(index) if !index.is_empty() => index,
+            _ => return 0,
+        };
+        let lower = index.partition_point(start);
+        let upper = index.partition_point(end);
+        let live = self.footer.record_count - self.footer.tombstone_count;
+        (upper.saturating_sub(lower) as u64).saturating_mul(live) / index.len() as u64
+    }
+
+    /// The smallest and the largest key of the records, including the tombstones.
+    pub(crate) fn key_range(&self) -> Option<(Bytes, Bytes)> {
+        self.key_range.clone()
+    }
+
+    /// The statistics of the segment.
+    pub(crate) fn footer(&self) -> Footer {
+        self.footer
+    }
+
+    /// The path of the segment file, or of the pack holding the segment.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the segment in bytes.
+    pub(crate) fn size(&self) -> Result<u64, std::io::Error> {
+        match &self.packed {
+            Some(packed) => Ok(packed.len),
+            None => Ok(std::fs::metadata(&self.path)?.len()),
+        }
+    }
+
+    /// Mark the segment obsolete, so its file is removed once the segment is dropped by
+    /// all the snapshots holding it. A pack is removed once all its segments are.
+    pub(crate) fn mark_obsolete(&self) {
+        if !self.obsolete.swap(true, Ordering::SeqCst) {
+            if let Some(packed) = &self.packed {
+                packed.pack.live.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if let Some((cache, id)) = &self.index_cache {
+            cache.remove(*id);
+        }
+        if self.obsolete.load(Ordering::SeqCst) && self.packed.is_none() {
+            // The file can only be removed on some systems once it is unmapped, which it
+            // is here unless a reader still has it.
+            #[cfg(feature = "mmap")]
+            drop(self.mapped.take());
+            match std::fs::remove_file(&self.path) {
+                Ok(()) => tracing::info!("removed the obsolete segment file {:?}", self.path),
+                Err(err) => tracing::error!(
+                    "failed to remove the obsolete segment file {:?}, err={}",
+                    self.path,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Reader of the records of a segment in the row format, see [`Segment::rows`].
+struct RowReader {
+    records: Reader<Take<Take<SegmentFile>>>,
+    columns: Option<ColumnReader>,
+    key_record: ByteRecord,
+}
+
+/// Reader of the value column of a columnar segment.
+struct ColumnReader {
+    source: FileSource,
+    /// The offset of the value column in the file.
+    base: u64,
+    with_values: bool,
+    buffer_size: usize,
+    /// The opened column with the offset it is at, relative to `base`.
+    column: Option<(BufReader<SegmentFile>, u64)>,
+    value: Vec<u8>,
+}
+
+impl RowReader {
+    /// Read the next record into `row`, returning whether there is one.
+    fn read(&mut self, row: &mut ByteRecord) -> Result<bool, std::io::Error> {
+        let columns = match &mut self.columns {
+            Some(columns) => columns,
+            None => return Ok(self.records.read_byte_record(row)?),
+        };
+        if !self.records.read_byte_record(&mut self.key_record)? {
+            return Ok(false);
+        }
+        let record = &self.key_record;
+        let (key, value) = match column_record_to_entry(record) {
+            Some((key, value, _)) => (key, value),
+            None => {
+                row.clone_from(record);
+                return Ok(true);
+            }
+        };
+        row.clear();
+        row.push_field(key);
+        match value {
+            Some(RecordValue::Column { offset, len }) => {
+                row.push_field(columns.read(offset, len)?);
             }
+            _ => {
+                row.push_field(b"");
+                row.push_field(TOMBSTONE_TAG);
+            }
+        }
+        row.push_field(record.get(1).unwrap_or_default());
+        Ok(true)
+    }
+}
+
+impl ColumnReader {
+    /// Read the value at `offset` of the column, or an empty one unless `with_values`.
+    ///
+    /// The values are read in order, so the column is only sought at the first value and
+    /// when a value is skipped.
+    fn read(&mut self, offset: u64, len: u64) -> Result<&[u8], std::io::Error> {
+        self.value.clear();
+        if !self.with_values {
+            return Ok(&self.value);
+        }
+        let (reader, position) = match &mut self.column {
+            Some(column) => column,
+            None => self.column.insert((
+                BufReader::with_capacity(self.buffer_size, self.source.open()?),
+                u64::MAX,
+            )),
+        };
+        if *position != offset {
+            reader.seek(SeekFrom::Start(self.base + offset))?;
+        }
+        self.value.resize(len as usize, 0);
+        reader.read_exact(&mut self.value)?;
+        *position = offset + len;
+        Ok(&self.value)
+    }
+}
+
+/// The size of the chunks of a [`BytesPool`].
+const POOL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Copies of byte slices sharing larger allocations, for the many small keys and values
+/// read out of a segment.
+///
+/// A chunk is freed once all the copies in it are dropped, so a single copy kept for long
+/// holds the whole chunk.
+#[derive(Debug, Default)]
+pub(crate) struct BytesPool {
+    buf: BytesMut,
+}
+
+impl BytesPool {
+    pub(crate) fn copy(&mut self, data: &[u8]) -> Bytes {
+        if self.buf.capacity() < data.len() {
+            self.buf = BytesMut::with_capacity(POOL_CHUNK_SIZE.max(data.len()));
+        }
+        self.buf.extend_from_slice(data);
+        self.buf.split().freeze()
+    }
+}
+
+/// Segments by id.
+pub(crate) type Segments = BTreeMap<u64, Arc<Segment>>;
+
+/// A set of segments published as immutable snapshots.
+///
+/// Readers only hold the lock to clone the current snapshot, and writers publish a new
+/// snapshot at once, so readers never wait for file IO of writers and always see a
+/// consistent set of segments.
+#[derive(Debug, Default)]
+pub(crate) struct SegmentSet {
+    current: RwLock<Arc<Segments>>,
+}
+
+impl SegmentSet {
+    pub(crate) fn new(segments: Segments) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(segments)),
+        }
+    }
+
+    /// The current snapshot.
+    pub(crate) fn snapshot(&self) -> Arc<Segments> {
+        // A snapshot is replaced as a whole, so it stays consistent even if a writer has
+        // panicked while holding the lock.
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Publish a new snapshot updated by `f`.
+    pub(crate) fn update<R>(&self, f: impl FnOnce(&mut Segments) -> R) -> R {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let mut segments = Segments::clone(&current);
+        let res = f(&mut segments);
+        *current = Arc::new(segments);
+        res
+    }
+}
+
+/// What a lookup does with the records of a segment it cannot read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OnCorrupt {
+    /// Skip them, so a corrupt entry of the key looks like a missing one.
+    Skip,
+    /// Fail with [`MapError::CorruptSegment`], reporting the id of the segment.
+    Fail { segment_id: u64 },
+}
+
+impl Segment {
+    /// Look up the key, with no value if the key is deleted in this segment.
+    ///
+    /// If the key appears more than once, which a segment written by this crate never
+    /// has, the last entry wins.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Option<Entry>, MapError> {
+        self.lookup_with(
+            key,
+            OnCorrupt::Skip,
+            |value| Arc::new(Bytes::copy_from_slice(value)),
+            &mut 0,
+        )
+    }
+
+    /// Look up the key like [`Segment::lookup`], mapping the value in place with `f`
+    /// instead of copying it, and adding the bytes of the records and the values read to
+    /// `bytes_read`.
+    pub(crate) fn lookup_with<V>(
+        &self,
+        key: &[u8],
+        on_corrupt: OnCorrupt,
+        f: impl Fn(&[u8]) -> V,
+        bytes_read: &mut u64,
+    ) -> Result<Option<Entry<V>>, MapError> {
+        self.lookup_record(key, on_corrupt, bytes_read, |value, bytes_read| {
+            Ok(match value {
+                RecordValue::Inline(value) => f(value),
+                RecordValue::Column { offset, len } => {
+                    *bytes_read += len;
+                    f(&self.read_column(offset, len)?)
+                }
+            })
+        })
+    }
+
+    /// Look up the length of the value of the key like [`Segment::lookup_with`], which
+    /// a columnar segment has in the record of the key, so its value column is not read.
+    pub(crate) fn lookup_len(
+        &self,
+        key: &[u8],
+        on_corrupt: OnCorrupt,
+        bytes_read: &mut u64,
+    ) -> Result<Option<Entry<usize>>, MapError> {
+        self.lookup_record(key, on_corrupt, bytes_read, |value, _| Ok(value.len()))
+    }
+
+    /// Look up the key, taking the value of the entry found from its record with `value`.
+    fn lookup_record<V>(
+        &self,
+        key: &[u8],
+        on_corrupt: OnCorrupt,
+        bytes_read: &mut u64,
+        value: impl Fn(RecordValue<'_>, &mut u64) -> Result<V, MapError>,
+    ) -> Result<Option<Entry<V>>, MapError> {
+        let index = self.index();
+        let offset = if let Some(index) = &index {
+            index.floor(key).map(|idx| index.offset(idx))
         } else {
             Some(0)
         };
+        let mut found = None;
         if let Some(offset) = offset {
             for record in self.records(offset)? {
-                if let Ok(record) = record {
-                    if let Some((k, v)) = record_to_kv(&record) {
-                        if k == key.as_ref() {
-                            return Ok(Some(Arc::new(v)));
-                        }
+                let record = match (record, on_corrupt) {
+                    (Ok(record), _) => record,
+                    (Err(_), OnCorrupt::Skip) => continue,
+                    (Err(err), OnCorrupt::Fail { .. }) => return Err(err.into()),
+                };
+                *bytes_read += record.as_slice().len() as u64;
+                let entry = match self.layout {
+                    Layout::Rows => record_to_entry(&record)
+                        .map(|(key, value, seq)| (key, value.map(RecordValue::Inline), seq)),
+                    Layout::Columns { .. } => column_record_to_entry(&record),
+                };
+                if let (None, OnCorrupt::Fail { segment_id }) = (entry, on_corrupt) {
+                    if Footer::from_record(&record).is_none() && !is_columns_header(&record) {
+                        let position = record.position().map_or(0, |position| position.byte());
+                        return Err(MapError::CorruptSegment {
+                            segment_id,
+                            offset: offset + position,
+                        });
+                    }
+                }
+                if let Some((k, record_value, seq)) = entry {
+                    if k == key {
+                        let record_value = match record_value {
+                            Some(record_value) => Some(value(record_value, bytes_read)?),
+                            None => None,
+                        };
+                        found = Some(Entry {
+                            seq,
+                            value: record_value,
+                        });
+                    } else if k > key && index.is_some() {
+                        // The keys are sorted, so no more entries of the key follow.
+                        break;
                     }
                 }
             }
         }
-        Ok(None)
+        Ok(found)
+    }
+}
+
+impl Get for Segment {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<bytes::Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        Ok(self.lookup(key.as_ref())?.and_then(|entry| entry.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The files of `dir` by name, with the footer of the segment `name` cut off as it
+    /// holds the creation time.
+    fn files(dir: &Path, name: &str) -> BTreeMap<String, Vec<u8>> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let name_of = entry.file_name().into_string().unwrap();
+                let mut data = std::fs::read(entry.path()).unwrap();
+                if name_of == name {
+                    let end = data[..data.len() - 1]
+                        .iter()
+                        .rposition(|byte| *byte == b'\n')
+                        .unwrap();
+                    data.truncate(end + 1);
+                }
+                (name_of, data)
+            })
+            .collect()
+    }
+
+    fn entries() -> impl Iterator<Item = (Bytes, Bytes)> {
+        (0..500).map(|i| {
+            (
+                Bytes::from(format!("key{:05}", i)),
+                Bytes::from(format!("value,\"{}\"\n", i)),
+            )
+        })
+    }
+
+    #[test]
+    fn a_segment_from_a_sorted_iter_is_the_one_from_a_tree() {
+        let root = std::env::temp_dir().join(format!("nouzdb-sorted-{}", std::process::id()));
+        let blocks = Blocks {
+            size: 256,
+            alignment: None,
+        };
+        for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+            let tree: Tree = entries()
+                .map(|(key, value)| {
+                    let entry = Entry {
+                        seq: 7,
+                        value: Some(Arc::new(value)),
+                    };
+                    (key, entry)
+                })
+                .collect();
+            let mut footers = Vec::new();
+            let mut contents = Vec::new();
+            for (name, raw) in [
+                ("tree", RawSegment::new(0, Arc::new(tree))),
+                ("sorted", RawSegment::from_sorted_iter(7, entries())),
+            ] {
+                let dir = root.join(name);
+                let _ = std::fs::remove_dir_all(&dir);
+                std::fs::create_dir_all(&dir).unwrap();
+                let segment = raw
+                    .write_to_path(&dir.join("1.data"), format, blocks)
+                    .unwrap();
+                let mut footer = segment.footer();
+                footer.created_at = 0;
+                footers.push(footer);
+                contents.push(files(&dir, "1.data"));
+            }
+            assert_eq!(footers[0], footers[1]);
+            assert_eq!(footers[0].record_count, 500);
+            assert_eq!(contents[0], contents[1]);
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn a_sorted_iter_out_of_order_fails_to_write() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-unsorted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for keys in [["b", "a"], ["a", "a"]] {
+            let iter = keys.map(|key| (Bytes::from(key), Bytes::from("value")));
+            let err = RawSegment::from_sorted_iter(1, iter)
+                .write_to_path(&dir.join("1.data"), SegmentFormat::Rows, Blocks::default())
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(matches!(
+                err.into_inner().unwrap().downcast::<MapError>().as_deref(),
+                Ok(MapError::UnorderedKeys(_))
+            ));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn aligned_blocks_start_at_multiples_of_the_alignment() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-aligned-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let blocks = Blocks {
+            size: 200,
+            alignment: Some(512),
+        };
+        for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+            let path = dir.join("1.data");
+            RawSegment::from_sorted_iter(1, entries())
+                .write_to_path(&path, format, blocks)
+                .unwrap();
+            let mut segment = Segment::from_path(&path);
+            segment.initialize_index(blocks).unwrap();
+            let index = segment.index.as_ref().unwrap();
+            assert!(index.len() > 10, "{} blocks", index.len());
+            for idx in 0..index.len() {
+                assert_eq!(index.offset(idx) % 512, 0, "block {}", idx);
+            }
+            for (key, value) in entries() {
+                assert_eq!(segment.get(&key).unwrap().as_deref(), Some(&value));
+            }
+            assert_eq!(segment.get(b"key00000a".as_slice()).unwrap(), None);
+            assert_eq!(segment.footer().record_count, 500);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn setting_the_read_options_again_keeps_a_single_cache_id() {
+        let dir = std::env::temp_dir().join(format!("nouzdb-cache-id-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.data");
+        RawSegment::from_sorted_iter(1, entries())
+            .write_to_path(&path, SegmentFormat::Rows, Blocks::default())
+            .unwrap();
+        let cache = Arc::new(IndexCache::new(4));
+        let mut options = crate::DatabaseBuilder::default().read_options();
+        options.index_cache = Some(cache.clone());
+        let mut segment = Segment::from_path(&path);
+        segment.set_read_options(&options).unwrap();
+        segment.initialize_index(Blocks::default()).unwrap();
+        let id = segment.index_cache.as_ref().unwrap().1;
+        assert!(cache.get(id).is_some());
+        segment.set_read_options(&options).unwrap();
+        assert_eq!(segment.index_cache.as_ref().unwrap().1, id);
+        assert!(cache.get(id).is_some());
+
+        let other = Arc::new(IndexCache::new(4));
+        options.index_cache = Some(other.clone());
+        segment.set_read_options(&options).unwrap();
+        assert!(cache.get(id).is_none());
+        assert!(Arc::ptr_eq(
+            &segment.index_cache.as_ref().unwrap().0,
+            &other
+        ));
+        options.index_cache = None;
+        segment.set_read_options(&options).unwrap();
+        assert!(segment.index_cache.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -0,0 +1,176 @@
+//! Statistics of the database.
+
+use crate::database::BackgroundTask;
+use bytes::Bytes;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Information of a segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Segment id.
+    pub id: u64,
+    /// Path of the segment file.
+    pub path: PathBuf,
+    /// Size of the segment file in bytes.
+    pub file_size: u64,
+    /// Number of records.
+    pub record_count: u64,
+    /// Total bytes of the keys.
+    pub key_bytes: u64,
+    /// Total bytes of the values.
+    pub value_bytes: u64,
+    /// Number of tombstones of deleted keys, which are counted in `record_count`.
+    pub tombstone_count: u64,
+    /// The smallest key of the records, including the tombstones, `None` if there is no
+    /// record.
+    pub min_key: Option<Bytes>,
+    /// The largest key of the records, including the tombstones.
+    pub max_key: Option<Bytes>,
+    /// When the segment file was written, which is unknown for segments written before
+    /// this was recorded.
+    pub created_at: Option<SystemTime>,
+}
+
+impl SegmentInfo {
+    /// Average bytes of the key and the value of a record.
+    pub fn average_record_size(&self) -> f64 {
+        if self.record_count == 0 {
+            0.0
+        } else {
+            (self.key_bytes + self.value_bytes) as f64 / self.record_count as f64
+        }
+    }
+}
+
+/// Disk usage of the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Total size of the segment files in bytes.
+    pub segment_bytes: u64,
+    /// Total size of the log files in bytes.
+    pub log_bytes: u64,
+    /// Estimated bytes of segment files taken by shadowed entries and tombstones, which a
+    /// full compaction reclaims.
+    pub reclaimable_bytes: u64,
+}
+
+/// A suggestion of [`Database::tuning_hints`](crate::Database::tuning_hints).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningHint {
+    /// There are more segments than a single merge takes, so lookups search many files.
+    /// Compacting, or raising `max_merge_segments`, brings them down.
+    TooManySegments {
+        /// Number of segments.
+        count: usize,
+    },
+    /// Most of the segment bytes are taken by shadowed entries and tombstones, which
+    /// compacting reclaims.
+    HighSpaceAmplification {
+        /// Estimated reclaimable bytes.
+        reclaimable_bytes: u64,
+        /// Total size of the segment files in bytes.
+        segment_bytes: u64,
+    },
+    /// The active memtable has grown well past the switch mem size while frozen ones wait
+    /// to be written out, so flushing falls behind the writes. A smaller switch mem size
+    /// makes each flush shorter.
+    LargeMemtable {
+        /// Size of the active memtable.
+        size: usize,
+        /// The switch mem size.
+        switch_mem_size: usize,
+    },
+}
+
+/// The result of [`Database::vacuum_segment`](crate::Database::vacuum_segment).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReclaimedBytes {
+    /// Id of the rewritten segment, or `None` if no record is left and the segment is
+    /// removed.
+    pub segment_id: Option<u64>,
+    /// Number of the dropped records.
+    pub dropped_records: u64,
+    /// Bytes the segment shrinks by.
+    pub bytes: u64,
+}
+
+/// The result of [`DatabaseBuilder::recover`](crate::DatabaseBuilder::recover).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of the keys replayed from the logs, counted once per log.
+    pub keys_recovered: usize,
+    /// Number of the segments written.
+    pub segments_written: usize,
+    /// Number of the log records skipped for being corrupt, including the ones after a
+    /// corrupt record in the same log.
+    pub records_skipped: usize,
+}
+
+/// Diagnostics of a lookup by [`Database::get_with_stats`](crate::Database::get_with_stats).
+///
+/// The segments have no bloom filters, so there is no field for them: every segment
+/// searched is counted in `segments_examined`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// Whether the entry of the key is found in the active memtable.
+    pub active_hit: bool,
+    /// Whether the entry of the key is found in a memtable waiting to be written out.
+    pub frozen_hit: bool,
+    /// Whether the entry of the key is found in the value cache.
+    pub cache_hit: bool,
+    /// Number of the segments searched, leaving out the ones skipped for having no entry
+    /// newer than the one found.
+    pub segments_examined: usize,
+    /// Bytes of the segment records read, without the separators, and of the values read
+    /// from value columns.
+    pub bytes_read: u64,
+    /// Time taken by the lookup, including resolving the value.
+    pub elapsed: Duration,
+}
+
+/// Whether a database is operational, see [`Database::health`](crate::Database::health).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the merge and the idle flush tasks started on open are still running.
+    pub tasks_alive: bool,
+    /// The last error or panic of a background task, until the task next succeeds.
+    pub background_error: Option<(BackgroundTask, String)>,
+    /// Whether a file can be written to the data folder.
+    pub disk_writable: bool,
+}
+
+impl Health {
+    /// Whether all the checks pass, so the database can serve reads and writes.
+    pub fn is_healthy(&self) -> bool {
+        self.tasks_alive && self.background_error.is_none() && self.disk_writable
+    }
+}
+
+/// The latency above which operations are logged as slow, see
+/// [`DatabaseBuilder::slow_op_threshold`](crate::DatabaseBuilder::slow_op_threshold).
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SlowOps(pub(crate) Option<Duration>);
+
+impl SlowOps {
+    /// Run `f`, warning if it takes longer than the threshold. Without a threshold the
+    /// clock is never read.
+    pub(crate) fn time<R>(self, op: &str, f: impl FnOnce() -> R) -> R {
+        let threshold = match self.0 {
+            Some(threshold) => threshold,
+            None => return f(),
+        };
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+        if elapsed > threshold {
+            tracing::warn!(
+                "slow {} took {:?}, over the threshold of {:?}",
+                op,
+                elapsed,
+                threshold
+            );
+        }
+        res
+    }
+}
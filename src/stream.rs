@@ -0,0 +1,69 @@
+//! Streaming ranges to async code.
+
+use crate::{Database, MapError};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::ops::RangeBounds;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+/// The number of entries read from the segments at once.
+const CHUNK_SIZE: usize = 256;
+
+type Item = Result<(Bytes, Arc<Bytes>), MapError>;
+
+/// A stream of the entries in a range, see [`Database::range_stream`].
+pub struct RangeStream {
+    chunks: mpsc::Receiver<Vec<Item>>,
+    chunk: std::vec::IntoIter<Item>,
+}
+
+impl Stream for RangeStream {
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        loop {
+            if let Some(item) = self.chunk.next() {
+                return Poll::Ready(Some(item));
+            }
+            match self.chunks.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => self.chunk = chunk.into_iter(),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Stream the entries with keys in the given range, in key order, like
+    /// [`Database::range`].
+    ///
+    /// The entries are read in chunks by a blocking task of the tokio runtime, which
+    /// reads the next chunk while the current one is consumed and stops once the stream
+    /// is dropped. Panics if called outside a tokio runtime.
+    pub fn range_stream<K, R>(&self, range: R) -> Result<RangeStream, MapError>
+    where
+        K: ?Sized,
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let mut entries = self.scan(
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+        )?;
+        let (tx, chunks) = mpsc::channel(1);
+        tokio::task::spawn_blocking(move || loop {
+            let chunk: Vec<Item> = entries.by_ref().take(CHUNK_SIZE).collect();
+            if chunk.is_empty() || tx.blocking_send(chunk).is_err() {
+                break;
+            }
+        });
+        Ok(RangeStream {
+            chunks,
+            chunk: Vec::new().into_iter(),
+        })
+    }
+}
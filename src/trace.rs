@@ -0,0 +1,262 @@
+//! Trace of the operations on a [`Database`], which can be replayed to reproduce bugs.
+//!
+//! A trace is a csv file of `[op, key, value, outcome]` records, appended by a database
+//! opened with [`DatabaseBuilder::op_trace`](crate::DatabaseBuilder::op_trace). Sets,
+//! deletes, gets, flushes and compactions are recorded, and so are the segments dropped
+//! or vacuumed, while the background merges are not. The writes of a transaction are
+//! recorded as sets and deletes once it commits, and an ingest or a replace of all the
+//! data is recorded as a record per entry followed by a record of the outcome.
+
+use crate::format;
+use crate::{Database, Get, Map};
+use bytes::Bytes;
+use csv::{ByteRecord, Writer};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors of [`replay`].
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    /// Io errors.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A record of the trace is not a valid operation.
+    #[error("invalid trace record {0}")]
+    InvalidRecord(usize),
+
+    /// An operation has a different outcome than it had when it was recorded.
+    #[error("operation {index} diverged: recorded {recorded}, replayed {replayed}")]
+    Diverged {
+        /// Index of the operation in the trace.
+        index: usize,
+        /// The recorded outcome.
+        recorded: String,
+        /// The outcome of the replay.
+        replayed: String,
+    },
+}
+
+/// A traced operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Set,
+    Delete,
+    Get,
+    Flush,
+    Compact,
+    /// Compacting the segments older than an age, with the age in milliseconds as the key.
+    CompactOlderThan,
+    /// An entry of the next ingest.
+    IngestEntry,
+    Ingest,
+    /// An entry of the next replace of all the data.
+    ReplaceEntry,
+    ReplaceAll,
+    /// Dropping the oldest segments, with their number as the key.
+    DropOldest,
+    /// Vacuuming a segment, with its id as the key.
+    Vacuum,
+}
+
+impl Op {
+    fn name(&self) -> &'static [u8] {
+        match self {
+            Self::Set => b"set",
+            Self::Delete => b"delete",
+            Self::Get => b"get",
+            Self::Flush => b"flush",
+            Self::Compact => b"compact",
+            Self::CompactOlderThan => b"compact_older_than",
+            Self::IngestEntry => b"ingest_entry",
+            Self::Ingest => b"ingest",
+            Self::ReplaceEntry => b"replace_entry",
+            Self::ReplaceAll => b"replace_all",
+            Self::DropOldest => b"drop_oldest",
+            Self::Vacuum => b"vacuum",
+        }
+    }
+
+    fn parse(name: &[u8]) -> Option<Self> {
+        [
+            Self::Set,
+            Self::Delete,
+            Self::Get,
+            Self::Flush,
+            Self::Compact,
+            Self::CompactOlderThan,
+            Self::IngestEntry,
+            Self::Ingest,
+            Self::ReplaceEntry,
+            Self::ReplaceAll,
+            Self::DropOldest,
+            Self::Vacuum,
+        ]
+        .into_iter()
+        .find(|op| op.name() == name)
+    }
+}
+
+/// The outcome of an operation, with the value found by a get.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Ok,
+    Found(Bytes),
+    Missing,
+    Failed,
+}
+
+impl Outcome {
+    /// The outcome of an operation without a value.
+    pub(crate) fn of<T, E>(res: &Result<T, E>) -> Self {
+        match res {
+            Ok(_) => Self::Ok,
+            Err(_) => Self::Failed,
+        }
+    }
+
+    /// The outcome of a get.
+    pub(crate) fn of_get<E>(res: &Result<Option<Arc<Bytes>>, E>) -> Self {
+        match res {
+            Ok(Some(value)) => Self::Found(value.as_ref().clone()),
+            Ok(None) => Self::Missing,
+            Err(_) => Self::Failed,
+        }
+    }
+
+    /// The found value and the name of the outcome.
+    fn to_fields(&self) -> (Option<&[u8]>, &'static [u8]) {
+        match self {
+            Self::Ok => (None, b"ok"),
+            Self::Found(value) => (Some(value), b"found"),
+            Self::Missing => (None, b"missing"),
+            Self::Failed => (None, b"failed"),
+        }
+    }
+
+    fn from_fields(value: &[u8], outcome: &[u8]) -> Option<Self> {
+        match outcome {
+            b"ok" => Some(Self::Ok),
+            b"found" => Some(Self::Found(Bytes::copy_from_slice(value))),
+            b"missing" => Some(Self::Missing),
+            b"failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Found(value) => write!(f, "found {:?}", value),
+            Self::Missing => write!(f, "missing"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// The writer of a trace.
+pub(crate) struct OpTrace {
+    writer: Mutex<Writer<File>>,
+}
+
+impl OpTrace {
+    /// Open the trace at `path` for appending.
+    pub(crate) fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let writer = format::writer(file);
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Append a record, flushing it right away so it survives a crash. The value is the
+    /// value of a set, and the found value replaces it for a get.
+    ///
+    /// Failing to trace never fails the operation, it is only logged.
+    pub(crate) fn record(&self, op: Op, key: &[u8], value: &[u8], outcome: &Outcome) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let (found, outcome) = outcome.to_fields();
+        let value = found.unwrap_or(value);
+        let res = writer
+            .write_record([op.name(), key, value, outcome])
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.flush());
+        if let Err(err) = res {
+            tracing::warn!("failed to trace operation: err={}", err);
+        }
+    }
+}
+
+impl fmt::Debug for OpTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OpTrace")
+    }
+}
+
+/// Re-execute the operations of the trace at `path` against `db`, which should be a
+/// fresh database opened with the same options as the traced one.
+///
+/// Returns the number of records replayed, or the first operation whose outcome diverges
+/// from the recorded one.
+pub fn replay<P>(path: &P, db: &mut Database) -> Result<usize, ReplayError>
+where
+    P: AsRef<Path> + ?Sized,
+{
+    let reader = format::reader_from_path(path.as_ref()).map_err(std::io::Error::from)?;
+    let mut count = 0;
+    // The entries of the next ingest or replace.
+    let mut entries = Vec::new();
+    for (index, record) in reader.into_byte_records().enumerate() {
+        let record: ByteRecord = record.map_err(std::io::Error::from)?;
+        let (op, key, recorded) = match (record.get(0), record.get(1), record.get(2), record.get(3))
+        {
+            (Some(op), Some(key), Some(value), Some(outcome)) => (
+                Op::parse(op),
+                Bytes::copy_from_slice(key),
+                Outcome::from_fields(value, outcome),
+            ),
+            _ => return Err(ReplayError::InvalidRecord(index)),
+        };
+        let (op, recorded) = op.zip(recorded).ok_or(ReplayError::InvalidRecord(index))?;
+        let value = || Bytes::copy_from_slice(record.get(2).unwrap_or_default());
+        let number = || {
+            std::str::from_utf8(&key)
+                .ok()
+                .and_then(|number| number.parse::<u64>().ok())
+                .ok_or(ReplayError::InvalidRecord(index))
+        };
+        let replayed = match op {
+            Op::Set => Outcome::of(&db.set(key, value())),
+            Op::Delete => Outcome::of(&db.delete(key)),
+            Op::Get => Outcome::of_get(&db.get(&key)),
+            Op::Flush => Outcome::of(&db.flush()),
+            Op::Compact => Outcome::of(&db.compact()),
+            Op::CompactOlderThan => {
+                Outcome::of(&db.compact_older_than(Duration::from_millis(number()?)))
+            }
+            Op::IngestEntry | Op::ReplaceEntry => {
+                entries.push((key, value()));
+                Outcome::Ok
+            }
+            Op::Ingest => Outcome::of(&db.ingest_sorted(std::mem::take(&mut entries))),
+            Op::ReplaceAll => Outcome::of(&db.replace_all(std::mem::take(&mut entries))),
+            Op::DropOldest => Outcome::of(&db.drop_oldest_segments(number()? as usize)),
+            Op::Vacuum => Outcome::of(&db.vacuum_segment(number()?)),
+        };
+        if replayed != recorded {
+            return Err(ReplayError::Diverged {
+                index,
+                recorded: recorded.to_string(),
+                replayed: replayed.to_string(),
+            });
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -1,14 +1,28 @@
 use crate::errors::MapError;
+use crate::value::Value;
 use bytes::Bytes;
 use std::sync::Arc;
 
 /// Get.
 pub trait Get {
     /// Get the value corresponding to the given key.
+    ///
+    /// The key can be anything viewed as bytes, such as `str`, `String`, `[u8]`, `[u8; N]`,
+    /// `Vec<u8>` or `Bytes`, and is only compared by its bytes, so a key set as a `String`
+    /// is found by any of them.
     fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
     where
         Q: ?Sized,
         Q: AsRef<[u8]>;
+
+    /// Get the value like [`Get::get`], as a [`Value`] that compares with byte slices.
+    fn get_value<Q>(&self, key: &Q) -> Result<Option<Value>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        Ok(self.get(key)?.map(Value::from))
+    }
 }
 
 /// A Map.
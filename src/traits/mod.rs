@@ -3,4 +3,12 @@
 /// Map.
 pub mod map;
 
+/// Value resolver.
+pub mod resolver;
+
+/// Validator.
+pub mod validator;
+
 pub use map::{Get, Map};
+pub use resolver::ValueResolver;
+pub use validator::Validator;
@@ -0,0 +1,15 @@
+use crate::errors::MapError;
+use bytes::Bytes;
+use std::fmt;
+
+/// Resolve the stored value references into the values kept elsewhere.
+pub trait ValueResolver: Send + Sync {
+    /// Resolve the value referenced by the stored `reference`.
+    fn resolve(&self, reference: &[u8]) -> Result<Bytes, MapError>;
+}
+
+impl fmt::Debug for dyn ValueResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueResolver")
+    }
+}
@@ -0,0 +1,14 @@
+use std::fmt;
+
+/// Check the keys and the values before they are written, for constraints of the
+/// application such as the format of the values.
+pub trait Validator: Send + Sync {
+    /// Accept the key and the value, or reject them with the reason.
+    fn validate(&self, key: &[u8], value: &[u8]) -> Result<(), String>;
+}
+
+impl fmt::Debug for dyn Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Validator")
+    }
+}
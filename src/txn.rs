@@ -0,0 +1,122 @@
+//! The [`Txn`] structure.
+
+use crate::cache::ValueCache;
+use crate::database::{get_from_segments, lookup, resolve, ReadOrder};
+use crate::memtable::Memtable;
+use crate::schema::{KeyNormalizer, WriteCheck};
+use crate::segment::Segments;
+use crate::trace::{Op, OpTrace, Outcome};
+use crate::{Get, Map, MapError, ValueResolver};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLockWriteGuard};
+
+/// A transaction started by [`Database::transaction`](crate::Database::transaction).
+///
+/// Writes are buffered in the transaction and applied to the database as a single log
+/// record when the transaction succeeds.
+pub struct Txn<'a> {
+    memtable: RwLockWriteGuard<'a, Memtable>,
+    segments: Arc<Segments>,
+    writes: BTreeMap<Bytes, Option<Bytes>>,
+    write_check: &'a WriteCheck,
+    key_normalizer: Option<&'a KeyNormalizer>,
+    read_order: ReadOrder,
+    strict_reads: bool,
+    value_resolver: Option<&'a Arc<dyn ValueResolver>>,
+}
+
+impl<'a> Txn<'a> {
+    pub(crate) fn new(
+        memtable: RwLockWriteGuard<'a, Memtable>,
+        segments: Arc<Segments>,
+        write_check: &'a WriteCheck,
+        key_normalizer: Option<&'a KeyNormalizer>,
+        read_order: ReadOrder,
+        strict_reads: bool,
+        value_resolver: Option<&'a Arc<dyn ValueResolver>>,
+    ) -> Self {
+        Self {
+            memtable,
+            segments,
+            writes: BTreeMap::new(),
+            write_check,
+            key_normalizer,
+            read_order,
+            strict_reads,
+            value_resolver,
+        }
+    }
+
+    /// Apply the pending writes, dropping their keys from the value cache, and return
+    /// whether the memtable has switched.
+    ///
+    /// Once applied, the writes are appended to the trace as sets and deletes.
+    pub(crate) fn commit(
+        mut self,
+        value_cache: Option<&ValueCache>,
+        op_trace: Option<&OpTrace>,
+    ) -> Result<bool, MapError> {
+        let writes = std::mem::take(&mut self.writes);
+        let kept: Vec<(Bytes, Option<Bytes>)> = match (value_cache, op_trace) {
+            (None, None) => Vec::new(),
+            _ => writes
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        };
+        self.memtable.apply(writes.into_iter().collect())?;
+        for (key, value) in kept {
+            if let Some(cache) = value_cache {
+                cache.invalidate(&key);
+            }
+            if let Some(trace) = op_trace {
+                match value {
+                    Some(value) => trace.record(Op::Set, &key, &value, &Outcome::Ok),
+                    None => trace.record(Op::Delete, &key, b"", &Outcome::Ok),
+                }
+            }
+        }
+        Ok(self.memtable.try_switch()?)
+    }
+
+    fn write(&mut self, key: Bytes, value: Option<Bytes>) -> Result<(), MapError> {
+        let key = KeyNormalizer::apply(self.key_normalizer, &key).unwrap_or(key);
+        self.write_check.check(&key, value.as_deref())?;
+        self.writes.insert(key, value);
+        Ok(())
+    }
+}
+
+impl Get for Txn<'_> {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Bytes>>, MapError>
+    where
+        Q: ?Sized,
+        Q: AsRef<[u8]>,
+    {
+        let normalized = KeyNormalizer::apply(self.key_normalizer, key.as_ref());
+        let key = normalized.as_deref().unwrap_or(key.as_ref());
+        let value = match self.writes.get(key) {
+            Some(value) => value.clone().map(Arc::new),
+            None => lookup(
+                self.read_order,
+                || Ok(self.memtable.lookup(key)),
+                || get_from_segments(&self.segments, key, self.strict_reads),
+            )?,
+        };
+        resolve(self.value_resolver, value)
+    }
+}
+
+impl Map for Txn<'_> {
+    fn set<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<(), MapError> {
+        self.write(key.into(), Some(value.into()))
+    }
+}
+
+impl Txn<'_> {
+    /// Delete the given key, doing nothing if it does not exist.
+    pub fn delete<K: Into<Bytes>>(&mut self, key: K) -> Result<(), MapError> {
+        self.write(key.into(), None)
+    }
+}
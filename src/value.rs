@@ -0,0 +1,115 @@
+//! Values returned by reads.
+
+use crate::segment::record_value;
+use bytes::Bytes;
+use csv::ByteRecord;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A value of [`Get::get_value`](crate::Get::get_value), which derefs to the bytes and
+/// compares with byte slices directly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(Arc<Bytes>);
+
+impl Value {
+    /// The shared bytes of the value.
+    pub fn into_inner(self) -> Arc<Bytes> {
+        self.0
+    }
+}
+
+impl From<Arc<Bytes>> for Value {
+    fn from(value: Arc<Bytes>) -> Self {
+        Self(value)
+    }
+}
+
+impl Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<[u8]> for Value {
+    fn eq(&self, other: &[u8]) -> bool {
+        self[..] == *other
+    }
+}
+
+impl PartialEq<&[u8]> for Value {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self[..] == **other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for Value {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for Value {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+/// An entry of [`Database::scan_raw`](crate::Database::scan_raw).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawEntry {
+    /// The key is set to the value.
+    Value(Arc<Bytes>),
+    /// The key is deleted.
+    Tombstone,
+}
+
+/// A value of [`Database::range_lazy`](crate::Database::range_lazy), which is only
+/// copied out of the segment record it is read from when loaded.
+pub struct LazyValue(Inner);
+
+enum Inner {
+    Loaded(Arc<Bytes>),
+    Record(ByteRecord),
+}
+
+impl LazyValue {
+    pub(crate) fn loaded(value: Arc<Bytes>) -> Self {
+        Self(Inner::Loaded(value))
+    }
+
+    pub(crate) fn from_record(record: ByteRecord) -> Self {
+        Self(Inner::Record(record))
+    }
+
+    /// Whether the value is already in memory as [`Bytes`], which is the case for the
+    /// values from the memtable, so loading it is a cheap clone.
+    pub fn is_loaded(&self) -> bool {
+        matches!(self.0, Inner::Loaded(_))
+    }
+
+    /// The value, copied out of its record if it is not loaded yet.
+    pub fn load(&self) -> Arc<Bytes> {
+        match &self.0 {
+            Inner::Loaded(value) => value.clone(),
+            Inner::Record(record) => Arc::new(Bytes::copy_from_slice(record_value(record))),
+        }
+    }
+}
+
+impl fmt::Debug for LazyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Inner::Loaded(value) => f.debug_tuple("LazyValue").field(value).finish(),
+            Inner::Record(_) => write!(f, "LazyValue(..)"),
+        }
+    }
+}
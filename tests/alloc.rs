@@ -0,0 +1,154 @@
+//! Tests counting the allocations of the calling thread.
+
+mod common;
+
+use bytes::Bytes;
+use common::{quiet, TempDir};
+use nouzdb::{Get, Map};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// The system allocator, counting the allocations of each thread and their bytes.
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count(size: usize) {
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + size));
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The bytes allocated by `f` in the calling thread.
+fn allocated(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.with(Cell::get);
+    f();
+    ALLOCATED.with(Cell::get) - before
+}
+
+/// The number of allocations made by `f` in the calling thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn set_moves_bytes_keys_and_values_without_copying_them() {
+    let dir = TempDir::new("set-allocations");
+    let mut db = quiet()
+        .switch_mem_size(64 * 1024 * 1024)
+        .open(dir.path())
+        .unwrap();
+    let count = 100;
+    let entry = |i: usize| {
+        (
+            Bytes::from(format!("{:0>4096}", i)),
+            Bytes::from(format!("{:0>16384}", i)),
+        )
+    };
+    let entries: Vec<(Bytes, Bytes)> = (0..count).map(entry).collect();
+    // The log buffer grows to the size of the records with the first write.
+    let (key, value) = entry(count);
+    db.set(key, value).unwrap();
+    let bytes = allocated(|| {
+        for (key, value) in entries {
+            db.set(key, value).unwrap();
+        }
+    });
+    // A copy of a key alone would take 4096 bytes.
+    assert!(bytes < count * 1024, "{} bytes for {} sets", bytes, count);
+}
+
+#[test]
+fn flush_allocations_do_not_grow_with_the_entries() {
+    let mut counts = Vec::new();
+    for count in [1000, 10_000] {
+        let dir = TempDir::new("flush-allocations");
+        let mut db = quiet()
+            .switch_mem_size(64 * 1024 * 1024)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..count {
+            let (key, value) = common::entry(i);
+            db.set(key, value).unwrap();
+        }
+        counts.push(allocations(|| db.flush().unwrap()));
+        assert_eq!(db.segment_infos().unwrap()[0].record_count, count as u64);
+        for i in (0..count).step_by(101) {
+            let (key, value) = common::entry(i);
+            assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+        }
+    }
+    // Ten times the entries take far less than ten times the allocations, as only the
+    // index grows with them.
+    assert!(counts[1] < counts[0] * 2, "{:?}", counts);
+}
+
+#[test]
+fn range_lazy_copies_only_the_loaded_values() {
+    let dir = TempDir::new("range-lazy-allocations");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let value = "v".repeat(1000);
+    for i in 0..1000 {
+        db.set(format!("key{:05}", i), value.clone()).unwrap();
+    }
+    db.flush().unwrap();
+    db.set("key99999", "in the memtable").unwrap();
+
+    // The bytes of a scan loading the values of every `step`th key.
+    let scan = |step: usize| {
+        allocated(|| {
+            for (idx, entry) in db.range_lazy::<str, _>(..).unwrap().enumerate() {
+                let (key, lazy) = entry.unwrap();
+                assert_eq!(lazy.is_loaded(), key.as_ref() == b"key99999");
+                if step != 0 && idx % step == 0 && !lazy.is_loaded() {
+                    assert_eq!(lazy.load().len(), 1000);
+                }
+            }
+        })
+    };
+    let none = scan(0);
+    let some = scan(100);
+    let all = scan(1);
+    // Ten values are copied, against a thousand for the full scan.
+    assert!(some - none < 20 * 1000, "{} against {}", some, none);
+    assert!(all - none > 990 * 1000, "{} against {}", all, none);
+}
+
+#[test]
+fn a_scan_allocates_about_once_per_record() {
+    let dir = TempDir::new("scan-allocations");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..10_000 {
+        db.set(format!("key{:08}", i), format!("value{:08}", i))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    let mut count = 0;
+    let allocations = allocations(|| count = db.range::<str, _>(..).unwrap().count());
+    assert_eq!(count, 10_000);
+    // The keys and the values are copied into shared chunks, leaving the Arc of each value.
+    assert!(allocations < count * 3 / 2, "{} allocations", allocations);
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::TempDir;
+use nouzdb::{BuilderError, DatabaseBuilder, Error, Map};
+
+#[test]
+fn data_suffix_tmp_collides_with_the_tmp_suffix() {
+    let dir = TempDir::new("suffix-tmp");
+    let mut builder = DatabaseBuilder::default();
+    builder.data_suffix("tmp");
+    assert!(matches!(
+        builder.validate(),
+        Err(BuilderError::DuplicateSuffix(suffix)) if suffix == "tmp"
+    ));
+    assert!(matches!(
+        builder.open(dir.path()),
+        Err(Error::Builder(BuilderError::DuplicateSuffix(_)))
+    ));
+    // Nothing is touched.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    // Moving the temporary files elsewhere resolves it.
+    builder.tmp_suffix("partial");
+    builder.open(dir.path()).unwrap();
+}
+
+#[test]
+fn suffixes_must_be_valid() {
+    for suffix in ["", "a.b"] {
+        let mut builder = DatabaseBuilder::default();
+        builder.log_suffix(suffix);
+        assert!(matches!(
+            builder.validate(),
+            Err(BuilderError::InvalidSuffix(invalid)) if invalid == suffix
+        ));
+    }
+    let mut builder = DatabaseBuilder::default();
+    builder.pack_suffix("log");
+    assert!(matches!(
+        builder.validate(),
+        Err(BuilderError::DuplicateSuffix(_))
+    ));
+}
+
+#[test]
+fn a_zero_switch_mem_size_is_rejected() {
+    let dir = TempDir::new("zero-switch");
+    let mut builder = DatabaseBuilder::default();
+    builder.switch_mem_size(0);
+    assert!(matches!(
+        builder.validate(),
+        Err(BuilderError::InvalidSwitchMemSize)
+    ));
+    assert!(matches!(
+        builder.open(dir.path()),
+        Err(Error::Builder(BuilderError::InvalidSwitchMemSize))
+    ));
+
+    let mut db = DatabaseBuilder::default()
+        .sync_flush(true)
+        .open(dir.path())
+        .unwrap();
+    assert!(matches!(
+        db.set_switch_mem_size(0),
+        Err(Error::Builder(BuilderError::InvalidSwitchMemSize))
+    ));
+    // The size is left as it was, so the writes stay in the memtable.
+    for i in 0..100 {
+        db.set(format!("key{}", i), "value").unwrap();
+    }
+    assert!(db.segment_infos().unwrap().is_empty());
+}
+
+/// The error of validating the default options changed by `f`.
+fn invalid(f: impl FnOnce(&mut DatabaseBuilder)) -> BuilderError {
+    let mut builder = DatabaseBuilder::default();
+    f(&mut builder);
+    builder.validate().unwrap_err()
+}
+
+#[test]
+fn segment_counts_below_two_are_rejected() {
+    for count in [0, 1] {
+        assert!(matches!(
+            invalid(|builder| {
+                builder.max_merge_segments(count);
+            }),
+            BuilderError::InvalidMaxMergeSegments(invalid) if invalid == count
+        ));
+        assert!(matches!(
+            invalid(|builder| {
+                builder.max_segments(count);
+            }),
+            BuilderError::InvalidMaxSegments(invalid) if invalid == count
+        ));
+        assert!(matches!(
+            invalid(|builder| {
+                builder.pack_segments(count);
+            }),
+            BuilderError::InvalidPackSegments(invalid) if invalid == count
+        ));
+    }
+    let mut builder = DatabaseBuilder::default();
+    builder
+        .max_merge_segments(2)
+        .max_segments(2)
+        .pack_segments(2);
+    builder.validate().unwrap();
+}
+
+#[test]
+fn a_zero_block_size_is_rejected() {
+    assert!(matches!(
+        invalid(|builder| {
+            builder.block_size(0);
+        }),
+        BuilderError::InvalidBlockSize
+    ));
+}
+
+#[test]
+fn a_zero_read_buffer_size_is_rejected() {
+    assert!(matches!(
+        invalid(|builder| {
+            builder.read_buffer_size(0);
+        }),
+        BuilderError::InvalidReadBufferSize
+    ));
+}
+
+#[test]
+fn a_zero_max_cached_segment_meta_is_rejected() {
+    assert!(matches!(
+        invalid(|builder| {
+            builder.max_cached_segment_meta(0);
+        }),
+        BuilderError::InvalidMaxCachedSegmentMeta
+    ));
+    let mut builder = DatabaseBuilder::default();
+    builder.max_cached_segment_meta(1);
+    builder.validate().unwrap();
+}
+
+#[test]
+fn a_negative_or_non_finite_self_compact_tombstone_ratio_is_rejected() {
+    for ratio in [-0.1, f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            invalid(|builder| {
+                builder.self_compact_tombstone_ratio(ratio);
+            }),
+            BuilderError::InvalidSelfCompactTombstoneRatio(_)
+        ));
+    }
+    for ratio in [0.0, 1.0, 2.0] {
+        let mut builder = DatabaseBuilder::default();
+        builder.self_compact_tombstone_ratio(ratio);
+        builder.validate().unwrap();
+    }
+}
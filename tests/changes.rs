@@ -0,0 +1,80 @@
+mod common;
+
+use bytes::Bytes;
+use common::{quiet, TempDir};
+use nouzdb::{Get, Map, RawEntry};
+use std::sync::Arc;
+
+fn value(value: &str) -> RawEntry {
+    RawEntry::Value(Arc::new(Bytes::from(value.to_string())))
+}
+
+#[test]
+fn scan_raw_yields_tombstones_that_range_leaves_out() {
+    let dir = TempDir::new("scan-raw");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "2").unwrap();
+    db.set("c", "3").unwrap();
+    db.flush().unwrap();
+    db.delete("b").unwrap();
+    db.set("c", "newer").unwrap();
+    let raw: Vec<_> = db.scan_raw().unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        raw,
+        [
+            (Bytes::from("a"), value("1")),
+            (Bytes::from("b"), RawEntry::Tombstone),
+            (Bytes::from("c"), value("newer")),
+        ]
+    );
+    let keys: Vec<_> = db
+        .range::<str, _>(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, ["a", "c"]);
+}
+
+#[test]
+fn changes_since_yields_exactly_the_later_writes() {
+    let dir = TempDir::new("changes-since");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "2").unwrap();
+    db.flush().unwrap();
+    db.set("c", "3").unwrap();
+    let seq = db.last_seq().unwrap();
+
+    db.set("d", "4").unwrap();
+    db.flush().unwrap();
+    db.delete("a").unwrap();
+    db.set("b", "newer").unwrap();
+    let changes: Vec<_> = db
+        .changes_since(seq)
+        .unwrap()
+        .map(|(_, key, entry)| (key, entry))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            (Bytes::from("d"), value("4")),
+            (Bytes::from("a"), RawEntry::Tombstone),
+            (Bytes::from("b"), value("newer")),
+        ]
+    );
+    let seqs: Vec<_> = db
+        .changes_since(seq)
+        .unwrap()
+        .map(|(seq, ..)| seq)
+        .collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(seqs[0] > seq);
+    assert_eq!(db.changes_since(db.last_seq().unwrap()).unwrap().count(), 0);
+
+    // The sequence numbers are read back from the segments and the logs after a reopen.
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(db.changes_since(seq).unwrap().count(), 3);
+    assert!(db.get("a").unwrap().is_none());
+}
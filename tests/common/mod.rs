@@ -0,0 +1,74 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use nouzdb::{Database, DatabaseBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh folder under the temporary folder of the OS, removed once dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "nouzdb-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The path of `name` in the folder.
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A builder with synchronous flushes and no background merges, so the segments only
+/// change as the test says.
+pub fn quiet() -> DatabaseBuilder {
+    let mut builder = DatabaseBuilder::default();
+    builder.sync_flush(true).auto_merge(false);
+    builder
+}
+
+/// Copy the files of the folder `from` to `to`, as a crash at this point leaves them.
+/// The lock file is left out, as the lock of a crashed process is gone.
+pub fn copy_files(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_file() && entry.file_name() != "LOCK" {
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+/// The ids of the segments, from the oldest to the newest.
+pub fn segment_ids(db: &Database) -> Vec<u64> {
+    db.segment_infos()
+        .unwrap()
+        .iter()
+        .map(|info| info.id)
+        .collect()
+}
+
+/// The key and the value of the `i`th entry of a test data set.
+pub fn entry(i: usize) -> (String, String) {
+    (format!("key{:05}", i), format!("value{:05}", i))
+}
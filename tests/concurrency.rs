@@ -0,0 +1,305 @@
+mod common;
+
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{DatabaseHandle, Get};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+#[test]
+fn reads_go_on_during_flushes_and_merges() {
+    let dir = TempDir::new("concurrent-merge");
+    let db = DatabaseHandle::from(quiet().open(dir.path()).unwrap());
+    for segment in 0..5 {
+        for i in segment * 1000..(segment + 1) * 1000 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicUsize::new(0));
+    let readers: Vec<_> = (0..3)
+        .map(|reader| {
+            let db = db.read_handle();
+            let (stop, reads) = (stop.clone(), reads.clone());
+            thread::spawn(move || {
+                let mut i = reader;
+                while !stop.load(Ordering::Relaxed) {
+                    let (key, value) = entry(i % 5000);
+                    assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+                    reads.fetch_add(1, Ordering::Relaxed);
+                    i += 7919;
+                }
+            })
+        })
+        .collect();
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 5000..6000 {
+                let (key, value) = entry(i);
+                db.set(key, value).unwrap();
+                if i % 200 == 0 {
+                    db.flush().unwrap();
+                }
+            }
+        })
+    };
+    let mut reads_during_merges = 0;
+    for _ in 0..3 {
+        let before = reads.load(Ordering::Relaxed);
+        db.compact().unwrap();
+        reads_during_merges += reads.load(Ordering::Relaxed) - before;
+    }
+    writer.join().unwrap();
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert!(reads_during_merges > 0);
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+    for i in (0..6000).step_by(7) {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn a_key_is_never_missing_while_its_segments_are_merged() {
+    let dir = TempDir::new("read-during-merge");
+    let db = DatabaseHandle::from(quiet().open(dir.path()).unwrap());
+    db.set("key", "0").unwrap();
+    db.flush().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let db = db.read_handle();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut reads = 0;
+            while !stop.load(Ordering::Relaxed) {
+                assert!(db.get("key").unwrap().is_some());
+                reads += 1;
+            }
+            reads
+        })
+    };
+    for round in 1..30 {
+        // The key moves to the newest segment, and is merged with the older ones.
+        db.set("key", round.to_string()).unwrap();
+        for i in 0..50 {
+            let (key, value) = entry(round * 50 + i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    assert!(reader.join().unwrap() > 0);
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"29"[..]);
+}
+
+#[test]
+fn a_long_read_does_not_hold_back_a_flush() {
+    let dir = TempDir::new("long-read");
+    let db = DatabaseHandle::from(quiet().open(dir.path()).unwrap());
+    for segment in 0..4 {
+        for i in segment * 100..(segment + 1) * 100 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let (started, on_start) = std::sync::mpsc::channel();
+    let reader = {
+        let db = db.read_handle();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut count = 0;
+            for entry in db.range::<str, _>(..).unwrap() {
+                entry.unwrap();
+                if count == 0 {
+                    started.send(()).unwrap();
+                }
+                count += 1;
+                thread::sleep(std::time::Duration::from_millis(2));
+            }
+            done.store(true, Ordering::Relaxed);
+            count
+        })
+    };
+    on_start.recv().unwrap();
+    // The read takes about 800ms, and the flush is done long before it.
+    db.set("new", "1").unwrap();
+    db.flush().unwrap();
+    assert!(!done.load(Ordering::Relaxed));
+    assert_eq!(segment_ids(&db).len(), 5);
+    assert_eq!(reader.join().unwrap(), 400);
+}
+
+#[test]
+fn cloned_handles_write_from_several_threads() {
+    let dir = TempDir::new("cloned-handles");
+    let db = DatabaseHandle::from(quiet().switch_mem_size(4096).open(dir.path()).unwrap());
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in (writer..2000).step_by(4) {
+                    let (key, value) = entry(i);
+                    db.set(key, value).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    for i in 0..2000 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(db.range::<str, _>(..).unwrap().count(), 2000);
+}
+
+#[test]
+fn read_handles_are_shared_between_threads_while_another_writes() {
+    fn assert_shareable<T: Clone + Send + Sync>(_: &T) {}
+
+    let dir = TempDir::new("read-handles");
+    let db = DatabaseHandle::from(quiet().open(dir.path()).unwrap());
+    for i in 0..500 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    let reader = db.read_handle();
+    assert_shareable(&reader);
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let reader = reader.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    for i in (0..500).step_by(13) {
+                        let (key, value) = entry(i);
+                        assert_eq!(
+                            reader.get(&key).unwrap().unwrap().as_ref(),
+                            value.as_bytes()
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    for i in 500..2000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+        if i % 500 == 0 {
+            db.flush().unwrap();
+        }
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(reader.range::<str, _>(..).unwrap().count(), 2000);
+}
+
+#[test]
+fn a_write_is_read_back_across_switches_and_flushes() {
+    let dir = TempDir::new("read-your-writes");
+    let mut builder = nouzdb::DatabaseBuilder::default();
+    builder.auto_merge(false).switch_mem_size(256);
+    let db = DatabaseHandle::from(builder.open(dir.path()).unwrap());
+    let writers: Vec<_> = (0..2)
+        .map(|writer| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in (writer..4000).step_by(2) {
+                    let (key, value) = entry(i);
+                    db.set(key.clone(), value.clone()).unwrap();
+                    assert_eq!(
+                        db.get(&key).unwrap().as_deref().map(|value| &value[..]),
+                        Some(value.as_bytes()),
+                        "{}",
+                        key
+                    );
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    // A switch waits for the previous memtable to be written out, so there are only a few.
+    assert!(segment_ids(&db).len() > 1);
+}
+
+#[test]
+fn scans_see_either_version_of_a_replace_all() {
+    use bytes::Bytes;
+
+    /// Version `v` of the data, with a different number of keys for each version.
+    fn version(v: usize) -> Vec<(Bytes, Bytes)> {
+        (0..300 + v % 2 * 200)
+            .map(|i| {
+                let (key, value) = entry(i);
+                (Bytes::from(key), Bytes::from(format!("v{}-{}", v, value)))
+            })
+            .collect()
+    }
+
+    let dir = TempDir::new("replace-all");
+    let db = DatabaseHandle::from(quiet().open(dir.path()).unwrap());
+    // The first version is in a segment and the memtable.
+    for (idx, (key, value)) in version(1).into_iter().enumerate() {
+        db.set(key, value).unwrap();
+        if idx == 250 {
+            db.flush().unwrap();
+        }
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let scans = Arc::new(AtomicUsize::new(0));
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let db = db.read_handle();
+            let (stop, scans) = (stop.clone(), scans.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let entries: Vec<_> = db
+                        .range::<str, _>(..)
+                        .unwrap()
+                        .map(|entry| {
+                            let (key, value) = entry.unwrap();
+                            (key, value.as_ref().clone())
+                        })
+                        .collect();
+                    let v: usize = std::str::from_utf8(&entries[0].1[1..2])
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    assert_eq!(entries, version(v));
+                    scans.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    for v in 2..8 {
+        let before = scans.load(Ordering::Relaxed);
+        while scans.load(Ordering::Relaxed) == before {
+            thread::yield_now();
+        }
+        db.replace_all(version(v)).unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(segment_ids(&db).len(), 1);
+    assert_eq!(db.range::<str, _>(..).unwrap().count(), version(7).len());
+}
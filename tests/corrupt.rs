@@ -0,0 +1,137 @@
+mod common;
+
+use bytes::Bytes;
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{Get, Map, MapError};
+
+#[test]
+fn lookups_in_a_truncated_segment_still_find_the_complete_records() {
+    let dir = TempDir::new("truncated-segment");
+    let mut builder = quiet();
+    builder.block_size(256);
+    let mut db = builder.open(dir.path()).unwrap();
+    for i in 0..200 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let id = segment_ids(&db)[0];
+    drop(db);
+    // Cut the segment in the middle of the value of the 150th record, so the footer is
+    // gone and the last record has no sequence number.
+    let path = dir.join(&format!("{}.data", id));
+    let data = std::fs::read(&path).unwrap();
+    let (key, _) = entry(150);
+    let start = data
+        .windows(key.len())
+        .position(|window| window == key.as_bytes())
+        .unwrap();
+    let cut = start + key.len() + ",val".len();
+    std::fs::write(&path, &data[..cut]).unwrap();
+
+    let db = builder.open(dir.path()).unwrap();
+    for i in 0..150 {
+        let (key, value) = entry(i);
+        assert_eq!(
+            db.get(&key).unwrap().unwrap().as_ref(),
+            value.as_bytes(),
+            "{}",
+            key
+        );
+    }
+    for i in 151..200 {
+        assert!(db.get(&entry(i).0).unwrap().is_none());
+    }
+}
+
+#[test]
+fn the_last_entry_of_a_duplicated_key_in_a_segment_wins() {
+    let dir = TempDir::new("duplicate-key");
+    std::fs::write(dir.join("1.data"), "a,1,1\nb,first,2\nb,second,2\nc,3,3\n").unwrap();
+    let mut db = quiet().open(dir.path()).unwrap();
+    assert_eq!(db.get("b").unwrap().unwrap().as_ref(), &b"second"[..]);
+    let entries: Vec<_> = db
+        .range::<str, _>(..)
+        .unwrap()
+        .map(|entry| {
+            let (key, value) = entry.unwrap();
+            (key, value.as_ref().clone())
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        [("a", "1"), ("b", "second"), ("c", "3")]
+            .map(|(key, value)| (Bytes::from(key), Bytes::from(value)))
+    );
+    // A merge keeps only the last entry as well.
+    db.set("d", "4").unwrap();
+    db.flush().unwrap();
+    db.compact().unwrap();
+    assert_eq!(db.get("b").unwrap().unwrap().as_ref(), &b"second"[..]);
+    let infos = db.segment_infos().unwrap();
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0].record_count, 4);
+}
+
+#[test]
+fn strict_reads_fail_on_a_corrupt_record_where_lenient_ones_miss_it() {
+    let dir = TempDir::new("strict-reads");
+    std::fs::write(dir.join("1.data"), "a,1,1\nb,2,not a seq\nc,3,3\n").unwrap();
+    let db = quiet().open(dir.path()).unwrap();
+    assert!(db.get("b").unwrap().is_none());
+    assert_eq!(db.get("c").unwrap().unwrap().as_ref(), &b"3"[..]);
+    drop(db);
+
+    let db = quiet().strict_reads(true).open(dir.path()).unwrap();
+    assert!(matches!(
+        db.get("b"),
+        Err(MapError::CorruptSegment {
+            segment_id: 1,
+            offset: 6
+        })
+    ));
+}
+
+#[test]
+fn a_merge_fails_on_a_corrupt_record_instead_of_dropping_it() {
+    let dir = TempDir::new("merge-corrupt");
+    std::fs::write(dir.join("1.data"), "a,1,1\nb,2,not a seq\nc,3,3\n").unwrap();
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("d", "4").unwrap();
+    db.flush().unwrap();
+    let ids = segment_ids(&db);
+    for _ in 0..2 {
+        let err = db.compact().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("corrupt record at offset 6 of segment 1"),
+            "{}",
+            err
+        );
+        // Nothing of the merge is left, so it can be tried again.
+        assert_eq!(segment_ids(&db), ids);
+        let files = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(files, ids.len() + 2, "the segments, the log and the lock");
+    }
+    assert_eq!(db.get("c").unwrap().unwrap().as_ref(), &b"3"[..]);
+    assert_eq!(db.get("d").unwrap().unwrap().as_ref(), &b"4"[..]);
+}
+
+#[test]
+fn a_merge_of_segments_out_of_order_fails_to_write() {
+    let dir = TempDir::new("merge-unordered");
+    std::fs::write(dir.join("1.data"), "b,2,2\na,1,1\nc,3,3\n").unwrap();
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("d", "4").unwrap();
+    db.flush().unwrap();
+    let ids = segment_ids(&db);
+    let err = db.compact().unwrap_err();
+    assert!(
+        err.to_string().contains("keys written out of order"),
+        "{}",
+        err
+    );
+    assert_eq!(segment_ids(&db), ids);
+    let files = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(files, ids.len() + 2, "the segments, the log and the lock");
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::database::WriteStall;
+use nouzdb::{DatabaseBuilder, Get, Map, MapError};
+use std::time::Duration;
+
+#[test]
+fn two_quick_flushes_are_both_written_out() {
+    let dir = TempDir::new("two-flushes");
+    let mut builder = DatabaseBuilder::default();
+    builder.auto_merge(false);
+    let mut db = builder.open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.flush().unwrap();
+    // The first memtable is most likely still frozen, so this one is queued behind it.
+    db.set("b", "2").unwrap();
+    db.flush().unwrap();
+    assert!(db.wait_until_flushed("a", Duration::from_secs(10)).unwrap());
+    assert!(db.wait_until_flushed("b", Duration::from_secs(10)).unwrap());
+    assert_eq!(segment_ids(&db).len(), 2);
+    drop(db);
+    let db = builder.open(dir.path()).unwrap();
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"1"[..]);
+    assert_eq!(db.get("b").unwrap().unwrap().as_ref(), &b"2"[..]);
+}
+
+#[test]
+fn an_idle_memtable_is_flushed() {
+    let dir = TempDir::new("idle-flush");
+    let mut db = DatabaseBuilder::default()
+        .auto_merge(false)
+        .poll_period(Duration::from_millis(10))
+        .idle_flush(Duration::from_millis(100))
+        .open(dir.path())
+        .unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "2").unwrap();
+    assert!(segment_ids(&db).is_empty());
+    assert!(db.wait_until_flushed("b", Duration::from_secs(10)).unwrap());
+    assert_eq!(segment_ids(&db).len(), 1);
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"1"[..]);
+}
+
+#[test]
+fn lowering_the_switch_size_switches_at_the_next_write() {
+    let dir = TempDir::new("switch-size");
+    let mut db = quiet()
+        .switch_mem_size(1024 * 1024)
+        .open(dir.path())
+        .unwrap();
+    for i in 0..100 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    assert!(segment_ids(&db).is_empty());
+    db.set_switch_mem_size(100).unwrap();
+    assert!(segment_ids(&db).is_empty());
+    db.set("one more", "key").unwrap();
+    assert!(db
+        .wait_until_flushed("one more", Duration::from_secs(10))
+        .unwrap());
+    assert_eq!(segment_ids(&db).len(), 1);
+}
+
+#[test]
+fn flushes_switches_and_merges_sync_their_folders() {
+    let dir = TempDir::new("sync-dir");
+    let logs = dir.join("logs");
+    let data = dir.join("data");
+    let mut builder = quiet();
+    builder.log_dir(&logs).switch_mem_size(1024);
+    let mut db = builder.open(&data).unwrap();
+    // Every switch creates a log and renames a segment into place.
+    for i in 0..500 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    assert!(segment_ids(&db).len() > 2);
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+    drop(db);
+    let db = builder.open(&data).unwrap();
+    for i in 0..500 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+/// A builder switching at 4MB with the flushes in the background, stalling the writes
+/// at 4KB.
+fn stalling(stall: WriteStall) -> DatabaseBuilder {
+    let mut builder = quiet();
+    builder
+        .sync_flush(false)
+        .switch_mem_size(4 * 1024 * 1024)
+        .stall_threshold(4 * 1024)
+        .write_stall(stall);
+    builder
+}
+
+fn large_value(i: usize) -> String {
+    format!("{:0>1024}", i)
+}
+
+#[test]
+fn a_write_past_the_stall_threshold_fails_while_a_flush_is_behind() {
+    let dir = TempDir::new("stall-fail");
+    let mut db = stalling(WriteStall::Fail).open(dir.path()).unwrap();
+    let mut stalled = None;
+    for i in 0..8 * 1024 {
+        match db.set(entry(i).0, large_value(i)) {
+            Ok(()) => {}
+            Err(MapError::WriteStalled) => {
+                stalled = Some(i);
+                break;
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+    // The switch is at about the 4000th write, and the active memtable takes only about
+    // four more before the frozen one is written out.
+    let stalled = stalled.expect("no write stalled");
+    assert!((4000..4200).contains(&stalled), "stalled at {}", stalled);
+    assert!(db.get(&entry(stalled).0).unwrap().is_none());
+    db.flush().unwrap();
+    db.set(entry(stalled).0, large_value(stalled)).unwrap();
+    for i in (0..=stalled).step_by(97) {
+        let value = large_value(i);
+        assert_eq!(
+            db.get(&entry(i).0).unwrap().unwrap().as_ref(),
+            value.as_bytes()
+        );
+    }
+}
+
+#[test]
+fn a_write_past_the_stall_threshold_waits_for_the_flush() {
+    let dir = TempDir::new("stall-block");
+    let mut db = stalling(WriteStall::Block).open(dir.path()).unwrap();
+    for i in 0..4100 {
+        db.set(entry(i).0, large_value(i)).unwrap();
+    }
+    // The last writes waited for the first memtable to be written out.
+    assert_eq!(segment_ids(&db).len(), 1);
+    for i in (0..4100).step_by(97) {
+        let value = large_value(i);
+        assert_eq!(
+            db.get(&entry(i).0).unwrap().unwrap().as_ref(),
+            value.as_bytes()
+        );
+    }
+}
+
+#[test]
+fn a_sync_flush_is_written_out_when_the_switching_write_returns() {
+    let dir = TempDir::new("sync-flush");
+    let mut builder = DatabaseBuilder::default();
+    builder
+        .auto_merge(false)
+        .sync_flush(true)
+        .switch_mem_size(1024);
+    let mut db = builder.open(dir.path()).unwrap();
+    let mut i = 0;
+    while segment_ids(&db).is_empty() {
+        assert!(i < 1000, "no switch");
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+        i += 1;
+    }
+    // No wait: the write switching the memtable out returned after the flush.
+    let ids = segment_ids(&db);
+    let path = dir.join(&format!("{}.data", ids[0]));
+    assert!(path.exists(), "{:?}", path);
+    let infos = db.segment_infos().unwrap();
+    assert_eq!(infos[0].record_count as usize, i);
+}
+
+#[test]
+fn wait_until_flushed_returns_once_the_key_is_in_a_segment() {
+    let dir = TempDir::new("wait-until-flushed");
+    let mut builder = DatabaseBuilder::default();
+    builder.auto_merge(false).switch_mem_size(64);
+    let mut db = builder.open(dir.path()).unwrap();
+    db.set("kept", "in the memtable").unwrap();
+    // Nothing switches the memtable out, so the wait runs out.
+    assert!(!db
+        .wait_until_flushed("kept", Duration::from_millis(50))
+        .unwrap());
+    assert!(!db
+        .wait_until_flushed("missing", Duration::from_millis(10))
+        .unwrap());
+    for i in 0..10 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    assert!(db
+        .wait_until_flushed(&entry(0).0, Duration::from_secs(10))
+        .unwrap());
+    assert!(db
+        .wait_until_flushed("kept", Duration::from_secs(10))
+        .unwrap());
+    assert!(!segment_ids(&db).is_empty());
+}
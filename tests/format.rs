@@ -0,0 +1,87 @@
+mod common;
+
+use bytes::Bytes;
+use common::{copy_files, quiet, TempDir};
+use nouzdb::database::SegmentFormat;
+use nouzdb::{replay, Database, Get, Map};
+
+/// Records that only survive a round trip when quotes, delimiters and line breaks are
+/// escaped, and when records of other lengths are accepted in the same file.
+fn awkward() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("comma,key", "a,b,c"),
+        ("quote\"key", "say \"hi\""),
+        ("line\nkey", "first\nsecond\r\nthird"),
+        ("plain", ""),
+        ("", "empty key"),
+    ]
+}
+
+fn assert_awkward(db: &Database) {
+    for (key, value) in awkward() {
+        assert_eq!(
+            db.get(key).unwrap().unwrap().as_ref(),
+            &Bytes::from(value),
+            "key {:?}",
+            key
+        );
+    }
+}
+
+#[test]
+fn the_log_the_segments_and_the_trace_read_back_awkward_records() {
+    let dir = TempDir::new("format");
+    let trace = dir.join("trace.csv");
+    let data = dir.join("data");
+    let mut db = quiet().op_trace(&trace).open(&data).unwrap();
+    for (key, value) in awkward() {
+        db.set(key, value).unwrap();
+    }
+
+    // The log, recovered after a crash.
+    let crashed = dir.join("crashed");
+    copy_files(&data, &crashed);
+    let recovered = quiet().open(&crashed).unwrap();
+    assert_awkward(&recovered);
+    drop(recovered);
+
+    // A segment, read once the memtable is flushed.
+    db.flush().unwrap();
+    drop(db);
+    let reopened = quiet().open(&data).unwrap();
+    assert_awkward(&reopened);
+    drop(reopened);
+
+    // The trace, replayed into a fresh folder.
+    let mut replayed = quiet().open(&dir.join("replayed")).unwrap();
+    replay(&trace, &mut replayed).unwrap();
+    assert_awkward(&replayed);
+}
+
+#[test]
+fn values_of_many_megabytes_round_trip() {
+    let large = Bytes::from(vec![b'v'; 10 * 1024 * 1024]);
+    let key = Bytes::from("k".repeat(100_000));
+    for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+        let dir = TempDir::new("large-values");
+        let mut builder = quiet();
+        builder.segment_format(format);
+        let mut db = builder.open(dir.path()).unwrap();
+        db.set(key.clone(), large.clone()).unwrap();
+        db.set("after", "small").unwrap();
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), &large);
+        // Replayed from the log.
+        let crashed = TempDir::new("large-values-crashed");
+        copy_files(dir.path(), crashed.path());
+        let replayed = builder.open(crashed.path()).unwrap();
+        assert_eq!(replayed.get(&key).unwrap().unwrap().as_ref(), &large);
+        drop(replayed);
+        // Read from a segment.
+        db.flush().unwrap();
+        drop(db);
+        let db = builder.open(dir.path()).unwrap();
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), &large);
+        assert_eq!(db.get("after").unwrap().unwrap().as_ref(), &b"small"[..]);
+        assert_eq!(db.value_len(&key).unwrap(), Some(large.len()));
+    }
+}
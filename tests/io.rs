@@ -0,0 +1,230 @@
+//! Tests counting the bytes read from the files, through the io statistics of the
+//! process, so they only run on Linux and one at a time.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{entry, quiet, TempDir};
+use nouzdb::database::SegmentFormat;
+use nouzdb::{Get, Map};
+use std::sync::{Mutex, MutexGuard};
+
+/// Held by every test, as the statistics are of the whole process.
+static IO: Mutex<()> = Mutex::new(());
+
+fn serial() -> MutexGuard<'static, ()> {
+    IO.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// The io statistic `name` of the process, such as `rchar` for the bytes read.
+fn io_stat(name: &str) -> u64 {
+    let io = std::fs::read_to_string("/proc/self/io").unwrap();
+    io.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+/// The bytes read by `f`.
+fn bytes_read(f: impl FnOnce()) -> u64 {
+    let before = io_stat("rchar");
+    f();
+    io_stat("rchar") - before
+}
+
+/// The read calls made by `f`.
+fn read_calls(f: impl FnOnce()) -> u64 {
+    let before = io_stat("syscr");
+    f();
+    io_stat("syscr") - before
+}
+
+#[test]
+fn columnar_keys_read_fewer_bytes_than_rows() {
+    let _serial = serial();
+    let value = "v".repeat(1024);
+    let mut read = Vec::new();
+    for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+        let dir = TempDir::new("columns-keys");
+        let mut db = quiet().segment_format(format).open(dir.path()).unwrap();
+        for i in 0..500 {
+            db.set(format!("key{:04}", i), format!("{}{}", value, i))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..500 {
+            assert_eq!(
+                db.get(&format!("key{:04}", i)).unwrap().unwrap().as_ref(),
+                format!("{}{}", value, i).as_bytes()
+            );
+        }
+        let values: Vec<_> = db
+            .range::<str, _>(..)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(values.len(), 500);
+        assert_eq!(values[7].1.as_ref(), format!("{}7", value).as_bytes());
+        read.push(bytes_read(|| {
+            let keys: Vec<_> = db.keys::<str, _>(..).unwrap().map(Result::unwrap).collect();
+            assert_eq!(keys.len(), 500);
+        }));
+    }
+    assert!(
+        read[1] * 10 < read[0],
+        "rows={} columns={}",
+        read[0],
+        read[1]
+    );
+}
+
+#[test]
+fn warm_up_reads_every_segment_and_lookups_then_use_the_index() {
+    let _serial = serial();
+    let dir = TempDir::new("warm-up");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        for i in 0..1000 {
+            let (key, value) = entry(round * 1000 + i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    let sizes: u64 = db
+        .segment_infos()
+        .unwrap()
+        .iter()
+        .map(|info| info.file_size)
+        .sum();
+    assert!(bytes_read(|| db.warm_up().unwrap()) >= sizes);
+    // The indices are built, so a lookup only reads a block of the segment with the key.
+    let (key, value) = entry(1500);
+    let (found, stats) = db.get_with_stats(&key).unwrap();
+    assert_eq!(found.unwrap().as_ref(), value.as_bytes());
+    assert!(stats.bytes_read < 2 * 4096, "{:?}", stats);
+}
+
+#[test]
+fn value_len_reads_fewer_bytes_than_get_for_large_values() {
+    let _serial = serial();
+    for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+        let dir = TempDir::new("value-len");
+        let mut db = quiet().segment_format(format).open(dir.path()).unwrap();
+        for i in 0..8 {
+            db.set(format!("key{}", i), "v".repeat(100_000 + i))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        db.set("unflushed", "12345").unwrap();
+        assert_eq!(db.value_len("unflushed").unwrap(), Some(5));
+        assert_eq!(db.value_len("missing").unwrap(), None);
+        let mut len = None;
+        let len_read = bytes_read(|| len = db.value_len("key3").unwrap());
+        assert_eq!(len, Some(100_003));
+        let get_read = bytes_read(|| assert!(db.get("key3").unwrap().is_some()));
+        if format == SegmentFormat::Columns {
+            // Only the key column is read.
+            assert!(len_read * 10 < get_read, "{} {}", len_read, get_read);
+        } else {
+            assert!(len_read <= get_read, "{} {}", len_read, get_read);
+        }
+    }
+}
+
+#[test]
+fn a_larger_read_buffer_takes_fewer_reads_for_a_scan() {
+    let _serial = serial();
+    let mut calls = Vec::new();
+    for size in [4 * 1024, 1024 * 1024] {
+        let dir = TempDir::new("read-buffer");
+        let mut db = quiet().read_buffer_size(size).open(dir.path()).unwrap();
+        for i in 0..20_000 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        calls.push(read_calls(|| {
+            assert_eq!(db.range::<str, _>(..).unwrap().count(), 20_000);
+        }));
+    }
+    assert!(calls[1] * 4 < calls[0], "read calls: {:?}", calls);
+}
+
+#[test]
+fn get_prefetch_reads_the_following_blocks_on_a_single_task() {
+    let _serial = serial();
+    let dir = TempDir::new("prefetch");
+    let mut db = quiet().block_size(4096).open(dir.path()).unwrap();
+    for i in 0..20_000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let (key, value) = entry(5000);
+    let get = bytes_read(|| {
+        db.get(&key).unwrap().unwrap();
+    });
+
+    let start = io_stat("rchar");
+    let found = db.get_prefetch(&key, 16).unwrap().unwrap();
+    assert_eq!(found.as_ref(), value.as_bytes());
+    // The blocks are read after the lookup returns.
+    let prefetched = || io_stat("rchar") - start - get;
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while prefetched() < 16 * 4096 && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(
+        prefetched() >= 16 * 4096,
+        "{} bytes prefetched",
+        prefetched()
+    );
+
+    let threads = || std::fs::read_dir("/proc/self/task").unwrap().count();
+    let before = threads();
+    for i in 0..100 {
+        db.get_prefetch(&entry(i * 100).0, 4).unwrap();
+    }
+    assert!(threads() <= before);
+}
+
+#[test]
+fn the_value_cache_reads_a_segment_key_only_once() {
+    let _serial = serial();
+    let dir = TempDir::new("value-cache");
+    let mut db = quiet().value_cache_entries(16).open(dir.path()).unwrap();
+    for i in 0..1000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let (key, value) = entry(500);
+    let first = bytes_read(|| {
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    });
+    let repeated = bytes_read(|| {
+        for _ in 0..100 {
+            let (found, stats) = db.get_with_stats(&key).unwrap();
+            assert_eq!(found.unwrap().as_ref(), value.as_bytes());
+            assert!(stats.cache_hit);
+            assert_eq!(stats.bytes_read, 0);
+        }
+    });
+    // The repeated lookups read nothing but the statistics of the process.
+    assert!(repeated < first, "{} against {}", repeated, first);
+
+    // A write of the key, and a compaction, leave the cache.
+    db.set(key.clone(), "newer").unwrap();
+    assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), &b"newer"[..]);
+    db.flush().unwrap();
+    db.compact().unwrap();
+    let (found, stats) = db.get_with_stats(&key).unwrap();
+    assert_eq!(found.unwrap().as_ref(), &b"newer"[..]);
+    assert!(!stats.cache_hit);
+    assert!(db.get_with_stats(&key).unwrap().1.cache_hit);
+    db.delete(key.clone()).unwrap();
+    assert!(db.get(&key).unwrap().is_none());
+}
@@ -0,0 +1,260 @@
+mod common;
+
+use bytes::Bytes;
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{DatabaseBuilder, Get, Map};
+use std::time::{Duration, Instant};
+
+#[test]
+fn segment_count_stabilizes_under_continuous_writes() {
+    let dir = TempDir::new("merge-steady");
+    let mut db = DatabaseBuilder::default()
+        .sync_flush(true)
+        .merge_period(Duration::from_millis(1))
+        .poll_period(Duration::from_millis(1))
+        .max_merge_segments(4)
+        .open(dir.path())
+        .unwrap();
+    let mut counts = Vec::new();
+    for round in 0..200 {
+        for i in 0..20 {
+            let (key, value) = entry(round * 20 + i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        counts.push(segment_ids(&db).len());
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    // Without merges there would be a segment per round.
+    let late = counts[100..].iter().max().unwrap();
+    assert!(*late < 50, "segment counts: {:?}", counts);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while segment_ids(&db).len() > 4 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(segment_ids(&db).len() <= 4);
+    for i in (0..4000).step_by(97) {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn max_segments_is_never_exceeded() {
+    let dir = TempDir::new("max-segments");
+    let mut db = quiet().max_segments(3).open(dir.path()).unwrap();
+    for round in 0..20 {
+        for i in 0..10 {
+            let (key, value) = entry(round * 10 + i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        assert!(segment_ids(&db).len() <= 3);
+    }
+    for i in 0..200 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn without_auto_merge_only_compact_merges() {
+    let dir = TempDir::new("no-auto-merge");
+    let mut db = DatabaseBuilder::default()
+        .sync_flush(true)
+        .auto_merge(false)
+        .merge_period(Duration::from_millis(1))
+        .poll_period(Duration::from_millis(1))
+        .open(dir.path())
+        .unwrap();
+    for round in 0..4 {
+        let (key, value) = entry(round);
+        db.set(key, value).unwrap();
+        db.flush().unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(segment_ids(&db).len(), 4);
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+    for round in 0..4 {
+        let (key, value) = entry(round);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn a_lone_segment_of_mostly_tombstones_is_compacted_by_itself() {
+    let dir = TempDir::new("self-compact");
+    let mut db = DatabaseBuilder::default()
+        .sync_flush(true)
+        .merge_period(Duration::from_millis(1))
+        .poll_period(Duration::from_millis(1))
+        .self_compact_tombstone_ratio(0.5)
+        .open(dir.path())
+        .unwrap();
+    for i in 0..10 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    for i in 10..100 {
+        db.delete(entry(i).0).unwrap();
+    }
+    db.flush().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let infos = loop {
+        let infos = db.segment_infos().unwrap();
+        if infos.iter().all(|info| info.tombstone_count == 0) || Instant::now() > deadline {
+            break infos;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0].tombstone_count, 0);
+    assert_eq!(infos[0].record_count, 10);
+    for i in 0..10 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn compact_older_than_merges_only_the_old_segments() {
+    let dir = TempDir::new("compact-older-than");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let write_segment = |db: &mut nouzdb::Database, segment: usize| {
+        for i in segment * 10..(segment + 1) * 10 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    };
+    write_segment(&mut db, 0);
+    write_segment(&mut db, 1);
+    // The creation times are kept in whole seconds.
+    std::thread::sleep(Duration::from_millis(2100));
+    write_segment(&mut db, 2);
+    write_segment(&mut db, 3);
+    let ids = segment_ids(&db);
+
+    db.compact_older_than(Duration::from_secs(1)).unwrap();
+    let merged = segment_ids(&db);
+    assert_eq!(merged.len(), 3);
+    assert_eq!(merged[..2], ids[2..]);
+    assert!(merged[2] > ids[3]);
+    let infos = db.segment_infos().unwrap();
+    assert_eq!(infos[2].record_count, 20);
+    for i in 0..40 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+
+    // The merged segment has the newest id but the oldest data, so it is dropped first.
+    assert_eq!(db.drop_oldest_segments(1).unwrap(), 1);
+    assert_eq!(segment_ids(&db), ids[2..]);
+    assert!(db.get(&entry(0).0).unwrap().is_none());
+    assert!(db.get(&entry(20).0).unwrap().is_some());
+}
+
+#[test]
+fn compact_older_than_keeps_a_newer_ingest_winning_the_tie() {
+    let dir = TempDir::new("compact-older-than-ingest");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.flush().unwrap();
+    db.set("k", "v1").unwrap();
+    db.flush().unwrap();
+    std::thread::sleep(Duration::from_millis(2100));
+    // The ingested entry takes the largest sequence number of the segments, that of "k".
+    db.ingest_sorted([(Bytes::from("k"), Bytes::from("v2"))])
+        .unwrap();
+    let ids = segment_ids(&db);
+
+    db.compact_older_than(Duration::from_millis(1500)).unwrap();
+    let merged = segment_ids(&db);
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged[0], ids[2]);
+    assert_eq!(db.get("k").unwrap().unwrap().as_ref(), &b"v2"[..]);
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"1"[..]);
+    let entries: Vec<_> = db
+        .range::<str, _>(..)
+        .unwrap()
+        .map(|entry| {
+            let (key, value) = entry.unwrap();
+            (key, value.as_ref().clone())
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            (Bytes::from("a"), Bytes::from("1")),
+            (Bytes::from("k"), Bytes::from("v2"))
+        ]
+    );
+}
+
+#[test]
+fn keys_encoded_in_reverse_merge_into_descending_order() {
+    // Keys are ordered by their bytes, so descending timestamps are stored inverted.
+    let key = |ts: u64| (u64::MAX - ts).to_be_bytes().to_vec();
+    let ts_of = |key: &[u8]| u64::MAX - u64::from_be_bytes(key.try_into().unwrap());
+    let dir = TempDir::new("descending-merge");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for ts in (0..100).step_by(2) {
+        db.set(key(ts), format!("old-{}", ts)).unwrap();
+    }
+    db.flush().unwrap();
+    for ts in (0..100).step_by(3) {
+        db.set(key(ts), format!("new-{}", ts)).unwrap();
+    }
+    db.flush().unwrap();
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+
+    let entries: Vec<(u64, String)> = db
+        .range::<[u8], _>(..)
+        .unwrap()
+        .map(|entry| {
+            let (key, value) = entry.unwrap();
+            let value = String::from_utf8(value.as_ref().to_vec()).unwrap();
+            (ts_of(&key), value)
+        })
+        .collect();
+    let expected: Vec<(u64, String)> = (0..100)
+        .rev()
+        .filter(|ts| ts % 2 == 0 || ts % 3 == 0)
+        .map(|ts| {
+            let version = if ts % 3 == 0 { "new" } else { "old" };
+            (ts, format!("{}-{}", version, ts))
+        })
+        .collect();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn merges_of_the_same_segments_write_the_same_bytes() {
+    // The bytes of the merged segment, without the footer holding the creation time.
+    let merged = |name: &str| {
+        let dir = TempDir::new(name);
+        let mut db = quiet().open(dir.path()).unwrap();
+        for segment in 0..4 {
+            for i in (segment * 500)..(segment * 500 + 1000) {
+                let (key, value) = entry(i);
+                db.set(key, value.repeat(4)).unwrap();
+            }
+            db.delete(entry(segment * 7).0).unwrap();
+            db.flush().unwrap();
+        }
+        db.compact().unwrap();
+        let (_, path) = db.segment_paths().remove(0);
+        let mut data = std::fs::read(path).unwrap();
+        let end = data[..data.len() - 1]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .unwrap();
+        data.truncate(end + 1);
+        data
+    };
+    let first = merged("merge-bytes-first");
+    assert!(first.len() > 2500 * 40);
+    assert_eq!(first, merged("merge-bytes-second"));
+}
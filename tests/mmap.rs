@@ -0,0 +1,60 @@
+//! Tests of the memory-mapped segments, which need the `mmap` feature.
+#![cfg(feature = "mmap")]
+
+mod common;
+
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{DatabaseHandle, Get};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+#[test]
+fn mapped_segments_are_read_while_they_are_merged_away() {
+    let dir = TempDir::new("mmap");
+    let db = DatabaseHandle::from(quiet().mmap_segments(true).open(dir.path()).unwrap());
+    for segment in 0..5 {
+        for i in segment * 500..(segment + 1) * 500 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    // A scan holds the mapped segments through the merges.
+    let mut scan = db.range::<str, _>(..).unwrap();
+    let first = scan.next().unwrap().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..2)
+        .map(|reader| {
+            let db = db.read_handle();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    for i in (reader..2500).step_by(7) {
+                        let (key, value) = entry(i);
+                        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+                    }
+                }
+            })
+        })
+        .collect();
+    for round in 0..5 {
+        let (key, value) = entry(round);
+        db.set(key, value).unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(segment_ids(&db).len(), 1);
+    assert_eq!(first.0, entry(0).0);
+    assert_eq!(scan.count(), 2499);
+    // The merged files are unmapped and removed once the scan is gone.
+    let data = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("data".as_ref()))
+        .count();
+    assert_eq!(data, 1);
+}
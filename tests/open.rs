@@ -0,0 +1,162 @@
+mod common;
+
+use common::{copy_files, entry, quiet, segment_ids, TempDir};
+use nouzdb::{Error, Get, Map};
+
+#[test]
+fn next_segment_id_follows_the_largest_restored_id() {
+    let dir = TempDir::new("max-segment-id");
+    for id in [4, 8] {
+        let mut db = quiet().max_segment_id(id).open(dir.path()).unwrap();
+        db.set(format!("key{}", id), "value").unwrap();
+        db.flush().unwrap();
+    }
+    let mut db = quiet().open(dir.path()).unwrap();
+    assert_eq!(segment_ids(&db), vec![5, 9]);
+    db.set("key", "value").unwrap();
+    db.flush().unwrap();
+    assert_eq!(segment_ids(&db), vec![5, 9, 10]);
+}
+
+#[test]
+fn max_segment_id_below_an_existing_segment_fails() {
+    let dir = TempDir::new("max-segment-id-below");
+    {
+        let mut db = quiet().max_segment_id(8).open(dir.path()).unwrap();
+        db.set("key", "value").unwrap();
+        db.flush().unwrap();
+    }
+    assert!(matches!(
+        quiet().max_segment_id(5).open(dir.path()),
+        Err(nouzdb::Error::InvalidMaxSegmentId {
+            given: 5,
+            existing: 9
+        })
+    ));
+}
+
+/// Write the entries to a fresh database flushed into a single segment, returning the
+/// path of the segment file.
+fn segment_of(dir: &TempDir, writes: Vec<(String, String)>) -> std::path::PathBuf {
+    let mut db = quiet().open(dir.path()).unwrap();
+    for (key, value) in writes {
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    db.segment_paths().remove(0).1
+}
+
+#[test]
+fn higher_sequence_number_wins_over_a_newer_segment_id() {
+    let old = TempDir::new("restore-old");
+    let old = segment_of(&old, vec![("key".to_string(), "old".to_string())]);
+    // The newer write has the higher sequence number, after many other writes.
+    let new = TempDir::new("restore-new");
+    let mut writes: Vec<(String, String)> = (0..50).map(entry).collect();
+    writes.push(("key".to_string(), "new".to_string()));
+    let new = segment_of(&new, writes);
+    // Restored with the ids the other way around.
+    let dir = TempDir::new("restore-swapped");
+    std::fs::copy(&new, dir.join("1.data")).unwrap();
+    std::fs::copy(&old, dir.join("7.data")).unwrap();
+    let mut db = quiet().open(dir.path()).unwrap();
+    assert_eq!(segment_ids(&db), vec![1, 7]);
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"new"[..]);
+    db.compact().unwrap();
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"new"[..]);
+    // New writes still win.
+    db.set("key", "newer").unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"newer"[..]);
+}
+
+#[test]
+fn ids_continue_above_gaps_and_leftover_temporary_files() {
+    let dir = TempDir::new("gapped-ids");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        let (key, value) = entry(round);
+        db.set(key, value).unwrap();
+        db.flush().unwrap();
+    }
+    let ids = segment_ids(&db);
+    drop(db);
+    // The middle segment went missing, and a later write never completed.
+    std::fs::remove_file(dir.join(&format!("{}.data", ids[1]))).unwrap();
+    let unfinished = dir.join(&format!("{}.tmp", ids[2] + 4));
+    std::fs::write(&unfinished, "partial").unwrap();
+    let mut db = quiet().open(dir.path()).unwrap();
+    assert_eq!(segment_ids(&db), vec![ids[0], ids[2]]);
+    assert!(!unfinished.exists());
+    db.set("new", "value").unwrap();
+    db.flush().unwrap();
+    assert_eq!(segment_ids(&db), vec![ids[0], ids[2], ids[2] + 5]);
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db), vec![ids[2] + 6]);
+    assert_eq!(db.get("new").unwrap().unwrap().as_ref(), &b"value"[..]);
+    assert_eq!(
+        db.get(&entry(2).0).unwrap().unwrap().as_ref(),
+        entry(2).1.as_bytes()
+    );
+}
+
+#[test]
+fn a_stale_lock_file_does_not_block_a_reopen() {
+    let dir = TempDir::new("stale-lock");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    // A second database is kept out while the first one holds the folder.
+    assert!(matches!(
+        quiet().open(dir.path()),
+        Err(Error::Locked(path)) if path == dir.join("LOCK")
+    ));
+    // A crashed process leaves its lock file behind without holding it.
+    let crashed = TempDir::new("stale-lock-crashed");
+    copy_files(dir.path(), crashed.path());
+    std::fs::write(crashed.join("LOCK"), "").unwrap();
+    let db = quiet().open(crashed.path()).unwrap();
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"1"[..]);
+}
+
+#[test]
+fn a_merge_cut_short_by_a_crash_leaves_the_original_segments() {
+    let dir = TempDir::new("crash-mid-merge");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        for i in (round * 50)..(round * 50 + 100) {
+            let (key, value) = entry(i);
+            db.set(key, format!("{}-{}", value, round)).unwrap();
+        }
+        db.delete(entry(round).0).unwrap();
+        db.flush().unwrap();
+    }
+    let ids = segment_ids(&db);
+    let crashed = TempDir::new("crash-mid-merge-crashed");
+    copy_files(dir.path(), crashed.path());
+    // Half of the merged segment, as a crash in the middle of writing it leaves it.
+    db.compact().unwrap();
+    let (merged_id, merged) = db.segment_paths().remove(0);
+    let data = std::fs::read(merged).unwrap();
+    let partial = crashed.join(&format!("{}.tmp", merged_id));
+    std::fs::write(&partial, &data[..data.len() / 2]).unwrap();
+
+    let db = quiet().open(crashed.path()).unwrap();
+    assert!(!partial.exists());
+    assert_eq!(segment_ids(&db), ids);
+    for i in 0..200 {
+        let (key, value) = entry(i);
+        let round = (i / 50).min(2);
+        let found = db.get(&key).unwrap();
+        if i < 3 {
+            assert!(found.is_none(), "{}", key);
+        } else {
+            let expected = format!("{}-{}", value, round);
+            assert_eq!(found.unwrap().as_ref(), expected.as_bytes(), "{}", key);
+        }
+    }
+    // The merge is done again from the originals.
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+    assert!(segment_ids(&db)[0] > ids[2]);
+    assert_eq!(db.range::<str, _>(..).unwrap().count(), 197);
+}
@@ -0,0 +1,332 @@
+mod common;
+
+use bytes::Bytes;
+use common::{quiet, TempDir};
+use nouzdb::database::{ReadOrder, SegmentFormat};
+use nouzdb::{Get, Map, MapError, ValueResolver};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[test]
+fn count_prefix_counts_distinct_live_keys_across_memtable_and_segments() {
+    let dir = TempDir::new("count-prefix");
+    let mut db = quiet().open(dir.path()).unwrap();
+    // The first segment.
+    for key in ["user:1", "user:2", "user:3", "usex:1", "admin:1"] {
+        db.set(key, "a").unwrap();
+    }
+    db.flush().unwrap();
+    // The second segment overwrites one key and deletes another.
+    db.set("user:2", "b").unwrap();
+    db.delete("user:3").unwrap();
+    db.set("user:4", "b").unwrap();
+    db.flush().unwrap();
+    // The memtable overwrites, revives and adds keys.
+    db.set("user:1", "c").unwrap();
+    db.set("user:3", "c").unwrap();
+    db.set("user:5", "c").unwrap();
+    db.delete("user:4").unwrap();
+    assert_eq!(db.segment_infos().unwrap().len(), 2);
+    // user:1, user:2, user:3 and user:5 are live.
+    assert_eq!(db.count_prefix("user:").unwrap(), 4);
+    assert_eq!(db.count_prefix("us").unwrap(), 5);
+    assert_eq!(db.count_prefix("").unwrap(), 6);
+    assert_eq!(db.count_prefix("none").unwrap(), 0);
+}
+
+/// A resolver of the values kept in a map, by their references.
+struct MapResolver(HashMap<Vec<u8>, Bytes>);
+
+impl ValueResolver for MapResolver {
+    fn resolve(&self, reference: &[u8]) -> Result<Bytes, MapError> {
+        self.0
+            .get(reference)
+            .cloned()
+            .ok_or_else(|| MapError::Resolve(String::from_utf8_lossy(reference).into_owned()))
+    }
+}
+
+#[test]
+fn get_returns_the_values_resolved_from_the_stored_references() {
+    let dir = TempDir::new("value-resolver");
+    let mut values = HashMap::new();
+    values.insert(b"ref:1".to_vec(), Bytes::from_static(b"the first value"));
+    values.insert(b"ref:2".to_vec(), Bytes::from_static(b"the second value"));
+    let mut db = quiet()
+        .value_resolver(Arc::new(MapResolver(values)))
+        .open(dir.path())
+        .unwrap();
+    db.set("a", "ref:1").unwrap();
+    db.flush().unwrap();
+    db.set("b", "ref:2").unwrap();
+    db.set("c", "ref:3").unwrap();
+    // From a segment and from the memtable.
+    assert_eq!(
+        db.get("a").unwrap().unwrap().as_ref(),
+        &b"the first value"[..]
+    );
+    assert_eq!(
+        db.get("b").unwrap().unwrap().as_ref(),
+        &b"the second value"[..]
+    );
+    assert!(db.get("missing").unwrap().is_none());
+    assert!(matches!(db.get("c"), Err(MapError::Resolve(_))));
+}
+
+#[test]
+fn scan_glob_returns_the_keys_matching_the_pattern() {
+    let dir = TempDir::new("scan-glob");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for key in ["user:1:name", "user:2:mail", "user:10:name", "usr:3:name"] {
+        db.set(key, key).unwrap();
+    }
+    db.flush().unwrap();
+    db.set("user:2:name", "user:2:name").unwrap();
+    db.set("user:3:names", "user:3:names").unwrap();
+    db.set("admin:1:name", "admin:1:name").unwrap();
+    let keys = |pattern: &str| -> Vec<Bytes> {
+        db.scan_glob(pattern)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect()
+    };
+    assert_eq!(
+        keys("user:*:name"),
+        ["user:10:name", "user:1:name", "user:2:name"]
+    );
+    assert_eq!(keys("user:?:name"), ["user:1:name", "user:2:name"]);
+    assert_eq!(keys("*:1:*"), ["admin:1:name", "user:1:name"]);
+    assert_eq!(keys("user:2:mail"), ["user:2:mail"]);
+    assert!(keys("user:*:phone").is_empty());
+}
+
+#[test]
+fn memtable_wins_over_segments_by_default() {
+    let dir = TempDir::new("read-order-default");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("key", "segment").unwrap();
+    db.flush().unwrap();
+    db.set("key", "memtable").unwrap();
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"memtable"[..]);
+    db.delete("key").unwrap();
+    assert!(db.get("key").unwrap().is_none());
+}
+
+#[test]
+fn segments_win_over_the_memtable_when_read_first() {
+    let dir = TempDir::new("read-order-segments");
+    let mut db = quiet()
+        .read_order(ReadOrder::SegmentsFirst)
+        .open(dir.path())
+        .unwrap();
+    db.set("key", "segment").unwrap();
+    db.flush().unwrap();
+    db.set("key", "memtable").unwrap();
+    db.set("other", "memtable").unwrap();
+    assert_eq!(db.get("key").unwrap().unwrap().as_ref(), &b"segment"[..]);
+    // Keys only in the memtable are still found.
+    assert_eq!(db.get("other").unwrap().unwrap().as_ref(), &b"memtable"[..]);
+}
+
+#[test]
+fn empty_values_round_trip_unlike_deletes() {
+    for format in [SegmentFormat::Rows, SegmentFormat::Columns] {
+        let dir = TempDir::new("empty-value");
+        let mut db = quiet().segment_format(format).open(dir.path()).unwrap();
+        db.set("empty", "").unwrap();
+        db.set("deleted", "x").unwrap();
+        db.delete("deleted").unwrap();
+        assert_eq!(db.get("empty").unwrap().unwrap().as_ref(), &b""[..]);
+        db.flush().unwrap();
+        assert_eq!(db.get("empty").unwrap().unwrap().as_ref(), &b""[..]);
+        assert!(db.get("deleted").unwrap().is_none());
+        db.set("other", "y").unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get("empty").unwrap().unwrap().as_ref(), &b""[..]);
+        assert!(db.get("deleted").unwrap().is_none());
+        drop(db);
+        let db = quiet().open(dir.path()).unwrap();
+        assert_eq!(db.get("empty").unwrap().unwrap().as_ref(), &b""[..]);
+        let keys: Vec<_> = db.keys::<str, _>(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(keys, ["empty", "other"]);
+    }
+}
+
+#[test]
+fn get_or_falls_back_to_the_default_without_writing_it() {
+    let dir = TempDir::new("get-or");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("stored", "value").unwrap();
+    db.set("deleted", "value").unwrap();
+    db.flush().unwrap();
+    db.delete("deleted").unwrap();
+    let stored = db.get_or("stored", || panic!("the key is stored")).unwrap();
+    assert_eq!(stored.as_ref(), &b"value"[..]);
+    for key in ["missing", "deleted"] {
+        let value = db.get_or(key, || Bytes::from("default")).unwrap();
+        assert_eq!(value.as_ref(), &b"default"[..]);
+        assert!(db.get(key).unwrap().is_none());
+    }
+}
+
+#[test]
+fn values_compare_with_byte_slices_and_arrays() {
+    let dir = TempDir::new("value");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("hello", "world").unwrap();
+    let value = db.get_value("hello").unwrap().unwrap();
+    assert!(value == b"world");
+    assert!(value == *b"world");
+    assert!(value == b"world"[..]);
+    let slice: &[u8] = b"world";
+    assert!(value == slice);
+    assert!(value != b"other");
+    assert_eq!(&value[..2], b"wo");
+    assert_eq!(value.len(), 5);
+    assert_eq!(value.as_ref(), b"world");
+    assert_eq!(value.into_inner().as_ref(), &Bytes::from("world"));
+    assert!(db.get_value("missing").unwrap().is_none());
+}
+
+#[test]
+fn every_form_of_a_key_finds_the_same_entry() {
+    let dir = TempDir::new("key-forms");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set(String::from("k"), "from a string").unwrap();
+    db.set(vec![0xff, 0x00], "from bytes").unwrap();
+    db.flush().unwrap();
+    db.set(&b"memtable"[..], "from a slice").unwrap();
+    for expected in [("k", "from a string"), ("memtable", "from a slice")] {
+        let (key, value) = expected;
+        let value = value.as_bytes();
+        assert_eq!(db.get(key).unwrap().unwrap().as_ref(), value);
+        assert_eq!(db.get(key.as_bytes()).unwrap().unwrap().as_ref(), value);
+        assert_eq!(db.get(&key.to_string()).unwrap().unwrap().as_ref(), value);
+        assert_eq!(
+            db.get(&key.as_bytes().to_vec()).unwrap().unwrap().as_ref(),
+            value
+        );
+        assert_eq!(db.get(&Bytes::from(key)).unwrap().unwrap().as_ref(), value);
+    }
+    assert_eq!(
+        db.get(b"k").unwrap().unwrap().as_ref(),
+        &b"from a string"[..]
+    );
+    assert_eq!(
+        db.get(&[0xff, 0x00]).unwrap().unwrap().as_ref(),
+        &b"from bytes"[..]
+    );
+    assert_eq!(
+        db.get(&vec![0xff, 0x00]).unwrap().unwrap().as_ref(),
+        &b"from bytes"[..]
+    );
+    assert!(db.get(&[0xff]).unwrap().is_none());
+}
+
+#[test]
+fn estimate_range_count_is_close_to_the_true_count() {
+    let dir = TempDir::new("estimate-range");
+    let mut db = quiet().block_size(256).open(dir.path()).unwrap();
+    for i in 0..10_000 {
+        db.set(format!("key{:05}", i), "value").unwrap();
+        if i % 2500 == 2499 {
+            db.flush().unwrap();
+        }
+    }
+    // Some in the memtable as well.
+    for i in 10_000..10_500 {
+        db.set(format!("key{:05}", i), "value").unwrap();
+    }
+    for (start, end) in [(0, 10_500), (1234, 5678), (9_900, 10_200), (3000, 3100)] {
+        let (start, end) = (format!("key{:05}", start), format!("key{:05}", end));
+        let count = db.range(start.as_str()..end.as_str()).unwrap().count();
+        let estimate = db.estimate_range_count(&start, &end).unwrap();
+        assert!(
+            estimate * 2 >= count && estimate <= count * 2,
+            "{}..{}: {} estimated for {}",
+            start,
+            end,
+            estimate,
+            count
+        );
+    }
+    assert_eq!(db.estimate_range_count("a", "b").unwrap(), 0);
+}
+
+#[test]
+fn inserted_keys_are_members_across_a_flush() {
+    let dir = TempDir::new("insert-key");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..100 {
+        assert!(db.insert_key(format!("key{:03}", i)).unwrap());
+    }
+    assert!(!db.insert_key("key007").unwrap());
+    db.flush().unwrap();
+    for i in 0..100 {
+        let key = format!("key{:03}", i);
+        assert!(db.get(&key).unwrap().unwrap().is_empty());
+        assert!(!db.insert_key(key).unwrap());
+    }
+    assert!(db.get("key100").unwrap().is_none());
+    db.delete("key042").unwrap();
+    assert!(db.insert_key("key042").unwrap());
+}
+
+#[test]
+fn read_stats_tell_the_memtable_hits_from_the_segment_hits() {
+    let dir = TempDir::new("read-stats");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        db.set(format!("round{}", round), "1").unwrap();
+        db.flush().unwrap();
+    }
+    db.set("active", "1").unwrap();
+
+    let (found, stats) = db.get_with_stats("active").unwrap();
+    assert!(found.is_some());
+    assert!(stats.active_hit && !stats.frozen_hit && !stats.cache_hit);
+    assert_eq!((stats.segments_examined, stats.bytes_read), (0, 0));
+    // The oldest segments hold no entry newer than the one found in the newest.
+    let (found, stats) = db.get_with_stats("round2").unwrap();
+    assert!(found.is_some());
+    assert!(!stats.active_hit && !stats.frozen_hit);
+    assert_eq!(stats.segments_examined, 1);
+    assert!(stats.bytes_read > 0);
+    for key in ["round0", "missing"] {
+        let (found, stats) = db.get_with_stats(key).unwrap();
+        assert_eq!(found.is_some(), key == "round0");
+        assert!(!stats.active_hit);
+        assert_eq!(stats.segments_examined, 3, "{}", key);
+    }
+}
+
+#[test]
+fn get_or_reserve_hands_back_the_allocation_of_the_key() {
+    use nouzdb::GetOrReserve;
+
+    let dir = TempDir::new("get-or-reserve");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let key = Bytes::from(format!("{:0>1024}", "counted"));
+    let ptr = key.as_ptr();
+    let key = match db.get_or_reserve(key).unwrap() {
+        GetOrReserve::Vacant(key) => key,
+        found => panic!("{:?}", found),
+    };
+    assert_eq!(key.as_ptr(), ptr);
+    db.set(key.clone(), "1").unwrap();
+    db.flush().unwrap();
+    match db.get_or_reserve(key).unwrap() {
+        GetOrReserve::Found { key, value } => {
+            assert_eq!(key.as_ptr(), ptr);
+            assert_eq!(value.as_ref(), &b"1"[..]);
+        }
+        vacant => panic!("{:?}", vacant),
+    }
+    // A shared reference is enough, as for `get`.
+    let db = &db;
+    assert!(matches!(
+        db.get_or_reserve(Bytes::from("missing")).unwrap(),
+        GetOrReserve::Vacant(key) if key == "missing"
+    ));
+}
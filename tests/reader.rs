@@ -0,0 +1,135 @@
+mod common;
+
+use bytes::Bytes;
+use common::{copy_files, entry, quiet, segment_ids, TempDir};
+use nouzdb::{inspect_directory, read_all_records, Get, Map};
+
+#[test]
+fn segment_set_reader_answers_like_the_flushed_database() {
+    let dir = TempDir::new("segment-set-reader");
+    let mut db = quiet().pack_segments(3).open(dir.path()).unwrap();
+    for round in 0..4 {
+        for i in (round * 50)..(round * 50 + 100) {
+            let (key, value) = entry(i);
+            db.set(key, format!("{}-{}", value, round)).unwrap();
+        }
+        db.delete(entry(round * 10).0).unwrap();
+        db.flush().unwrap();
+    }
+    db.set("unflushed", "x").unwrap();
+    // Three of the segments are in a pack.
+    assert!(std::fs::read_dir(dir.path())
+        .unwrap()
+        .any(|entry| entry.unwrap().path().extension() == Some("pack".as_ref())));
+    let reader = quiet().open_reader(dir.path()).unwrap();
+    assert_eq!(reader.len(), 4);
+    for i in 0..260 {
+        let (key, _) = entry(i);
+        assert_eq!(reader.get(&key).unwrap(), db.get(&key).unwrap(), "{}", key);
+    }
+    // Only the segments are read.
+    assert!(reader.get("unflushed").unwrap().is_none());
+    assert!(db.get("unflushed").unwrap().is_some());
+}
+
+#[test]
+fn read_all_records_rejects_unordered_or_malformed_segments() {
+    let dir = TempDir::new("read-all-records");
+    let path = dir.join("1.data");
+    std::fs::write(&path, "a,1,1\nb,,\0tombstone,2\nc,3,3\n").unwrap();
+    let records: Vec<_> = read_all_records(&path)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        records,
+        [("a", "1"), ("c", "3")].map(|(key, value)| (Bytes::from(key), Bytes::from(value)))
+    );
+    for data in ["b,2,2\na,1,1\n", "a,1,1\nno fields\n"] {
+        std::fs::write(&path, data).unwrap();
+        let err = read_all_records(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", data);
+    }
+    assert_eq!(
+        read_all_records(&dir.join("missing.data"))
+            .err()
+            .unwrap()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn inspect_directory_reports_the_segments_of_a_closed_database() {
+    let dir = TempDir::new("inspect-directory");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        for i in (round * 100)..(round * 100 + 150) {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.delete(format!("a{}", round)).unwrap();
+        db.flush().unwrap();
+    }
+    let expected = db.segment_infos().unwrap();
+    drop(db);
+    // A segment written before footers, whose statistics are found by a scan.
+    std::fs::write(dir.join("9.data"), "b,1,1000\nc,,\0tombstone,1001\n").unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a segment").unwrap();
+
+    let infos = inspect_directory(&dir.path(), "data").unwrap();
+    assert_eq!(infos.len(), 4);
+    assert_eq!(infos[..3], expected[..]);
+    for (round, info) in infos.iter().take(3).enumerate() {
+        assert_eq!(info.record_count, 151);
+        assert_eq!(info.tombstone_count, 1);
+        assert_eq!(
+            info.min_key.as_deref(),
+            Some(format!("a{}", round).as_bytes())
+        );
+        let max = entry(round * 100 + 149).0;
+        assert_eq!(info.max_key.as_deref(), Some(max.as_bytes()));
+        assert!(info.created_at.is_some());
+    }
+    let old = &infos[3];
+    assert_eq!((old.id, old.record_count, old.tombstone_count), (9, 2, 1));
+    assert_eq!(old.min_key.as_deref(), Some(&b"b"[..]));
+    assert_eq!(old.max_key.as_deref(), Some(&b"c"[..]));
+    assert_eq!(old.created_at, None);
+}
+
+#[test]
+fn segments_replaced_by_a_later_one_are_left_out_everywhere() {
+    let dir = TempDir::new("replaced-segments");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..2 {
+        for i in (round * 100)..(round * 100 + 100) {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    // A stop in the middle of the replace leaves the new segment with the old ones.
+    let crashed = TempDir::new("replaced-segments-crashed");
+    copy_files(dir.path(), crashed.path());
+    let replacement = (0..50).map(|i| (Bytes::from(entry(i).0), Bytes::from("new")));
+    db.replace_all(replacement).unwrap();
+    let (id, path) = db.segment_paths().remove(0);
+    std::fs::copy(path, crashed.join(&format!("{}.data", id))).unwrap();
+    drop(db);
+
+    let infos = inspect_directory(&crashed.path(), "data").unwrap();
+    assert_eq!(infos.len(), 1);
+    assert_eq!((infos[0].id, infos[0].record_count), (id, 50));
+    let reader = quiet().open_reader(crashed.path()).unwrap();
+    assert_eq!(reader.len(), 1);
+    assert_eq!(
+        reader.get(&entry(10).0).unwrap().unwrap().as_ref(),
+        &b"new"[..]
+    );
+    assert!(reader.get(&entry(150).0).unwrap().is_none());
+    drop(reader);
+    let db = quiet().open(crashed.path()).unwrap();
+    assert_eq!(segment_ids(&db), [id]);
+    assert!(db.get(&entry(150).0).unwrap().is_none());
+}
@@ -0,0 +1,239 @@
+mod common;
+
+use common::{copy_files, entry, quiet, segment_ids, TempDir};
+use nouzdb::{Error, Get, Map};
+use std::time::Duration;
+
+/// A folder left by a crash after `count` logged writes, with all of them in the log.
+fn crashed_with_log(name: &str, count: usize) -> TempDir {
+    let dir = TempDir::new(name);
+    let mut db = quiet()
+        .switch_mem_size(64 * 1024 * 1024)
+        .open(dir.path())
+        .unwrap();
+    for i in 0..count {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    let crashed = TempDir::new(&format!("{}-crashed", name));
+    copy_files(dir.path(), crashed.path());
+    crashed
+}
+
+#[test]
+fn replay_past_the_recovery_timeout_fails() {
+    let dir = crashed_with_log("recovery-timeout", 20_000);
+    let res = quiet()
+        .recovery_timeout(Duration::from_nanos(1))
+        .open(dir.path());
+    assert!(matches!(res, Err(Error::RecoveryTimedOut)));
+    // The log is left for a later open.
+    let db = quiet()
+        .recovery_timeout(Duration::from_secs(60))
+        .open(dir.path())
+        .unwrap();
+    for i in (0..20_000).step_by(999) {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn a_log_left_by_a_crash_after_its_flush_is_not_replayed_again() {
+    let dir = TempDir::new("missing-finalize");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..100 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    // The log before the flush, and the segment after it, as a crash just after the
+    // segment is renamed into place leaves them.
+    let crashed = TempDir::new("missing-finalize-crashed");
+    copy_files(dir.path(), crashed.path());
+    let log = db.active_log_path();
+    db.flush().unwrap();
+    let (id, path) = db.segment_paths().remove(0);
+    std::fs::copy(&path, crashed.join(&format!("{}.data", id))).unwrap();
+    drop(db);
+
+    let mut db = quiet().open(crashed.path()).unwrap();
+    assert_eq!(segment_ids(&db), [id]);
+    assert!(!crashed.path().join(log.file_name().unwrap()).exists());
+    db.set("after", "crash").unwrap();
+    db.flush().unwrap();
+    let infos = db.segment_infos().unwrap();
+    assert_eq!(infos.len(), 2);
+    // Only the new write is in the new segment.
+    assert_eq!(infos[1].record_count, 1);
+    for i in 0..100 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+#[test]
+fn the_logs_reproduce_the_memtable() {
+    let dir = TempDir::new("verify-wal");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        for i in 0..100 {
+            let (key, value) = entry(i);
+            db.set(key, format!("{}-{}", value, round)).unwrap();
+        }
+        for i in (0..100).step_by(7) {
+            db.delete(entry(i + round).0).unwrap();
+        }
+    }
+    db.transaction(|txn| {
+        txn.set("txn", "1")?;
+        txn.delete(entry(1).0)
+    })
+    .unwrap();
+    assert!(db.verify_wal_matches_memtable().unwrap());
+
+    db.set_ephemeral("ephemeral", "1").unwrap();
+    assert!(!db.verify_wal_matches_memtable().unwrap());
+}
+
+#[test]
+fn logs_and_segments_land_in_their_own_folders() {
+    let dir = TempDir::new("log-dir");
+    let logs = dir.join("logs");
+    let data = dir.join("data");
+    let extensions = |path: &std::path::Path| {
+        let mut extensions: Vec<_> = std::fs::read_dir(path)
+            .unwrap()
+            .filter_map(|entry| {
+                let path = entry.unwrap().path();
+                path.extension()
+                    .map(|ext| ext.to_string_lossy().into_owned())
+            })
+            .collect();
+        extensions.sort();
+        extensions.dedup();
+        extensions
+    };
+    let mut builder = quiet();
+    builder.log_dir(&logs);
+    let mut db = builder.open(&data).unwrap();
+    db.set("flushed", "1").unwrap();
+    db.flush().unwrap();
+    db.set("logged", "2").unwrap();
+    assert_eq!(extensions(&logs), ["log"]);
+    assert_eq!(extensions(&data), ["data"]);
+
+    // A crash leaves the last write in the log folder only.
+    let crashed = TempDir::new("log-dir-crashed");
+    copy_files(&logs, &crashed.join("logs"));
+    copy_files(&data, &crashed.join("data"));
+    drop(db);
+    let db = quiet()
+        .log_dir(&crashed.join("logs"))
+        .open(&crashed.join("data"))
+        .unwrap();
+    assert_eq!(db.get("flushed").unwrap().unwrap().as_ref(), &b"1"[..]);
+    assert_eq!(db.get("logged").unwrap().unwrap().as_ref(), &b"2"[..]);
+}
+
+#[test]
+fn recover_writes_every_log_out_to_a_segment() {
+    let first = crashed_with_log("recover-first", 10);
+    let second = TempDir::new("recover-second");
+    let mut db = quiet().open(second.path()).unwrap();
+    for i in 10..25 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    let dir = TempDir::new("recover");
+    std::fs::copy(first.join("1.log"), dir.join("1.log")).unwrap();
+    std::fs::copy(second.join("1.log"), dir.join("2.log")).unwrap();
+    drop(db);
+    // A torn record at the end of the newest log.
+    let mut log = std::fs::read(dir.join("2.log")).unwrap();
+    log.extend_from_slice(b"torn,record\n");
+    std::fs::write(dir.join("2.log"), log).unwrap();
+
+    let report = quiet().recover(dir.path()).unwrap();
+    assert_eq!(report.keys_recovered, 25);
+    assert_eq!(report.segments_written, 2);
+    assert_eq!(report.records_skipped, 1);
+    assert!(!dir.join("1.log").exists() && !dir.join("2.log").exists());
+
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(segment_ids(&db).len(), 2);
+    for i in 0..25 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+}
+
+/// Replace the first `from` in the log of `dir` with `to`.
+fn rewrite_log(dir: &TempDir, from: &[u8], to: &[u8]) {
+    let path = dir.join("1.log");
+    let mut log = std::fs::read(&path).unwrap();
+    let at = log
+        .windows(from.len())
+        .position(|window| window == from)
+        .unwrap();
+    log[at..at + from.len()].copy_from_slice(to);
+    std::fs::write(path, log).unwrap();
+}
+
+#[test]
+fn strict_recovery_fails_on_a_corrupt_record_before_the_log_end() {
+    let dir = crashed_with_log("strict-recovery", 10);
+    rewrite_log(&dir, b"value00005", b"value0000X");
+    match quiet().strict_recovery(true).open(dir.path()) {
+        Err(Error::CorruptWal { path, offset }) => {
+            assert_eq!(path, dir.join("1.log"));
+            assert!(offset > 0);
+        }
+        res => panic!("{:?}", res.map(|_| ())),
+    }
+    // The log is left untouched, so a lenient open still recovers the records before it.
+    let db = quiet().open(dir.path()).unwrap();
+    for i in 0..10 {
+        let (key, value) = entry(i);
+        let found = db.get(&key).unwrap();
+        if i < 5 {
+            assert_eq!(found.unwrap().as_ref(), value.as_bytes());
+        } else {
+            assert!(found.is_none(), "{}", key);
+        }
+    }
+}
+
+#[test]
+fn strict_recovery_leaves_out_a_torn_tail() {
+    let dir = crashed_with_log("strict-recovery-torn", 10);
+    rewrite_log(&dir, b"value00009", b"value0000X");
+    let db = quiet().strict_recovery(true).open(dir.path()).unwrap();
+    for i in 0..9 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    assert!(db.get(&entry(9).0).unwrap().is_none());
+}
+
+#[test]
+fn ephemeral_writes_are_lost_by_a_crash_before_their_flush() {
+    let dir = TempDir::new("ephemeral");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("logged", "1").unwrap();
+    db.set_ephemeral("ephemeral", "1").unwrap();
+    db.set_ephemeral("logged", "2").unwrap();
+    assert_eq!(db.get("ephemeral").unwrap().unwrap().as_ref(), &b"1"[..]);
+    assert_eq!(db.get("logged").unwrap().unwrap().as_ref(), &b"2"[..]);
+    let crashed = TempDir::new("ephemeral-crashed");
+    copy_files(dir.path(), crashed.path());
+    db.flush().unwrap();
+    drop(db);
+
+    let db = quiet().open(crashed.path()).unwrap();
+    assert!(db.get("ephemeral").unwrap().is_none());
+    assert_eq!(db.get("logged").unwrap().unwrap().as_ref(), &b"1"[..]);
+    // Once flushed to a segment, they are kept like any other write.
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(db.get("ephemeral").unwrap().unwrap().as_ref(), &b"1"[..]);
+    assert_eq!(db.get("logged").unwrap().unwrap().as_ref(), &b"2"[..]);
+}
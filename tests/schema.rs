@@ -0,0 +1,154 @@
+mod common;
+
+use bytes::Bytes;
+use common::{quiet, TempDir};
+use nouzdb::{Get, KeySchema, Map, MapError, Validator};
+use std::sync::Arc;
+
+#[test]
+fn range_time_returns_the_entries_in_the_time_window() {
+    let dir = TempDir::new("range-time");
+    let mut db = quiet()
+        .key_schema(KeySchema::BigEndianU64)
+        .open(dir.path())
+        .unwrap();
+    for ts in (1_000..2_000u64).step_by(100) {
+        db.set(ts.to_be_bytes().to_vec(), ts.to_string()).unwrap();
+    }
+    db.flush().unwrap();
+    // Past 255 the big-endian bytes sort by value unlike the decimal strings.
+    db.set(256u64.to_be_bytes().to_vec(), "256").unwrap();
+    db.set(1_250u64.to_be_bytes().to_vec(), "1250").unwrap();
+    assert!(matches!(db.set("short", "x"), Err(MapError::KeyNotAllow)));
+    let window: Vec<String> = db
+        .range_time(1_200, 1_500)
+        .unwrap()
+        .map(|entry| String::from_utf8(entry.unwrap().1.to_vec()).unwrap())
+        .collect();
+    assert_eq!(window, ["1200", "1250", "1300", "1400"]);
+    let window: Vec<String> = db
+        .range_time(0, 1_100)
+        .unwrap()
+        .map(|entry| String::from_utf8(entry.unwrap().1.to_vec()).unwrap())
+        .collect();
+    assert_eq!(window, ["256", "1000"]);
+}
+
+#[test]
+fn range_time_needs_the_big_endian_schema() {
+    let dir = TempDir::new("range-time-any");
+    let db = quiet().open(dir.path()).unwrap();
+    assert!(matches!(db.range_time(0, 1), Err(MapError::KeyNotAllow)));
+}
+
+#[test]
+fn keys_are_normalized_before_they_are_stored() {
+    let dir = TempDir::new("normalizer");
+    let mut db = quiet()
+        .key_normalizer(Arc::new(|key: &[u8]| Bytes::from(key.to_ascii_lowercase())))
+        .open(dir.path())
+        .unwrap();
+    db.set("hello", "world").unwrap();
+    assert_eq!(db.get("HELLO").unwrap().unwrap().as_ref(), &b"world"[..]);
+    db.set("Hello", "again").unwrap();
+    db.flush().unwrap();
+    assert_eq!(db.get("hello").unwrap().unwrap().as_ref(), &b"again"[..]);
+    db.set("OTHER", "x").unwrap();
+    // Scans return the stored keys.
+    let keys: Vec<_> = db.keys::<str, _>(..).unwrap().map(Result::unwrap).collect();
+    assert_eq!(keys, ["hello", "other"]);
+    db.delete("HeLLo").unwrap();
+    assert!(db.get("hello").unwrap().is_none());
+}
+
+#[test]
+fn ingested_keys_are_normalized() {
+    let dir = TempDir::new("normalizer-ingest");
+    let mut db = quiet()
+        .key_normalizer(Arc::new(|key: &[u8]| Bytes::from(key.to_ascii_lowercase())))
+        .open(dir.path())
+        .unwrap();
+    db.ingest_sorted([(Bytes::from("Hello"), Bytes::from("world"))])
+        .unwrap();
+    assert_eq!(db.get("Hello").unwrap().unwrap().as_ref(), &b"world"[..]);
+    let keys: Vec<_> = db.keys::<str, _>(..).unwrap().map(Result::unwrap).collect();
+    assert_eq!(keys, ["hello"]);
+    // Sorted before normalizing, "B" < "a", but not after.
+    let entries = [
+        (Bytes::from("B"), Bytes::from("1")),
+        (Bytes::from("a"), Bytes::from("2")),
+    ];
+    assert!(db.ingest_sorted(entries).is_err());
+    assert!(db.get("a").unwrap().is_none());
+}
+
+/// Rejects the keys longer than the limit.
+struct MaxKeyLen(usize);
+
+impl Validator for MaxKeyLen {
+    fn validate(&self, key: &[u8], _value: &[u8]) -> Result<(), String> {
+        if key.len() > self.0 {
+            return Err(format!("key of {} bytes", key.len()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn a_rejected_write_leaves_no_trace() {
+    let dir = TempDir::new("validator");
+    let mut builder = quiet();
+    builder.validator(Arc::new(MaxKeyLen(8)));
+    let mut db = builder.open(dir.path()).unwrap();
+    db.set("short", "1").unwrap();
+    let log = db.active_log_path();
+    let log_len = std::fs::metadata(&log).unwrap().len();
+    assert!(matches!(
+        db.set("much too long", "2"),
+        Err(MapError::Rejected(reason)) if reason == "key of 13 bytes"
+    ));
+    let res = db.transaction(|txn| {
+        txn.set("fine", "3")?;
+        txn.set("also too long", "4")
+    });
+    assert!(matches!(res, Err(MapError::Rejected(_))));
+    assert_eq!(std::fs::metadata(&log).unwrap().len(), log_len);
+    assert!(db.get("much too long").unwrap().is_none());
+    assert!(db.get("fine").unwrap().is_none());
+    // Deletes are not validated.
+    db.delete("much too long").unwrap();
+    drop(db);
+
+    let db = builder.open(dir.path()).unwrap();
+    db.flush().unwrap();
+    let keys: Vec<_> = db
+        .range::<str, _>(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, [Bytes::from("short")]);
+}
+
+#[test]
+fn replaced_entries_are_normalized_and_checked() {
+    let dir = TempDir::new("replace-checked");
+    let mut builder = quiet();
+    builder
+        .key_normalizer(Arc::new(|key: &[u8]| Bytes::from(key.to_ascii_lowercase())))
+        .validator(Arc::new(MaxKeyLen(8)));
+    let mut db = builder.open(dir.path()).unwrap();
+    db.set("kept", "1").unwrap();
+    let rejected = [
+        (Bytes::from("fine"), Bytes::from("2")),
+        (Bytes::from("much too long"), Bytes::from("3")),
+    ];
+    assert!(db.replace_all(rejected).is_err());
+    assert_eq!(db.get("kept").unwrap().unwrap().as_ref(), &b"1"[..]);
+    assert!(db.get("fine").unwrap().is_none());
+
+    db.replace_all([(Bytes::from("Hello"), Bytes::from("world"))])
+        .unwrap();
+    assert_eq!(db.get("HELLO").unwrap().unwrap().as_ref(), &b"world"[..]);
+    let keys: Vec<_> = db.keys::<str, _>(..).unwrap().map(Result::unwrap).collect();
+    assert_eq!(keys, ["hello"]);
+}
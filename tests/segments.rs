@@ -0,0 +1,459 @@
+mod common;
+
+use bytes::Bytes;
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{Error, Get, Map, TuningHint};
+
+fn sorted(range: std::ops::Range<usize>) -> Vec<(Bytes, Bytes)> {
+    range
+        .map(|i| {
+            let (key, value) = entry(i);
+            (Bytes::from(key), Bytes::from(value))
+        })
+        .collect()
+}
+
+#[test]
+fn ingest_sorted_writes_a_segment_read_like_the_others() {
+    let dir = TempDir::new("ingest");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("key00001", "old").unwrap();
+    db.flush().unwrap();
+    db.ingest_sorted(sorted(0..1000)).unwrap();
+    assert_eq!(segment_ids(&db).len(), 2);
+    // Newer than the segment, older than the memtable.
+    db.set("key00002", "new").unwrap();
+    assert_eq!(
+        db.get("key00001").unwrap().unwrap().as_ref(),
+        &b"value00001"[..]
+    );
+    assert_eq!(db.get("key00002").unwrap().unwrap().as_ref(), &b"new"[..]);
+    for i in (0..1000).step_by(37).skip(1) {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(
+        db.get("key00001").unwrap().unwrap().as_ref(),
+        &b"value00001"[..]
+    );
+    assert_eq!(
+        db.get("key00999").unwrap().unwrap().as_ref(),
+        &b"value00999"[..]
+    );
+}
+
+#[test]
+fn ingest_sorted_with_unsorted_keys_writes_nothing() {
+    let dir = TempDir::new("ingest-unsorted");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let mut entries = sorted(0..10);
+    entries.swap(3, 4);
+    assert!(db.ingest_sorted(entries).is_err());
+    assert!(segment_ids(&db).is_empty());
+    assert!(db.get("key00000").unwrap().is_none());
+    let names: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(
+        names
+            .iter()
+            .all(|name| !name.to_string_lossy().ends_with(".tmp")),
+        "{:?}",
+        names
+    );
+}
+
+#[test]
+fn segment_footer_counts_the_written_records() {
+    let dir = TempDir::new("footer-stats");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..250 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.delete("key00007").unwrap();
+    db.delete("missing").unwrap();
+    db.flush().unwrap();
+    let check = |db: &nouzdb::Database| {
+        let infos = db.segment_infos().unwrap();
+        assert_eq!(infos.len(), 1);
+        let info = &infos[0];
+        // The tombstones are records as well.
+        assert_eq!(info.record_count, 251);
+        assert_eq!(info.tombstone_count, 2);
+        assert_eq!(info.key_bytes, 251 * 8 - 1);
+        assert_eq!(info.value_bytes, 249 * 10);
+        assert_eq!(info.min_key.as_deref(), Some(&b"key00000"[..]));
+        assert_eq!(info.max_key.as_deref(), Some(&b"missing"[..]));
+        assert_eq!(
+            info.average_record_size(),
+            (251 * 8 - 1 + 249 * 10) as f64 / 251.0
+        );
+    };
+    check(&db);
+    // Read back from the footer.
+    drop(db);
+    check(&quiet().open(dir.path()).unwrap());
+}
+
+#[test]
+fn file_paths_are_the_files_on_disk() {
+    let dir = TempDir::new("paths");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let log = db.active_log_path();
+    assert!(log.exists());
+    assert_eq!(log.parent(), Some(dir.path()));
+    for round in 0..3 {
+        let (key, value) = entry(round);
+        db.set(key, value).unwrap();
+        db.flush().unwrap();
+    }
+    assert_ne!(db.active_log_path(), log);
+    assert!(db.active_log_path().exists());
+    let paths = db.segment_paths();
+    assert_eq!(
+        paths.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+        segment_ids(&db)
+    );
+    let mut data_files: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("data".as_ref()))
+        .collect();
+    data_files.sort();
+    let mut segment_files: Vec<_> = paths.into_iter().map(|(_, path)| path).collect();
+    segment_files.sort();
+    assert_eq!(segment_files, data_files);
+    for (id, path) in db.segment_paths() {
+        assert_eq!(path, dir.path().join(format!("{}.data", id)));
+    }
+}
+
+#[test]
+fn reclaimable_bytes_drop_to_zero_after_a_compaction() {
+    let dir = TempDir::new("disk-usage");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for round in 0..3 {
+        for i in 0..200 {
+            let (key, value) = entry(i);
+            db.set(key, format!("{}-{}", value, round)).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    for i in 0..50 {
+        db.delete(entry(i).0).unwrap();
+    }
+    db.flush().unwrap();
+    db.set("unflushed", "x").unwrap();
+    let usage = db.disk_usage().unwrap();
+    let sizes: u64 = db
+        .segment_infos()
+        .unwrap()
+        .iter()
+        .map(|info| info.file_size)
+        .sum();
+    assert_eq!(usage.segment_bytes, sizes);
+    assert!(usage.log_bytes > 0);
+    // Two of the three rounds are shadowed, and a quarter of the last one is deleted.
+    assert!(
+        usage.reclaimable_bytes > usage.segment_bytes * 2 / 3,
+        "{:?}",
+        usage
+    );
+    assert!(usage.reclaimable_bytes < usage.segment_bytes);
+    db.compact().unwrap();
+    let usage = db.disk_usage().unwrap();
+    assert!(usage.segment_bytes < sizes / 3);
+    assert_eq!(usage.reclaimable_bytes, 0);
+}
+
+#[test]
+fn packed_segments_are_read_and_merged_from_a_few_files() {
+    let dir = TempDir::new("packed");
+    let mut db = quiet().pack_segments(8).open(dir.path()).unwrap();
+    for round in 0..40 {
+        for i in 0..10 {
+            let (key, value) = entry(round * 10 + i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    assert_eq!(segment_ids(&db).len(), 40);
+    let files = std::fs::read_dir(dir.path()).unwrap().count();
+    assert!(files < 20, "{} files", files);
+    for i in (0..400).step_by(3) {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+
+    db.compact().unwrap();
+    assert_eq!(segment_ids(&db).len(), 1);
+    for i in 0..400 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    let packs = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("pack".as_ref()))
+        .count();
+    assert_eq!(packs, 0);
+}
+
+#[test]
+fn segments_record_their_creation_time() {
+    let dir = TempDir::new("created-at");
+    let mut db = quiet().open(dir.path()).unwrap();
+    let before = std::time::SystemTime::now();
+    db.set("a", "1").unwrap();
+    db.flush().unwrap();
+    let after = std::time::SystemTime::now();
+    let created_at = db.segment_infos().unwrap()[0].created_at.unwrap();
+    // The footer keeps whole seconds.
+    let slack = std::time::Duration::from_secs(1);
+    assert!(created_at + slack >= before && created_at <= after);
+
+    // The time is read back from the footer after a reopen.
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(db.segment_infos().unwrap()[0].created_at, Some(created_at));
+}
+
+#[test]
+fn dropping_the_oldest_segments_expires_their_keys() {
+    let dir = TempDir::new("drop-oldest");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for segment in 0..5 {
+        for i in segment * 10..(segment + 1) * 10 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let ids = segment_ids(&db);
+    // A scan started before the drop still sees the dropped entries.
+    let scan = db.range::<str, _>(..).unwrap();
+    assert_eq!(db.drop_oldest_segments(2).unwrap(), 2);
+    assert_eq!(segment_ids(&db), ids[2..]);
+    assert_eq!(scan.count(), 50);
+    for i in 0..20 {
+        assert!(db.get(&entry(i).0).unwrap().is_none());
+    }
+    for i in 20..50 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    // The files are gone once the scan is, and the drop lasts across a reopen.
+    for id in &ids[..2] {
+        assert!(!dir.join(&format!("{}.data", id)).exists());
+    }
+    drop(db);
+    let db = quiet().open(dir.path()).unwrap();
+    assert_eq!(segment_ids(&db), ids[2..]);
+    assert!(db.get(&entry(0).0).unwrap().is_none());
+}
+
+#[test]
+fn many_segments_produce_the_too_many_segments_hint() {
+    let dir = TempDir::new("tuning-hints");
+    let mut db = quiet().max_merge_segments(4).open(dir.path()).unwrap();
+    for segment in 0..6 {
+        for i in segment * 10..(segment + 1) * 10 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+        let hinted = db
+            .tuning_hints()
+            .unwrap()
+            .contains(&TuningHint::TooManySegments { count: segment + 1 });
+        assert_eq!(hinted, segment + 1 > 4);
+    }
+    db.compact().unwrap();
+    assert!(db.tuning_hints().unwrap().is_empty());
+}
+
+#[test]
+fn vacuuming_a_segment_drops_its_overwritten_entries() {
+    let dir = TempDir::new("vacuum");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..100 {
+        db.set(entry(i).0, "old").unwrap();
+    }
+    db.flush().unwrap();
+    for i in 0..80 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let ids = segment_ids(&db);
+    let size = db.segment_infos().unwrap()[0].file_size;
+
+    let reclaimed = db.vacuum_segment(ids[0]).unwrap();
+    assert_eq!(reclaimed.dropped_records, 80);
+    let new_id = reclaimed.segment_id.unwrap();
+    assert!(new_id > ids[1]);
+    assert_eq!(segment_ids(&db), [ids[1], new_id]);
+    let vacuumed = db.segment_infos().unwrap()[1].file_size;
+    assert_eq!(vacuumed, size - reclaimed.bytes);
+    assert!(vacuumed * 3 < size);
+    for i in 0..100 {
+        let (key, value) = entry(i);
+        let expected = if i < 80 { value.as_bytes() } else { b"old" };
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), expected);
+    }
+
+    // The vacuumed segment has the newest id but the oldest data, so it is dropped first.
+    assert_eq!(db.drop_oldest_segments(1).unwrap(), 1);
+    assert_eq!(segment_ids(&db), [ids[1]]);
+    assert!(db.get(&entry(90).0).unwrap().is_none());
+    assert!(db.get(&entry(10).0).unwrap().is_some());
+}
+
+#[test]
+fn vacuuming_keeps_an_ingested_entry_winning_the_tie() {
+    let dir = TempDir::new("vacuum-ingest");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("k", "v1").unwrap();
+    db.flush().unwrap();
+    // The ingested entry takes the sequence number of "k" and wins by the larger id.
+    db.ingest_sorted([(Bytes::from("k"), Bytes::from("v2"))])
+        .unwrap();
+    let ids = segment_ids(&db);
+
+    let reclaimed = db.vacuum_segment(ids[0]).unwrap();
+    assert_eq!(reclaimed.dropped_records, 1);
+    assert_eq!(reclaimed.segment_id, None);
+    assert_eq!(db.get("k").unwrap().unwrap().as_ref(), &b"v2"[..]);
+
+    // The ingested entry is live, and stays the winner once rewritten.
+    db.set("k", "v1").unwrap();
+    db.flush().unwrap();
+    db.ingest_sorted([(Bytes::from("k"), Bytes::from("v3"))])
+        .unwrap();
+    let ids = segment_ids(&db);
+    let reclaimed = db.vacuum_segment(ids[2]).unwrap();
+    assert_eq!(reclaimed.dropped_records, 0);
+    assert_eq!(db.get("k").unwrap().unwrap().as_ref(), &b"v3"[..]);
+}
+
+#[test]
+fn records_larger_than_a_block_are_all_found_through_the_index() {
+    let dir = TempDir::new("large-records");
+    let mut builder = quiet();
+    builder.block_size(16);
+    let mut db = builder.open(dir.path()).unwrap();
+    let value = |i: usize| format!("{}{}", "v".repeat(i % 7 * 20), i);
+    for i in 0..300 {
+        db.set(entry(i).0, value(i)).unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+    // The index is built again from the records on open.
+    let db = builder.open(dir.path()).unwrap();
+    for i in 0..300 {
+        let (key, _) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value(i).as_bytes());
+    }
+    assert!(db.get("key00000a").unwrap().is_none());
+    assert!(db.get("").unwrap().is_none());
+    assert!(db.get("zzz").unwrap().is_none());
+    let (start, end) = (entry(100).0, entry(200).0);
+    assert_eq!(db.range(start.as_str()..end.as_str()).unwrap().count(), 100);
+}
+
+#[test]
+fn aligned_segments_are_read_across_the_padding() {
+    let dir = TempDir::new("aligned");
+    let mut builder = quiet();
+    builder.block_size(1000).block_alignment(1024);
+    let mut db = builder.open(dir.path()).unwrap();
+    for i in 0..1000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    drop(db);
+    // The index is built again on open, finding the blocks after the padding.
+    let db = builder.open(dir.path()).unwrap();
+    for i in 0..1000 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    let keys: Vec<_> = db
+        .range::<str, _>(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys.len(), 1000);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn a_rebuilt_index_finds_the_records_of_a_rewritten_file() {
+    let dir = TempDir::new("rebuild-index");
+    let mut builder = quiet();
+    builder.block_size(64);
+    let mut db = builder.open(dir.path()).unwrap();
+    let long = "v".repeat(100);
+    db.set(entry(0).0, long.clone()).unwrap();
+    for i in 1..200 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let (id, path) = db.segment_paths().remove(0);
+    // A shorter first value moves every later record back past the offsets of the index,
+    // so the records starting the blocks are missed.
+    let data = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, data.replacen(&long, "short", 1)).unwrap();
+    let missed = (1..200)
+        .filter(|i| db.get(&entry(*i).0).unwrap().is_none())
+        .count();
+    assert!(missed > 0);
+
+    db.rebuild_segment_index(id).unwrap();
+    assert_eq!(
+        db.get(&entry(0).0).unwrap().unwrap().as_ref(),
+        &b"short"[..]
+    );
+    for i in 1..200 {
+        let (key, value) = entry(i);
+        assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+    }
+    assert_eq!(db.segment_infos().unwrap()[0].record_count, 200);
+    assert!(matches!(
+        db.rebuild_segment_index(id + 1),
+        Err(Error::SegmentNotFound(missing)) if missing == id + 1
+    ));
+}
+
+#[test]
+fn lookups_succeed_with_fewer_cached_indices_than_segments() {
+    let dir = TempDir::new("index-cache");
+    let mut builder = quiet();
+    builder.max_cached_segment_meta(3).block_size(64);
+    let mut db = builder.open(dir.path()).unwrap();
+    for segment in 0..30 {
+        for i in (segment * 20)..(segment * 20 + 20) {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    assert_eq!(segment_ids(&db).len(), 30);
+    for round in 0..2 {
+        for i in (round..600).step_by(7) {
+            let (key, value) = entry(i);
+            assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+        }
+    }
+    assert!(db.get("missing").unwrap().is_none());
+    assert_eq!(db.range::<str, _>(..).unwrap().count(), 600);
+    drop(db);
+    let db = builder.open(dir.path()).unwrap();
+    let (key, value) = entry(321);
+    assert_eq!(db.get(&key).unwrap().unwrap().as_ref(), value.as_bytes());
+}
@@ -0,0 +1,88 @@
+//! Tests of the warnings of slow operations, with a subscriber of the test thread
+//! recording them.
+
+mod common;
+
+use common::{entry, quiet, TempDir};
+use nouzdb::{Get, Map};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+/// The messages of the warnings.
+#[derive(Clone, Default)]
+struct Warnings(Arc<Mutex<Vec<String>>>);
+
+struct Message<'a>(&'a mut String);
+
+impl Visit for Message<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            *self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Warnings {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() == Level::WARN {
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.0.lock().unwrap().push(message);
+        }
+    }
+}
+
+impl Warnings {
+    /// The warnings of slow operations of the kind `op`.
+    fn slow(&self, op: &str) -> usize {
+        let prefix = format!("slow {} took", op);
+        let warnings = self.0.lock().unwrap();
+        warnings
+            .iter()
+            .filter(|warning| warning.starts_with(&prefix))
+            .count()
+    }
+}
+
+#[test]
+fn operations_over_the_threshold_are_warned_about() {
+    let warnings = Warnings::default();
+    let subscriber = tracing_subscriber::registry().with(warnings.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let dir = TempDir::new("slow-ops");
+    let mut builder = quiet();
+    builder.slow_op_threshold(Duration::from_nanos(1));
+    let mut db = builder.open(dir.path()).unwrap();
+    // Many segments to look a missing key up in.
+    for segment in 0..20 {
+        let (key, value) = entry(segment);
+        db.set(key, value).unwrap();
+        db.flush().unwrap();
+    }
+    db.delete(entry(0).0).unwrap();
+    assert!(db.get("missing").unwrap().is_none());
+    db.compact().unwrap();
+    assert_eq!(warnings.slow("set"), 20);
+    assert_eq!(warnings.slow("delete"), 1);
+    assert_eq!(warnings.slow("get"), 1);
+    assert_eq!(warnings.slow("flush"), 20);
+    assert_eq!(warnings.slow("merge"), 1);
+    let message = warnings.0.lock().unwrap()[0].clone();
+    assert!(message.contains("over the threshold of 1ns"), "{}", message);
+    drop(db);
+
+    // Nothing is warned about under a threshold no operation reaches.
+    let before = warnings.0.lock().unwrap().len();
+    let dir = TempDir::new("fast-ops");
+    let mut builder = quiet();
+    builder.slow_op_threshold(Duration::from_secs(60));
+    let mut db = builder.open(dir.path()).unwrap();
+    db.set("a", "1").unwrap();
+    db.flush().unwrap();
+    assert!(db.get("a").unwrap().is_some());
+    assert_eq!(warnings.0.lock().unwrap().len(), before);
+}
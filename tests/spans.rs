@@ -0,0 +1,88 @@
+//! Tests of the tracing spans, with a subscriber of the whole process recording them.
+
+mod common;
+
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::Map;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+type Fields = BTreeMap<&'static str, String>;
+
+/// The closed spans, with their names and their fields.
+static SPANS: Mutex<Vec<(&'static str, Fields)>> = Mutex::new(Vec::new());
+
+struct Recorder<'a>(&'a mut Fields);
+
+impl Visit for Recorder<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// A layer keeping the fields of the spans, and moving them to [`SPANS`] once closed.
+struct Spans;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Spans {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::new();
+        attrs.record(&mut Recorder(&mut fields));
+        ctx.span(id).unwrap().extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        values.record(&mut Recorder(extensions.get_mut::<Fields>().unwrap()));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).unwrap();
+        let fields = span.extensions_mut().remove::<Fields>().unwrap();
+        SPANS.lock().unwrap().push((span.name(), fields));
+    }
+}
+
+#[test]
+fn flushes_and_merges_are_spans_with_segment_ids_and_sizes() {
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(Spans)).unwrap();
+    let dir = TempDir::new("spans");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for segment in 0..2 {
+        for i in segment * 10..(segment + 1) * 10 {
+            let (key, value) = entry(i);
+            db.set(key, value).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let flushed = segment_ids(&db);
+    db.compact().unwrap();
+    let merged = segment_ids(&db)[0];
+    drop(db);
+
+    let spans = SPANS.lock().unwrap();
+    let flushes: Vec<&Fields> = spans
+        .iter()
+        .filter(|(name, _)| *name == "flush")
+        .map(|(_, fields)| fields)
+        .collect();
+    assert_eq!(flushes.len(), 2);
+    for (fields, id) in flushes.iter().zip(&flushed) {
+        assert_eq!(fields["segment_id"], id.to_string());
+        assert!(fields.contains_key("log_id"));
+        assert!(fields["bytes"].parse::<u64>().unwrap() > 0);
+    }
+    let (_, merge) = spans.iter().find(|(name, _)| *name == "merge").unwrap();
+    assert_eq!(merge["segment_id"], merged.to_string());
+    let mut inputs = flushed.clone();
+    inputs.reverse();
+    assert_eq!(merge["inputs"], format!("{:?}", inputs));
+    assert!(merge["input_bytes"].parse::<u64>().unwrap() > 0);
+    assert!(merge["bytes"].parse::<u64>().unwrap() > 0);
+}
@@ -0,0 +1,60 @@
+//! Tests of the range streams, which need the `tokio` feature.
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use common::{entry, quiet, TempDir};
+use futures_core::Stream;
+use nouzdb::Map;
+use std::future::poll_fn;
+use std::pin::Pin;
+
+#[tokio::test]
+async fn a_range_stream_yields_the_range() {
+    let dir = TempDir::new("range-stream");
+    let mut db = quiet().open(dir.path()).unwrap();
+    // Several chunks from a segment, then entries of the memtable.
+    for i in 0..1000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    for i in (0..1200).step_by(3) {
+        db.set(entry(i).0, "newer").unwrap();
+    }
+    db.delete(entry(11).0).unwrap();
+
+    let (start, end) = (entry(10).0, entry(1100).0);
+    let mut stream = db.range_stream(start.as_str()..end.as_str()).unwrap();
+    let mut streamed = Vec::new();
+    while let Some(item) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+        let (key, value) = item.unwrap();
+        streamed.push((key, value));
+    }
+    let expected: Vec<_> = db
+        .range(start.as_str()..end.as_str())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    // The keys 10..1000 of the segment but the deleted one, and every third key of
+    // 1000..1100.
+    assert_eq!(streamed.len(), 989 + 33);
+    assert_eq!(streamed, expected);
+}
+
+#[tokio::test]
+async fn a_dropped_range_stream_stops_reading() {
+    let dir = TempDir::new("range-stream-dropped");
+    let mut db = quiet().open(dir.path()).unwrap();
+    for i in 0..5000 {
+        let (key, value) = entry(i);
+        db.set(key, value).unwrap();
+    }
+    db.flush().unwrap();
+    let mut stream = db.range_stream::<str, _>(..).unwrap();
+    let first = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+    assert_eq!(first.unwrap().unwrap().0, entry(0).0);
+    drop(stream);
+    // The blocking task ends, so the database closes without waiting for it.
+    drop(db);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use bytes::Bytes;
+use common::{entry, quiet, segment_ids, TempDir};
+use nouzdb::{replay, Database, Get, Map, ReplayError};
+use std::time::Duration;
+
+fn entries(db: &Database) -> Vec<(Bytes, Bytes)> {
+    db.range::<str, _>(..)
+        .unwrap()
+        .map(|entry| {
+            let (key, value) = entry.unwrap();
+            (key, value.as_ref().clone())
+        })
+        .collect()
+}
+
+fn sorted(range: std::ops::Range<usize>) -> Vec<(Bytes, Bytes)> {
+    range
+        .map(|i| {
+            let (key, value) = entry(i);
+            (Bytes::from(key), Bytes::from(value))
+        })
+        .collect()
+}
+
+/// Run a workload touching every traced operation.
+fn workload(db: &mut Database) {
+    db.get("untouched").unwrap();
+    db.set("a", "1").unwrap();
+    db.set("b", "2").unwrap();
+    db.get("a").unwrap();
+    db.delete("b").unwrap();
+    db.get("b").unwrap();
+    db.flush().unwrap();
+    db.replace_all(sorted(0..20)).unwrap();
+    db.ingest_sorted(sorted(10..30)).unwrap();
+    db.transaction(|txn| {
+        let value = txn.get("key00003")?.unwrap();
+        txn.set("copy", value.as_ref().clone())?;
+        txn.delete("key00004")
+    })
+    .unwrap();
+    db.flush().unwrap();
+    db.set("key00011", "newer").unwrap();
+    db.flush().unwrap();
+    let oldest = segment_ids(db)[1];
+    db.vacuum_segment(oldest).unwrap();
+    assert!(db.vacuum_segment(1000).is_err());
+    db.drop_oldest_segments(1).unwrap();
+    db.compact_older_than(Duration::from_secs(3600)).unwrap();
+    db.compact().unwrap();
+    db.get("copy").unwrap();
+    db.get("key00005").unwrap();
+}
+
+#[test]
+fn replaying_a_trace_reproduces_the_final_state() {
+    let dir = TempDir::new("trace");
+    let trace = dir.join("trace.csv");
+    let data = dir.join("data");
+    let mut db = quiet().op_trace(&trace).open(&data).unwrap();
+    workload(&mut db);
+    let expected = entries(&db);
+    let ids = segment_ids(&db);
+    drop(db);
+    assert!(!expected.is_empty());
+
+    let replayed = dir.join("replayed");
+    let mut db = quiet().open(&replayed).unwrap();
+    let count = replay(&trace, &mut db).unwrap();
+    assert!(count > 40);
+    assert_eq!(entries(&db), expected);
+    assert_eq!(segment_ids(&db), ids);
+}
+
+#[test]
+fn replaying_against_other_data_diverges() {
+    let dir = TempDir::new("trace-diverged");
+    let trace = dir.join("trace.csv");
+    let mut db = quiet().op_trace(&trace).open(&dir.join("data")).unwrap();
+    workload(&mut db);
+    drop(db);
+    let mut db = quiet().open(&dir.join("other")).unwrap();
+    db.set("untouched", "other").unwrap();
+    assert!(matches!(
+        replay(&trace, &mut db),
+        Err(ReplayError::Diverged { index: 0, .. })
+    ));
+}
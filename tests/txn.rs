@@ -0,0 +1,74 @@
+mod common;
+
+use common::{copy_files, quiet, TempDir};
+use nouzdb::{DatabaseHandle, Get, Map, MapError, Txn};
+use std::thread;
+
+fn balance(txn: &Txn<'_>, key: &str) -> Result<i64, MapError> {
+    let value = txn.get(key)?.unwrap();
+    Ok(std::str::from_utf8(&value).unwrap().parse().unwrap())
+}
+
+/// Move `amount` from `from` to `to`, returning the sum of the balances seen.
+fn transfer(db: &DatabaseHandle, from: &str, to: &str, amount: i64) -> Result<i64, MapError> {
+    db.transaction(|txn| {
+        let (a, b) = (balance(txn, from)?, balance(txn, to)?);
+        txn.set(from.to_string(), (a - amount).to_string())?;
+        // Reads see the pending writes.
+        assert_eq!(balance(txn, from)?, a - amount);
+        txn.set(to.to_string(), (b + amount).to_string())?;
+        Ok(a + b)
+    })
+}
+
+#[test]
+fn transfers_never_show_a_partial_state() {
+    let dir = TempDir::new("txn-transfer");
+    let db = DatabaseHandle::from(quiet().switch_mem_size(4096).open(dir.path()).unwrap());
+    db.set("a", "100").unwrap();
+    db.set("b", "0").unwrap();
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let (from, to) = if (thread + i) % 2 == 0 {
+                        ("a", "b")
+                    } else {
+                        ("b", "a")
+                    };
+                    assert_eq!(transfer(&db, from, to, 7).unwrap(), 100);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let get = |key: &str| -> i64 {
+        let value = db.get(key).unwrap().unwrap();
+        std::str::from_utf8(&value).unwrap().parse().unwrap()
+    };
+    assert_eq!(get("a") + get("b"), 100);
+}
+
+#[test]
+fn a_failed_transaction_writes_nothing() {
+    let dir = TempDir::new("txn-failed");
+    let mut db = quiet().open(dir.path()).unwrap();
+    db.set("a", "100").unwrap();
+    let res: Result<(), MapError> = db.transaction(|txn| {
+        txn.set("a", "0")?;
+        txn.set("b", "100")?;
+        Err(MapError::Rejected("insufficient funds".to_string()))
+    });
+    assert!(matches!(res, Err(MapError::Rejected(_))));
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"100"[..]);
+    assert!(db.get("b").unwrap().is_none());
+    // Nor in the log, as a crash now shows.
+    let crashed = TempDir::new("txn-failed-crashed");
+    copy_files(dir.path(), crashed.path());
+    let db = quiet().open(crashed.path()).unwrap();
+    assert_eq!(db.get("a").unwrap().unwrap().as_ref(), &b"100"[..]);
+    assert!(db.get("b").unwrap().is_none());
+}